@@ -0,0 +1,868 @@
+/// An assembler that turns LC3 assembly source into an image the VM can load.
+///
+/// The assembler makes two passes over the source. The first works out the address of every
+/// statement so that labels can be used before they are defined, and the second encodes each
+/// statement into words. Every error is reported with the line and column it occurred on, and
+/// `check` collects all of a program's errors and warnings instead of stopping at the first.
+///
+/// The supported directives are `.ORIG`, `.FILL`, `.BLKW`, `.STRINGZ`, and `.END`. Only one
+/// `.ORIG` block is supported, since an image can only have one origin. A bare `.ORIG` without an
+/// address starts the program at x3000 with a warning.
+///
+/// Besides the instructions and trap aliases, the pseudo-instructions `RET` (`JMP R7`), `NOP`
+/// (the word 0, a branch that is never taken), and `ZERO Rn` (`AND Rn, Rn, #0`) are accepted.
+use crate::lc3::consts::{Op, Trap, PC_START};
+use log::warn;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    error::Error,
+    fmt,
+};
+
+/// An error found while assembling a program
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssembleError {
+    /// The line the error is on, starting from 1
+    pub line: usize,
+
+    /// The column of the token the error is about, starting from 1
+    pub column: usize,
+
+    /// What went wrong
+    pub kind: AssembleErrorKind,
+}
+
+/// The different errors the assembler can report
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AssembleErrorKind {
+    /// A statement appears before the `.ORIG` directive
+    MissingOrigin,
+
+    /// There is more than one `.ORIG` directive
+    MultipleOrigins,
+
+    /// The mnemonic isn't an instruction, trap alias, or directive
+    UnknownMnemonic(String),
+
+    /// The same label is defined more than once
+    DuplicateLabel(String),
+
+    /// A label is used but never defined
+    UndefinedLabel(String),
+
+    /// An instruction or directive has the wrong number of operands
+    WrongOperandCount {
+        /// The number of operands the mnemonic takes
+        expected: usize,
+
+        /// The number of operands that were given
+        found: usize,
+    },
+
+    /// An operand isn't valid where it was used, such as a number where a register is expected
+    InvalidOperand(String),
+
+    /// A value doesn't fit in the bits available for it
+    OutOfRange {
+        /// The value that was given
+        value: i32,
+
+        /// The number of bits available
+        bits: u32,
+    },
+
+    /// A string literal is missing its closing quote or has an unknown escape sequence
+    InvalidString,
+
+    /// The program doesn't fit in memory
+    ProgramTooLarge,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl fmt::Display for AssembleErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleErrorKind::MissingOrigin => write!(f, "statement before .ORIG"),
+            AssembleErrorKind::MultipleOrigins => write!(f, "only one .ORIG is supported"),
+            AssembleErrorKind::UnknownMnemonic(m) => write!(f, "unknown mnemonic {}", m),
+            AssembleErrorKind::DuplicateLabel(l) => write!(f, "label {} is already defined", l),
+            AssembleErrorKind::UndefinedLabel(l) => write!(f, "undefined label {}", l),
+            AssembleErrorKind::WrongOperandCount { expected, found } => {
+                write!(f, "expected {} operands, found {}", expected, found)
+            }
+            AssembleErrorKind::InvalidOperand(o) => write!(f, "invalid operand {}", o),
+            AssembleErrorKind::OutOfRange { value, bits } => {
+                write!(f, "{} does not fit in {} bits", value, bits)
+            }
+            AssembleErrorKind::InvalidString => write!(f, "invalid string literal"),
+            AssembleErrorKind::ProgramTooLarge => write!(f, "program does not fit in memory"),
+        }
+    }
+}
+
+impl Error for AssembleError {}
+
+/// An error or warning reported by `check`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// The line the problem is on, starting from 1
+    pub line: usize,
+
+    /// The column the problem is at, starting from 1
+    pub column: usize,
+
+    /// Whether this is only a warning, which doesn't stop the program from assembling
+    pub warning: bool,
+
+    /// A description of the problem
+    pub message: String,
+}
+
+impl From<&AssembleError> for Diagnostic {
+    fn from(error: &AssembleError) -> Self {
+        Diagnostic {
+            line: error.line,
+            column: error.column,
+            warning: false,
+            message: error.kind.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = if self.warning { "warning: " } else { "" };
+        write!(
+            f,
+            "{}:{}: {}{}",
+            self.line, self.column, severity, self.message
+        )
+    }
+}
+
+/// A single token of a source line
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    /// A mnemonic, label, register, or number
+    Word(String),
+
+    /// A string literal with its escape sequences resolved
+    Str(String),
+}
+
+/// A line of source that produces words or directs the assembler
+struct Statement {
+    /// The line the statement is on, starting from 1
+    line: usize,
+
+    /// The column of the mnemonic, starting from 1
+    column: usize,
+
+    /// The address of the statement's first word
+    address: u16,
+
+    /// The mnemonic or directive, in upper case
+    mnemonic: String,
+
+    /// Everything after the mnemonic
+    operands: Vec<Token>,
+
+    /// The column of each operand, starting from 1
+    operand_columns: Vec<usize>,
+}
+
+/// An error encoding a statement, along with the index of the operand it is about, if any
+struct EncodeError {
+    operand: Option<usize>,
+    kind: AssembleErrorKind,
+}
+
+impl From<AssembleErrorKind> for EncodeError {
+    fn from(kind: AssembleErrorKind) -> Self {
+        EncodeError {
+            operand: None,
+            kind,
+        }
+    }
+}
+
+/// Attribute an error to the operand at `idx`, for use with `map_err`
+fn at(idx: usize) -> impl Fn(AssembleErrorKind) -> EncodeError {
+    move |kind| EncodeError {
+        operand: Some(idx),
+        kind,
+    }
+}
+
+/// Assemble LC3 assembly source into an image
+///
+/// The first word of the result is the origin, followed by the words to load there, which is the
+/// layout `LC3::load_image` expects once the words are converted to bytes with `to_bytes`.
+pub fn assemble(source: &str) -> Result<Vec<u16>, AssembleError> {
+    let assembly = assemble_all(source);
+    for warning in &assembly.warnings {
+        warn!("{}", warning);
+    }
+    match assembly.errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(assembly.image),
+    }
+}
+
+/// Find every error and warning in LC3 assembly source, ordered by where they occur
+///
+/// A program assembles if none of the diagnostics are errors.
+pub fn check(source: &str) -> Vec<Diagnostic> {
+    let assembly = assemble_all(source);
+    let mut diagnostics: Vec<Diagnostic> = assembly
+        .errors
+        .iter()
+        .map(Diagnostic::from)
+        .chain(assembly.warnings)
+        .collect();
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    diagnostics
+}
+
+/// The result of assembling a program, which is only usable if there are no errors
+struct Assembly {
+    image: Vec<u16>,
+    errors: Vec<AssembleError>,
+    warnings: Vec<Diagnostic>,
+}
+
+/// Assemble a program, collecting every error instead of stopping at the first
+fn assemble_all(source: &str) -> Assembly {
+    let mut assembly = Assembly {
+        image: Vec::new(),
+        errors: Vec::new(),
+        warnings: Vec::new(),
+    };
+    let (origin, statements, labels) = match first_pass(source, &mut assembly) {
+        Some(pass) => pass,
+        None => return assembly,
+    };
+    assembly.image.push(origin);
+    for statement in &statements {
+        if let Err(error) = encode(statement, &labels, &mut assembly.image) {
+            // Errors about an operand point at it rather than at the mnemonic
+            let column = error
+                .operand
+                .and_then(|idx| statement.operand_columns.get(idx))
+                .copied()
+                .unwrap_or(statement.column);
+            assembly.errors.push(AssembleError {
+                line: statement.line,
+                column,
+                kind: error.kind,
+            });
+        }
+    }
+    assembly
+}
+
+/// Convert the words of an assembled image to the big-endian bytes of an object file
+pub fn to_bytes(words: &[u16]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_be_bytes()).collect()
+}
+
+/// The addresses of the labels in a program
+type SymbolTable = HashMap<String, u16>;
+
+/// Split the source into statements, giving each an address and collecting the labels
+///
+/// Returns the origin along with the statements and labels. Errors are added to the assembly,
+/// and a line with an error is skipped. Statements before the origin stop the pass, since none of
+/// the addresses after them would be right.
+fn first_pass(source: &str, assembly: &mut Assembly) -> Option<(u16, Vec<Statement>, SymbolTable)> {
+    let mut origin = None;
+    let mut address: u32 = 0;
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+
+    for (idx, text) in source.lines().enumerate() {
+        let line = idx + 1;
+        let mut tokens = match tokenize(text) {
+            Ok(tokens) => tokens,
+            Err((column, kind)) => {
+                assembly.errors.push(AssembleError { line, column, kind });
+                continue;
+            }
+        };
+        if tokens.is_empty() {
+            continue;
+        }
+        let error = |column, kind| AssembleError { line, column, kind };
+
+        // Anything in the first position that isn't a mnemonic is a label
+        if let (column, Token::Word(word)) = &tokens[0] {
+            if mnemonic_size(&word.to_uppercase()).is_none() {
+                let column = *column;
+                let label = word.trim_end_matches(':').to_string();
+                if !is_label(&label) {
+                    let kind = AssembleErrorKind::UnknownMnemonic(word.clone());
+                    assembly.errors.push(error(column, kind));
+                    continue;
+                }
+                if origin.is_none() {
+                    assembly
+                        .errors
+                        .push(error(column, AssembleErrorKind::MissingOrigin));
+                    return None;
+                }
+                match labels.entry(label) {
+                    Entry::Occupied(entry) => {
+                        let kind = AssembleErrorKind::DuplicateLabel(entry.key().clone());
+                        assembly.errors.push(error(column, kind));
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(address as u16);
+                    }
+                }
+                tokens.remove(0);
+            }
+        }
+
+        let (column, mnemonic) = match tokens.first() {
+            Some((column, Token::Word(word))) => (*column, word.to_uppercase()),
+            Some((column, Token::Str(_))) => {
+                let kind = AssembleErrorKind::UnknownMnemonic(String::from("\""));
+                assembly.errors.push(error(*column, kind));
+                continue;
+            }
+            None => continue,
+        };
+        let (operand_columns, operands): (Vec<usize>, Vec<Token>) =
+            tokens.split_off(1).into_iter().unzip();
+        let error = |kind| error(column, kind);
+
+        match mnemonic.as_str() {
+            ".ORIG" => {
+                if origin.is_some() {
+                    assembly
+                        .errors
+                        .push(error(AssembleErrorKind::MultipleOrigins));
+                    continue;
+                }
+                let start = if operands.is_empty() {
+                    assembly.warnings.push(Diagnostic {
+                        line,
+                        column,
+                        warning: true,
+                        message: format!(".ORIG without an address defaults to x{:04X}", PC_START),
+                    });
+                    Ok(PC_START)
+                } else {
+                    expect_operands(&operands, 1).and_then(|_| number(&operands[0], 16, false))
+                };
+                let start = match start {
+                    Ok(start) => start,
+                    Err(kind) => {
+                        // Without an origin none of the addresses can be worked out
+                        assembly.errors.push(error(kind));
+                        return None;
+                    }
+                };
+                origin = Some(start);
+                address = u32::from(start);
+                continue;
+            }
+            ".END" => break,
+            _ if origin.is_none() => {
+                assembly
+                    .errors
+                    .push(error(AssembleErrorKind::MissingOrigin));
+                return None;
+            }
+            _ => (),
+        }
+
+        let size = match mnemonic.as_str() {
+            ".BLKW" => expect_operands(&operands, 1)
+                .and_then(|_| number(&operands[0], 16, false))
+                .map(u32::from),
+            ".STRINGZ" => expect_operands(&operands, 1).and_then(|_| match &operands[0] {
+                Token::Str(s) => Ok(s.chars().count() as u32 + 1),
+                Token::Word(w) => Err(AssembleErrorKind::InvalidOperand(w.clone())),
+            }),
+            _ => Ok(mnemonic_size(&mnemonic).unwrap_or(1)),
+        };
+        let size = match size {
+            Ok(size) => size,
+            Err(kind) => {
+                assembly.errors.push(error(kind));
+                continue;
+            }
+        };
+        statements.push(Statement {
+            line,
+            column,
+            address: address as u16,
+            mnemonic,
+            operands,
+            operand_columns,
+        });
+        address += size;
+        if address > 0x10000 {
+            assembly
+                .errors
+                .push(error(AssembleErrorKind::ProgramTooLarge));
+            return None;
+        }
+    }
+
+    if origin.is_none() {
+        assembly.errors.push(AssembleError {
+            line: source.lines().count().max(1),
+            column: 1,
+            kind: AssembleErrorKind::MissingOrigin,
+        });
+    }
+    origin.map(|origin| (origin, statements, labels))
+}
+
+/// The number of words a mnemonic produces, or `None` if it isn't a mnemonic
+///
+/// Directives whose size depends on their operands report 0 here.
+fn mnemonic_size(mnemonic: &str) -> Option<u32> {
+    match mnemonic {
+        ".ORIG" | ".END" | ".BLKW" | ".STRINGZ" => Some(0),
+        ".FILL" | "ADD" | "AND" | "NOT" | "JMP" | "RET" | "JSR" | "JSRR" | "LD" | "LDI" | "LDR"
+        | "LEA" | "ST" | "STI" | "STR" | "TRAP" | "RTI" | "NOP" | "ZERO" => Some(1),
+        _ if branch_flags(mnemonic).is_some() || trap_alias(mnemonic).is_some() => Some(1),
+        _ => None,
+    }
+}
+
+/// The nzp bits for a branch mnemonic such as `BRnz`, where a plain `BR` branches always
+fn branch_flags(mnemonic: &str) -> Option<u16> {
+    let flags = mnemonic.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(0b111);
+    }
+    let mut nzp = 0;
+    let mut last = 0;
+    for c in flags.chars() {
+        // Each flag may appear at most once, in n, z, p order
+        let bit = match c {
+            'N' => 0b100,
+            'Z' => 0b010,
+            'P' => 0b001,
+            _ => return None,
+        };
+        if last != 0 && bit >= last {
+            return None;
+        }
+        nzp |= bit;
+        last = bit;
+    }
+    Some(nzp)
+}
+
+/// The trap routine for a trap alias such as `HALT`
+fn trap_alias(mnemonic: &str) -> Option<Trap> {
+    match mnemonic {
+        "GETC" => Some(Trap::GETC),
+        "OUT" => Some(Trap::OUT),
+        "PUTS" => Some(Trap::PUTS),
+        "IN" => Some(Trap::IN),
+        "PUTSP" => Some(Trap::PUTSP),
+        "HALT" => Some(Trap::HALT),
+        "PUTN" => Some(Trap::PUTN),
+        "MEMCPY" => Some(Trap::MEMCPY),
+        "RAND" => Some(Trap::RAND),
+        "GETN" => Some(Trap::GETN),
+        _ => None,
+    }
+}
+
+/// Whether a word can be used as a label
+fn is_label(word: &str) -> bool {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Split a line into tokens paired with their columns, dropping commas and comments
+///
+/// An invalid string literal is reported along with the column it starts at.
+fn tokenize(line: &str) -> Result<Vec<(usize, Token)>, (usize, AssembleErrorKind)> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().enumerate().map(|(i, c)| (i + 1, c)).peekable();
+    while let Some(&(column, c)) = chars.peek() {
+        match c {
+            ';' => break,
+            c if c.is_whitespace() || c == ',' => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let invalid = (column, AssembleErrorKind::InvalidString);
+                let mut s = String::new();
+                loop {
+                    match chars.next().map(|(_, c)| c) {
+                        Some('"') => break,
+                        Some('\\') => s.push(match chars.next().map(|(_, c)| c) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('0') => '\0',
+                            Some('"') => '"',
+                            Some('\\') => '\\',
+                            _ => return Err(invalid),
+                        }),
+                        Some(c) => s.push(c),
+                        None => return Err(invalid),
+                    }
+                }
+                tokens.push((column, Token::Str(s)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || c == ',' || c == ';' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((column, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Check that a statement has the expected number of operands
+fn expect_operands(operands: &[Token], expected: usize) -> Result<(), AssembleErrorKind> {
+    if operands.len() == expected {
+        Ok(())
+    } else {
+        Err(AssembleErrorKind::WrongOperandCount {
+            expected,
+            found: operands.len(),
+        })
+    }
+}
+
+/// The text of an operand, for error messages
+fn operand_text(token: &Token) -> String {
+    match token {
+        Token::Word(word) => word.clone(),
+        Token::Str(s) => format!("{:?}", s),
+    }
+}
+
+/// Parse a numeric literal: `#10`, `#-10`, `x1F`, `0x1F`, or a bare decimal number
+pub(crate) fn parse_number(word: &str) -> Option<i32> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, word),
+    };
+    let value = if let Some(dec) = digits.strip_prefix('#') {
+        return parse_number(dec).map(|v| if negative { -v } else { v });
+    } else if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .or_else(|| digits.strip_prefix('x'))
+        .or_else(|| digits.strip_prefix('X'))
+    {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Parse a numeric operand and check that it fits in `bits` bits
+///
+/// Signed values must fit in two's complement. Unsigned values may also be given as negative
+/// numbers as long as they fit in two's complement, so `.FILL #-1` works.
+fn number(token: &Token, bits: u32, signed: bool) -> Result<u16, AssembleErrorKind> {
+    let value = match token {
+        Token::Word(word) => parse_number(word),
+        Token::Str(_) => None,
+    }
+    .ok_or_else(|| AssembleErrorKind::InvalidOperand(operand_text(token)))?;
+    fit(value, bits, signed)
+}
+
+/// Check that a value fits in `bits` bits and truncate it to them
+fn fit(value: i32, bits: u32, signed: bool) -> Result<u16, AssembleErrorKind> {
+    let min = -(1 << (bits - 1));
+    let max = if signed {
+        (1 << (bits - 1)) - 1
+    } else {
+        (1 << bits) - 1
+    };
+    if value < min || value > max {
+        return Err(AssembleErrorKind::OutOfRange { value, bits });
+    }
+    Ok((value as u16) & ((1u32 << bits) - 1) as u16)
+}
+
+/// Parse a register operand such as `R3`
+fn register(token: &Token) -> Result<u16, AssembleErrorKind> {
+    match token {
+        Token::Word(word) if word.len() == 2 && word.to_uppercase().starts_with('R') => {
+            match word[1..].parse::<u16>() {
+                Ok(r) if r < 8 => Ok(r),
+                _ => Err(AssembleErrorKind::InvalidOperand(word.clone())),
+            }
+        }
+        _ => Err(AssembleErrorKind::InvalidOperand(operand_text(token))),
+    }
+}
+
+/// Parse a PC relative operand, which is either a label or a literal offset, into `bits` bits
+fn pc_offset(
+    token: &Token,
+    address: u16,
+    bits: u32,
+    labels: &SymbolTable,
+) -> Result<u16, AssembleErrorKind> {
+    let word = match token {
+        Token::Word(word) => word,
+        Token::Str(_) => return Err(AssembleErrorKind::InvalidOperand(operand_text(token))),
+    };
+    if let Some(offset) = parse_number(word) {
+        return fit(offset, bits, true);
+    }
+    match labels.get(word.as_str()) {
+        // The offset is relative to the incremented PC
+        Some(&target) => fit(i32::from(target) - i32::from(address) - 1, bits, true),
+        None => Err(AssembleErrorKind::UndefinedLabel(word.clone())),
+    }
+}
+
+/// Encode a statement and append its words to the image
+fn encode(
+    statement: &Statement,
+    labels: &SymbolTable,
+    image: &mut Vec<u16>,
+) -> Result<(), EncodeError> {
+    let ops = &statement.operands;
+    let address = statement.address;
+    let mnemonic = statement.mnemonic.as_str();
+    let opcode = |op: Op| (op as u16) << 12;
+
+    let word = match mnemonic {
+        ".FILL" => {
+            expect_operands(ops, 1)?;
+            match &ops[0] {
+                Token::Word(word) if parse_number(word).is_none() => *labels
+                    .get(word.as_str())
+                    .ok_or_else(|| AssembleErrorKind::UndefinedLabel(word.clone()))
+                    .map_err(at(0))?,
+                token => number(token, 16, false).map_err(at(0))?,
+            }
+        }
+        ".BLKW" => {
+            let count = number(&ops[0], 16, false).map_err(at(0))?;
+            image.resize(image.len() + count as usize, 0);
+            return Ok(());
+        }
+        ".STRINGZ" => {
+            if let Token::Str(s) = &ops[0] {
+                image.extend(s.chars().map(|c| c as u16));
+                image.push(0);
+            }
+            return Ok(());
+        }
+        "ADD" | "AND" => {
+            expect_operands(ops, 3)?;
+            let op = if mnemonic == "ADD" { Op::ADD } else { Op::AND };
+            let base = opcode(op)
+                | register(&ops[0]).map_err(at(0))? << 9
+                | register(&ops[1]).map_err(at(1))? << 6;
+            match register(&ops[2]) {
+                Ok(r2) => base | r2,
+                Err(_) => base | 1 << 5 | number(&ops[2], 5, true).map_err(at(2))?,
+            }
+        }
+        "NOT" => {
+            expect_operands(ops, 2)?;
+            opcode(Op::NOT)
+                | register(&ops[0]).map_err(at(0))? << 9
+                | register(&ops[1]).map_err(at(1))? << 6
+                | 0x3F
+        }
+        "JMP" | "JSRR" => {
+            expect_operands(ops, 1)?;
+            let op = if mnemonic == "JMP" { Op::JMP } else { Op::JSR };
+            opcode(op) | register(&ops[0]).map_err(at(0))? << 6
+        }
+        "RET" => {
+            expect_operands(ops, 0)?;
+            opcode(Op::JMP) | 7 << 6
+        }
+        "NOP" => {
+            expect_operands(ops, 0)?;
+            opcode(Op::BR)
+        }
+        "ZERO" => {
+            expect_operands(ops, 1)?;
+            let r = register(&ops[0]).map_err(at(0))?;
+            opcode(Op::AND) | r << 9 | r << 6 | 1 << 5
+        }
+        "JSR" => {
+            expect_operands(ops, 1)?;
+            opcode(Op::JSR) | 1 << 11 | pc_offset(&ops[0], address, 11, labels).map_err(at(0))?
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect_operands(ops, 2)?;
+            let op = match mnemonic {
+                "LD" => Op::LD,
+                "LDI" => Op::LDI,
+                "LEA" => Op::LEA,
+                "ST" => Op::ST,
+                _ => Op::STI,
+            };
+            opcode(op)
+                | register(&ops[0]).map_err(at(0))? << 9
+                | pc_offset(&ops[1], address, 9, labels).map_err(at(1))?
+        }
+        "LDR" | "STR" => {
+            expect_operands(ops, 3)?;
+            let op = if mnemonic == "LDR" { Op::LDR } else { Op::STR };
+            opcode(op)
+                | register(&ops[0]).map_err(at(0))? << 9
+                | register(&ops[1]).map_err(at(1))? << 6
+                | number(&ops[2], 6, true).map_err(at(2))?
+        }
+        "TRAP" => {
+            expect_operands(ops, 1)?;
+            let vector = number(&ops[0], 8, false).map_err(at(0))?;
+            opcode(Op::TRAP) | vector
+        }
+        "RTI" => {
+            expect_operands(ops, 0)?;
+            opcode(Op::RTI)
+        }
+        _ => {
+            if let Some(trap) = trap_alias(mnemonic) {
+                expect_operands(ops, 0)?;
+                opcode(Op::TRAP) | trap as u16
+            } else if let Some(nzp) = branch_flags(mnemonic) {
+                expect_operands(ops, 1)?;
+                opcode(Op::BR) | nzp << 9 | pc_offset(&ops[0], address, 9, labels).map_err(at(0))?
+            } else {
+                return Err(AssembleErrorKind::UnknownMnemonic(mnemonic.to_string()).into());
+            }
+        }
+    };
+    image.push(word);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::{DispatchTables, LC3};
+    use std::io;
+
+    /// Assemble the body of a program at x3000 and return the words after the origin
+    fn assemble_body(body: &str) -> Vec<u16> {
+        let image = assemble(&format!(".ORIG x3000\n{}\n.END", body)).unwrap();
+        image[1..].to_vec()
+    }
+
+    #[test]
+    fn ret_is_jmp_r7() {
+        assert_eq!(assemble_body("RET"), assemble_body("JMP R7"));
+        assert_eq!(assemble_body("RET"), [0xC1C0]);
+    }
+
+    #[test]
+    fn nop_is_zero_word() {
+        assert_eq!(assemble_body("NOP"), [0x0000]);
+    }
+
+    #[test]
+    fn zero_clears_register() {
+        assert_eq!(assemble_body("ZERO R1"), assemble_body("AND R1, R1, #0"));
+        assert_eq!(assemble_body("ZERO R1"), [0x5260]);
+    }
+
+    #[test]
+    fn pseudo_instructions_check_operands() {
+        let error = assemble(".ORIG x3000\nZERO\n.END").unwrap_err();
+        assert_eq!(
+            error.kind,
+            AssembleErrorKind::WrongOperandCount {
+                expected: 1,
+                found: 0
+            }
+        );
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn check_reports_every_error_with_its_position() {
+        let source = ".ORIG x3000\n  ADD R1, R9, #1\nLOOP BRz NOWHERE\nHALT\n.END";
+        let diagnostics = check(source);
+        let rendered: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            rendered,
+            ["2:11: invalid operand R9", "3:10: undefined label NOWHERE"]
+        );
+        assert!(diagnostics.iter().all(|d| !d.warning));
+    }
+
+    #[test]
+    fn bare_orig_defaults_to_x3000_with_warning() {
+        let source = ".ORIG\nHALT\n.END";
+        assert_eq!(assemble(source).unwrap(), [0x3000, 0xF025]);
+        assert_eq!(
+            check(source),
+            [Diagnostic {
+                line: 1,
+                column: 1,
+                warning: true,
+                message: String::from(".ORIG without an address defaults to x3000"),
+            }]
+        );
+    }
+
+    #[test]
+    fn hello_world_runs() {
+        let source = "
+            .ORIG x3000
+                    LEA R0, HELLO
+                    PUTS
+                    HALT
+            HELLO   .STRINGZ \"Hello, world!\"
+            .END";
+        let image = assemble(source).unwrap();
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.captured_output = Some(Vec::new());
+        vm.load_image(&to_bytes(&image)).unwrap();
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.take_captured_output(), b"Hello, world!HALT");
+    }
+
+    #[test]
+    fn far_branch_reports_its_line() {
+        let source = ".ORIG x3000\nBR FAR\n.BLKW 300\nFAR HALT\n.END";
+        let error = assemble(source).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            AssembleErrorKind::OutOfRange {
+                value: 300,
+                bits: 9
+            }
+        );
+    }
+}
@@ -0,0 +1,157 @@
+/// Runs a directory of programs headlessly and checks what each one prints.
+///
+/// Every image in the directory is a test case. An image can have two sidecar files next to it
+/// with the same stem: `<stem>.in`, which is fed to the program as keyboard input, and
+/// `<stem>.out`, which holds exactly what the program must print, including the `HALT` message.
+/// A case without a `.out` file passes as long as it halts without faulting.
+use crate::lc3::{error::RunError, run_to_halt};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The most instructions a test case may execute unless another limit is given
+pub const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+/// A program to run as part of a battery
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestCase {
+    /// The name the result is reported under, which is the image's file name
+    pub name: String,
+
+    /// The raw bytes of the image, in any format `LC3::read_image` accepts
+    pub image: Vec<u8>,
+
+    /// The program's keyboard input
+    pub input: Vec<u8>,
+
+    /// What the program must print, if it is checked
+    pub expected_output: Option<String>,
+}
+
+/// How a test case turned out
+#[derive(Debug)]
+pub enum Outcome {
+    /// The program halted and printed what was expected
+    Pass,
+
+    /// The program halted but printed something else
+    WrongOutput {
+        /// What the program printed
+        actual: String,
+    },
+
+    /// The image couldn't be loaded or the program faulted
+    Error(RunError),
+}
+
+impl Outcome {
+    /// Whether the test case passed
+    pub fn is_pass(&self) -> bool {
+        match self {
+            Outcome::Pass => true,
+            Outcome::WrongOutput { .. } | Outcome::Error(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::WrongOutput { actual } => write!(f, "FAIL: printed {:?}", actual),
+            Outcome::Error(e) => write!(f, "FAIL: {}", e),
+        }
+    }
+}
+
+/// Run a single test case, executing at most `max_steps` instructions
+pub fn run_case(case: &TestCase, max_steps: u64) -> Outcome {
+    match run_to_halt(&case.image, &case.input, max_steps) {
+        Ok(actual) => match &case.expected_output {
+            Some(expected) if *expected != actual => Outcome::WrongOutput { actual },
+            _ => Outcome::Pass,
+        },
+        Err(e) => Outcome::Error(e),
+    }
+}
+
+/// Run every test case in order, pairing each name with its outcome
+pub fn run_battery(cases: &[TestCase], max_steps: u64) -> Vec<(String, Outcome)> {
+    cases
+        .iter()
+        .map(|case| (case.name.clone(), run_case(case, max_steps)))
+        .collect()
+}
+
+/// Load the test cases in a directory, sorted by name
+///
+/// Every file other than the `.in` and `.out` sidecars is treated as an image.
+pub fn load_cases(dir: &Path) -> io::Result<Vec<TestCase>> {
+    let mut images: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    images.retain(|path| {
+        let sidecar = path
+            .extension()
+            .is_some_and(|ext| ext == "in" || ext == "out");
+        path.is_file() && !sidecar
+    });
+    images.sort();
+
+    images
+        .into_iter()
+        .map(|path| {
+            let input = match fs::read(path.with_extension("in")) {
+                Ok(input) => input,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            let expected_output = match fs::read_to_string(path.with_extension("out")) {
+                Ok(output) => Some(output),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            Ok(TestCase {
+                name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                image: fs::read(&path)?,
+                input,
+                expected_output,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+
+    /// A test case for a program that echoes one key
+    fn echo_case(name: &str, expected_output: &str) -> TestCase {
+        let image = assembler::assemble(".ORIG x3000\nGETC\nOUT\nHALT\n.END").unwrap();
+        TestCase {
+            name: String::from(name),
+            image: assembler::to_bytes(&image),
+            input: b"k".to_vec(),
+            expected_output: Some(String::from(expected_output)),
+        }
+    }
+
+    #[test]
+    fn battery_reports_pass_and_fail() {
+        let cases = [echo_case("good", "kHALT"), echo_case("bad", "jHALT")];
+        let results = run_battery(&cases, DEFAULT_MAX_STEPS);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "good");
+        assert!(results[0].1.is_pass());
+        assert_eq!(results[1].0, "bad");
+        match &results[1].1 {
+            Outcome::WrongOutput { actual } => assert_eq!(actual, "kHALT"),
+            outcome => panic!("unexpected outcome {}", outcome),
+        }
+    }
+}
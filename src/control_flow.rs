@@ -0,0 +1,233 @@
+/// Static control flow graphs of images, which can be rendered with Graphviz.
+///
+/// The graph is built by walking the image from its origin and following every branch, jump, and
+/// subroutine call whose target can be worked out from the instruction alone. Jumps through a
+/// register other than R7 lead to an "unknown target" node. `RET`, `RTI`, and `HALT` end a path,
+/// and so does a target outside of the image, since there is nothing there to disassemble.
+use crate::{
+    disassembler::disassemble,
+    lc3::{
+        consts::{Register, Trap},
+        instruction::decode::{decode, Instruction},
+    },
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Where control can go after an instruction
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Target {
+    /// A known address
+    Address(u16),
+
+    /// The value of a register, which can't be known without running the program
+    Unknown,
+}
+
+/// A run of instructions that is only ever entered at the top and left at the bottom
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BasicBlock {
+    /// The address of the first instruction
+    pub start: u16,
+
+    /// The disassembly of every instruction, paired with its address
+    pub instructions: Vec<(u16, String)>,
+}
+
+/// The basic blocks reachable from the origin of an image and the edges between them
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ControlFlowGraph {
+    /// The blocks, keyed by their first address
+    pub blocks: BTreeMap<u16, BasicBlock>,
+
+    /// Every edge, from the start of a block to where its last instruction can go
+    pub edges: BTreeSet<(u16, Target)>,
+}
+
+impl ControlFlowGraph {
+    /// Build the graph of an image, whose first word is its origin
+    pub fn build(image: &[u16]) -> Self {
+        let (origin, body) = match image.split_first() {
+            Some((&origin, body)) => (origin, body),
+            None => return Self::default(),
+        };
+        let word = |addr: u16| {
+            addr.checked_sub(origin)
+                .and_then(|offset| body.get(offset as usize))
+                .copied()
+        };
+
+        // Find every reachable instruction and where it can go
+        let mut successors: BTreeMap<u16, Vec<Target>> = BTreeMap::new();
+        let mut pending = vec![origin];
+        while let Some(addr) = pending.pop() {
+            if successors.contains_key(&addr) {
+                continue;
+            }
+            let instr = match word(addr) {
+                Some(instr) => instr,
+                None => continue,
+            };
+            let targets = successors_of(addr, instr);
+            pending.extend(targets.iter().filter_map(|&target| match target {
+                Target::Address(addr) => Some(addr),
+                Target::Unknown => None,
+            }));
+            successors.insert(addr, targets);
+        }
+
+        // A block starts at the origin, at a target of a jump, and after any instruction that
+        // doesn't just fall through
+        let falls_through =
+            |addr: u16| successors[&addr] == [Target::Address(addr.wrapping_add(1))];
+        let mut leaders: BTreeSet<u16> = BTreeSet::new();
+        leaders.insert(origin);
+        for (&addr, targets) in &successors {
+            if !falls_through(addr) {
+                leaders.extend(targets.iter().filter_map(|&target| match target {
+                    Target::Address(addr) => Some(addr),
+                    Target::Unknown => None,
+                }));
+            }
+        }
+        leaders.retain(|addr| successors.contains_key(addr));
+
+        let mut graph = Self::default();
+        for &start in &leaders {
+            let mut addr = start;
+            let mut instructions = Vec::new();
+            loop {
+                instructions.push((addr, disassemble(word(addr).unwrap_or_default())));
+                let next = addr.wrapping_add(1);
+                if !falls_through(addr)
+                    || leaders.contains(&next)
+                    || !successors.contains_key(&next)
+                {
+                    break;
+                }
+                addr = next;
+            }
+            for &target in &successors[&addr] {
+                graph.edges.insert((start, target));
+            }
+            graph.blocks.insert(
+                start,
+                BasicBlock {
+                    start,
+                    instructions,
+                },
+            );
+        }
+        graph
+    }
+
+    /// Render the graph in the Graphviz dot language, with each block's disassembly as its label
+    pub fn to_dot(&self) -> String {
+        let mut dot =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let label: String = block
+                .instructions
+                .iter()
+                .map(|(addr, text)| format!("x{:04X}  {}\\l", addr, escape(text)))
+                .collect();
+            dot.push_str(&format!(
+                "    b{:04x} [label=\"{}\"];\n",
+                block.start, label
+            ));
+        }
+        if self
+            .edges
+            .iter()
+            .any(|&(_, target)| target == Target::Unknown)
+        {
+            dot.push_str("    unknown [label=\"unknown target\", shape=ellipse];\n");
+        }
+        for &(from, target) in &self.edges {
+            let to = match target {
+                Target::Address(addr) if self.blocks.contains_key(&addr) => {
+                    format!("b{:04x}", addr)
+                }
+                Target::Address(addr) => format!("\"x{:04X}\"", addr),
+                Target::Unknown => String::from("unknown"),
+            };
+            dot.push_str(&format!("    b{:04x} -> {};\n", from, to));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Where control can go after the instruction `instr` at `addr`
+fn successors_of(addr: u16, instr: u16) -> Vec<Target> {
+    let next = addr.wrapping_add(1);
+    // PC relative offsets are relative to the incremented PC
+    let relative = |offset: i16| Target::Address(next.wrapping_add(offset as u16));
+    match decode(instr) {
+        Instruction::BR {
+            n: false,
+            z: false,
+            p: false,
+            ..
+        } => vec![Target::Address(next)],
+        Instruction::BR {
+            n: true,
+            z: true,
+            p: true,
+            offset,
+        } => vec![relative(offset)],
+        Instruction::BR { offset, .. } => vec![relative(offset), Target::Address(next)],
+        Instruction::JMP { base: Register::R7 } | Instruction::RTI | Instruction::RES => Vec::new(),
+        Instruction::JMP { .. } => vec![Target::Unknown],
+        Instruction::JSR { offset } => vec![relative(offset), Target::Address(next)],
+        Instruction::JSRR { .. } => vec![Target::Unknown, Target::Address(next)],
+        Instruction::TRAP { vector } if vector == Trap::HALT as u16 => Vec::new(),
+        _ => vec![Target::Address(next)],
+    }
+}
+
+/// Escape text for a double quoted dot string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+
+    #[test]
+    fn branch_splits_blocks() {
+        let image = assembler::assemble(
+            ".ORIG x3000\nAND R0, R0, #0\nADD R1, R1, #-1\nBRz DONE\nADD R0, R0, #1\n\
+             DONE HALT\n.END",
+        )
+        .unwrap();
+        let graph = ControlFlowGraph::build(&image);
+        assert_eq!(
+            graph.blocks.keys().copied().collect::<Vec<u16>>(),
+            [0x3000, 0x3003, 0x3004]
+        );
+        assert_eq!(graph.blocks[&0x3000].instructions.len(), 3);
+        let edges: Vec<(u16, Target)> = graph.edges.iter().copied().collect();
+        assert_eq!(
+            edges,
+            [
+                (0x3000, Target::Address(0x3003)),
+                (0x3000, Target::Address(0x3004)),
+                (0x3003, Target::Address(0x3004)),
+            ]
+        );
+        assert!(graph.to_dot().contains("    b3000 -> b3004;\n"));
+    }
+
+    #[test]
+    fn register_jump_has_unknown_target() {
+        let image = assembler::assemble(".ORIG x3000\nJMP R2\n.END").unwrap();
+        let graph = ControlFlowGraph::build(&image);
+        assert_eq!(
+            graph.edges.iter().copied().collect::<Vec<_>>(),
+            [(0x3000, Target::Unknown)]
+        );
+        assert!(graph.to_dot().contains("    b3000 -> unknown;\n"));
+    }
+}
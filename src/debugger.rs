@@ -2,43 +2,779 @@
 ///
 /// This provides a way to step through instructions and inspect memory through the execution of a
 /// program, allowing the user to either debug the VM or the program.
-use crate::lc3::{consts::Register, DispatchTables, LC3};
+use crate::{
+    assembler::parse_number,
+    disassembler::disassemble,
+    lc3::{
+        consts::{ConditionFlag, EffectiveAddress, Operation, Register, MEMORY_LIMIT},
+        error::LoadError,
+        instruction::get_cond_flag,
+        CpuState, DispatchTables, LC3,
+    },
+};
 use num_traits::FromPrimitive;
-use std::io;
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    sync::mpsc::Sender,
+};
 use tui::backend::Backend;
 use tui::layout::{Constraint, Direction, Layout, Rect};
 use tui::style::{Color, Modifier, Style};
-use tui::widgets::{Block, Borders, Row, SelectableList, Table, Widget};
+use tui::widgets::{Block, Borders, Paragraph, Row, SelectableList, Table, Text, Widget};
 use tui::{Frame, Terminal};
 
+/// The number of zero words starting at the PC that are treated as an empty memory region
+///
+/// A single zero word is a valid instruction (a BR that never branches), but a run of them almost
+/// certainly means no program was loaded there.
+const EMPTY_REGION_WORDS: usize = 8;
+
+/// The bytes that separate the VM's state from the debugging session in a saved session,
+/// including the format version
+const SESSION_MAGIC: &[u8; 5] = b"LC3D\x01";
+
+/// The default number of steps that can be undone
+pub const DEFAULT_HISTORY_LIMIT: usize = 10_000;
+
+/// The number of words shown on each row of the memory panel
+const MEMORY_ROW_WORDS: usize = 4;
+
+/// The number of rows the memory cursor moves by when paging through memory
+pub const MEMORY_PAGE_ROWS: i32 = 16;
+
+/// What the text typed into the debugger's prompt is for
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PromptKind {
+    /// An address to move the memory cursor to
+    MemoryAddress,
+
+    /// A new value for a register, and whether to recompute the condition flag from it
+    ///
+    /// The PC takes an address and COND takes a flag, `n`, `z`, or `p`. Neither of them ever
+    /// recomputes the condition flag.
+    Register(Register, bool),
+
+    /// An address and a value to write there, or just a value to write at the memory cursor
+    MemoryWrite,
+
+    /// The path of a file to export the executed instructions to
+    ExportHistory,
+
+    /// The path of a file to save the debugging session to
+    SaveSession,
+
+    /// The path of a file to restore a debugging session from
+    LoadSession,
+
+    /// A line of keyboard input for the program
+    Input,
+}
+
+impl PromptKind {
+    /// The label shown in front of the text being typed
+    fn label(self) -> String {
+        match self {
+            PromptKind::MemoryAddress => String::from("go to address"),
+            PromptKind::Register(Register::PC, _) => String::from("set PC to address"),
+            PromptKind::Register(Register::COND, _) => String::from("set COND flag (n, z, or p)"),
+            PromptKind::Register(register, _) => format!("set {:?}", register),
+            PromptKind::MemoryWrite => String::from("write [address] value"),
+            PromptKind::ExportHistory => String::from("export history to"),
+            PromptKind::SaveSession => String::from("save session to"),
+            PromptKind::LoadSession => String::from("load session from"),
+            PromptKind::Input => String::from("type input"),
+        }
+    }
+}
+
+/// A line of text being typed into the debugger
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Prompt {
+    /// What the text is for
+    pub kind: PromptKind,
+
+    /// The text typed so far
+    pub text: String,
+}
+
+/// A watched memory address whose value was changed by the last instruction
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WatchpointHit {
+    /// The watched address
+    pub addr: u16,
+
+    /// The value before the instruction executed
+    pub old: u16,
+
+    /// The value after the instruction executed
+    pub new: u16,
+}
+
+impl fmt::Display for WatchpointHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "watchpoint {:#06x} changed {:#06x} -> {:#06x}",
+            self.addr, self.old, self.new
+        )
+    }
+}
+
+/// Everything needed to undo one step
+struct UndoStep {
+    /// The state of the VM before the step, other than memory
+    cpu: CpuState,
+
+    /// The address and previous value of every memory write the step made
+    writes: Vec<(u16, u16)>,
+
+    /// The length of the output before the step
+    output_len: usize,
+}
+
 /// A struct representing the state of the debugging TUI
 pub struct Debugger<'a> {
     /// A reference to the VM that is being monitored
     pub vm: &'a mut LC3,
 
-    /// A list of opcodes that have been executed so far
-    op_history: Vec<String>,
+    /// The address and disassembly of every instruction executed so far, followed by the
+    /// upcoming one
+    op_history: Vec<(u16, String)>,
+
+    /// Everything the program has written through the output trap routines
+    pub output: String,
+
+    /// Whether long lines in the output panel wrap to the panel width or are truncated
+    pub wrap_output: bool,
+
+    /// Whether the debugger is automatically stepping through the program ("play" mode)
+    pub playing: bool,
+
+    /// A status message shown in the execution panel until the next step, such as the result of
+    /// reloading the image
+    pub message: Option<String>,
+
+    /// The addresses that continuing and play mode stop at before executing the instruction there
+    pub breakpoints: HashSet<u16>,
+
+    /// The memory addresses that continuing and play mode stop at as soon as their value changes
+    pub watchpoints: Vec<u16>,
+
+    /// The watchpoint that fired on the last step, if any
+    pub watchpoint_hit: Option<WatchpointHit>,
+
+    /// The address selected in the memory panel, which is always kept in view
+    pub memory_cursor: u16,
+
+    /// The prompt keys are typed into instead of being treated as commands, while it is open
+    pub prompt: Option<Prompt>,
+
+    /// The most steps that can be undone. The oldest steps are forgotten past this.
+    pub history_limit: usize,
+
+    /// The steps that can be undone, oldest first
+    undo_history: VecDeque<UndoStep>,
+
+    /// Where keyboard input typed through the debugger goes, if the debugger owns the keyboard
+    keys: Option<Sender<u8>>,
+
+    /// Whether the last step didn't happen because the program is waiting for a key
+    pub waiting_for_input: bool,
 }
 
 impl<'a> Debugger<'a> {
     pub fn new(vm: &'a mut LC3) -> Self {
-        let next_op = format!("{:?}", vm.parse_next_op());
+        let next_op = next_instruction(vm);
+        let memory_cursor = vm.registers[Register::PC as usize];
+        // Capture program output so it can be rendered in its own panel instead of being written
+        // over the TUI
+        vm.captured_output = Some(Vec::new());
+        // Journal memory writes so that steps can be undone
+        vm.write_journal = Some(Vec::new());
+        vm.start();
         Self {
             vm,
             op_history: vec![next_op],
+            output: String::new(),
+            wrap_output: true,
+            playing: false,
+            message: None,
+            breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            memory_cursor,
+            prompt: None,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            undo_history: VecDeque::new(),
+            keys: None,
+            waiting_for_input: false,
         }
     }
 
+    /// Give the program keyboard input typed through the debugger instead of its input stream
+    ///
+    /// This is for when the debugger reads its commands from stdin, so the VM can't read from it
+    /// too. Steps are held back while the program waits for a key, rather than blocking the
+    /// debugger, until one is typed with `type_input`.
+    pub fn own_keyboard(&mut self) {
+        self.keys = Some(self.vm.input_sender());
+    }
+
+    /// Type `input` on the program's keyboard, if the debugger owns it
+    pub fn type_input(&mut self, input: &[u8]) {
+        match &self.keys {
+            Some(keys) => {
+                for &key in input {
+                    // The VM owns the receiver, so this can't fail
+                    let _ = keys.send(key);
+                }
+            }
+            None => {
+                self.message = Some(String::from("the program reads input from its own stream"))
+            }
+        }
+    }
+
+    /// Create a debugger with breakpoints already set at `breakpoints`, such as the ones given on
+    /// the command line
+    pub fn with_breakpoints<I: IntoIterator<Item = u16>>(vm: &'a mut LC3, breakpoints: I) -> Self {
+        let mut debugger = Self::new(vm);
+        debugger.breakpoints.extend(breakpoints);
+        debugger
+    }
+
     /// Perform an event tick on the debugger
     ///
+    /// Nothing is executed if the debugger owns the keyboard and the program is waiting for a key,
+    /// which is reported through `message` and `waiting_for_input`. Returns whether an instruction
+    /// was executed.
+    ///
     /// This performs an iteration on the VM. It will move forward the instruction by one step.
-    pub fn tick(&mut self, tables: &DispatchTables) {
-        self.vm.step(&tables);
-        let next_op = format!("{:?}", self.vm.parse_next_op());
+    pub fn tick(&mut self, tables: &DispatchTables) -> bool {
+        self.waiting_for_input = self.keys.is_some() && self.vm.waiting_for_key();
+        if self.waiting_for_input {
+            self.message = Some(String::from("waiting for input, press i to type it"));
+            return false;
+        }
+        // Memory is read directly so that watching a memory mapped register has no side effects
+        let watched: Vec<u16> = self
+            .watchpoints
+            .iter()
+            .map(|&addr| self.vm.memory[addr as usize])
+            .collect();
+        let cpu = self.vm.cpu_state();
+        let output_len = self.output.len();
+        // Only the writes made by this step belong in its undo record
+        self.vm.take_write_journal();
+        let result = self.vm.step(tables);
+        let next_op = next_instruction(self.vm);
         self.op_history.push(next_op);
+        let output = self.vm.take_captured_output();
+        self.output.push_str(&String::from_utf8_lossy(&output));
+
+        let memory = &self.vm.memory;
+        self.watchpoint_hit = self
+            .watchpoints
+            .iter()
+            .zip(watched)
+            .find(|&(&addr, old)| memory[addr as usize] != old)
+            .map(|(&addr, old)| WatchpointHit {
+                addr,
+                old,
+                new: memory[addr as usize],
+            });
+        self.message = match result {
+            Err(error) => Some(format!("stopped: {}", error)),
+            Ok(()) => self.watchpoint_hit.map(|hit| hit.to_string()),
+        };
+
+        if self.history_limit > 0 {
+            let writes = self.vm.take_write_journal();
+            self.undo_history.push_back(UndoStep {
+                cpu,
+                writes,
+                output_len,
+            });
+            while self.undo_history.len() > self.history_limit {
+                self.undo_history.pop_front();
+            }
+        }
+        true
+    }
+
+    /// Undo the last step, restoring the registers, memory, and output from before it
+    ///
+    /// Input the program consumed isn't given back. Returns false if there is no step to undo.
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.undo_history.pop_back() {
+            Some(step) => step,
+            None => return false,
+        };
+        // Undo the writes in reverse so the oldest value of each address wins. Memory is written
+        // directly so that undoing isn't subject to the checks made on stores.
+        for &(addr, old) in step.writes.iter().rev() {
+            self.vm.memory[addr as usize] = old;
+        }
+        self.vm.restore_cpu_state(step.cpu);
+        self.output.truncate(step.output_len);
+        // The history always keeps the upcoming instruction
+        if self.op_history.len() > 1 {
+            self.op_history.pop();
+        }
+        self.watchpoint_hit = None;
+        self.message = None;
+        true
+    }
+
+    /// Freeze a register so instructions can't change it, or unfreeze it if it is already frozen
+    pub fn toggle_freeze(&mut self, register: Register) {
+        let frozen = &mut self.vm.frozen_registers;
+        match frozen.iter().position(|&r| r == register) {
+            Some(idx) => {
+                frozen.remove(idx);
+            }
+            None => frozen.push(register),
+        }
+    }
+
+    /// Stop before the instruction at `addr` is executed when continuing or playing
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Remove the breakpoint at `addr`, returning whether there was one
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Set a breakpoint at `addr`, or remove it if one is already set there
+    pub fn toggle_breakpoint(&mut self, addr: u16) {
+        if !self.remove_breakpoint(addr) {
+            self.add_breakpoint(addr);
+        }
+    }
+
+    /// Stop as soon as the value at `addr` changes when continuing or playing, or stop watching it
+    /// if it is already being watched
+    pub fn toggle_watchpoint(&mut self, addr: u16) {
+        match self.watchpoints.iter().position(|&watched| watched == addr) {
+            Some(idx) => {
+                self.watchpoints.remove(idx);
+            }
+            None => self.watchpoints.push(addr),
+        }
+    }
+
+    /// Move the memory cursor by a number of rows, wrapping around the ends of memory
+    pub fn scroll_memory(&mut self, rows: i32) {
+        let words = rows.wrapping_mul(MEMORY_ROW_WORDS as i32);
+        self.memory_cursor = self.memory_cursor.wrapping_add(words as u16);
+    }
+
+    /// Start typing into the prompt
+    pub fn open_prompt(&mut self, kind: PromptKind) {
+        self.prompt = Some(Prompt {
+            kind,
+            text: String::new(),
+        });
+    }
+
+    /// Handle a key typed while the prompt is open
+    ///
+    /// Enter submits the text, escape closes the prompt, and backspace deletes the last character.
+    /// Text that can't be used is reported through `message`.
+    pub fn prompt_key(&mut self, key: char) {
+        let prompt = match &mut self.prompt {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        match key {
+            '\r' | '\n' => {
+                let kind = prompt.kind;
+                let text = prompt.text.trim().to_string();
+                self.prompt = None;
+                self.submit_prompt(kind, &text);
+            }
+            '\x1b' => self.prompt = None,
+            '\x7f' | '\x08' => {
+                prompt.text.pop();
+            }
+            c if !c.is_control() => prompt.text.push(c),
+            _ => (),
+        }
+    }
+
+    /// Act on the text submitted through the prompt
+    fn submit_prompt(&mut self, kind: PromptKind, text: &str) {
+        match kind {
+            PromptKind::MemoryAddress => match parse_number(text) {
+                Some(addr) if 0 <= addr && addr <= i32::from(u16::MAX) => {
+                    self.memory_cursor = addr as u16
+                }
+                _ => self.message = Some(format!("invalid address: {}", text)),
+            },
+            PromptKind::Register(Register::PC, _) => match parse_number(text) {
+                Some(addr) if 0 <= addr && addr <= i32::from(u16::MAX) => {
+                    self.vm.registers[Register::PC as usize] = addr as u16
+                }
+                _ => self.message = Some(format!("invalid address: {}", text)),
+            },
+            PromptKind::Register(Register::COND, _) => match parse_condition_flag(text) {
+                Some(flag) => self.vm.set_condition(flag),
+                None => self.message = Some(format!("invalid condition flag: {}", text)),
+            },
+            PromptKind::Register(register, update_cond) => match parse_word(text) {
+                Some(value) => {
+                    self.vm.registers[register as usize] = value;
+                    if update_cond {
+                        self.vm.set_condition(get_cond_flag(value));
+                    }
+                }
+                None => self.message = Some(format!("invalid value for {:?}: {}", register, text)),
+            },
+            PromptKind::MemoryWrite => {
+                let words: Vec<&str> = text.split_whitespace().collect();
+                let (addr, value) = match words.as_slice() {
+                    [value] => (Some(i32::from(self.memory_cursor)), *value),
+                    [addr, value] => (parse_number(addr), *value),
+                    _ => {
+                        self.message = Some(format!("expected [address] value: {}", text));
+                        return;
+                    }
+                };
+                let addr = match addr {
+                    Some(addr) if 0 <= addr && (addr as usize) < MEMORY_LIMIT => addr as u16,
+                    _ => {
+                        self.message = Some(format!("invalid address: {}", words[0]));
+                        return;
+                    }
+                };
+                match parse_word(value) {
+                    Some(value) => self.write_memory(addr, value),
+                    None => self.message = Some(format!("invalid value: {}", value)),
+                }
+            }
+            PromptKind::ExportHistory => {
+                let exported = File::create(text).and_then(|file| {
+                    let mut writer = io::BufWriter::new(file);
+                    self.export_history(&mut writer)?;
+                    writer.flush()
+                });
+                self.message = Some(match exported {
+                    Ok(()) => format!("exported history to {}", text),
+                    Err(e) => format!("could not export history: {}", e),
+                });
+            }
+            PromptKind::Input => {
+                let mut input = text.as_bytes().to_vec();
+                input.push(b'\n');
+                self.type_input(&input);
+            }
+            PromptKind::SaveSession => {
+                self.message = Some(match self.save_session(Path::new(text)) {
+                    Ok(()) => format!("saved session to {}", text),
+                    Err(e) => format!("could not save session: {}", e),
+                });
+            }
+            PromptKind::LoadSession => {
+                self.message = Some(match self.load_session(Path::new(text)) {
+                    Ok(()) => format!("loaded session from {}", text),
+                    Err(e) => format!("could not load session: {}", e),
+                });
+            }
+        }
+    }
+
+    /// Save the VM's state and the debugging session to a file so it can be resumed with
+    /// `load_session`
+    ///
+    /// The file starts with everything `LC3::save_state` saves, followed by the breakpoints,
+    /// watchpoints, op history, output, and memory cursor. The undo history isn't saved.
+    pub fn save_session(&self, path: &Path) -> io::Result<()> {
+        let mut buf = self.vm.encode_state();
+        buf.extend_from_slice(SESSION_MAGIC);
+        buf.extend_from_slice(&self.memory_cursor.to_be_bytes());
+        let mut breakpoints: Vec<u16> = self.breakpoints.iter().copied().collect();
+        breakpoints.sort_unstable();
+        for addrs in &[&breakpoints, &self.watchpoints] {
+            buf.extend_from_slice(&(addrs.len() as u32).to_be_bytes());
+            buf.extend(addrs.iter().flat_map(|addr| addr.to_be_bytes()));
+        }
+        buf.extend_from_slice(&(self.op_history.len() as u32).to_be_bytes());
+        for (addr, instr) in &self.op_history {
+            buf.extend_from_slice(&addr.to_be_bytes());
+            push_string(&mut buf, instr);
+        }
+        push_string(&mut buf, &self.output);
+        File::create(path)?.write_all(&buf)
+    }
+
+    /// Restore the VM's state and the debugging session from a file written by `save_session`
+    ///
+    /// Nothing is changed if the file can't be read or the VM's state in it is malformed. If only
+    /// the session is malformed, the VM's state is still restored.
+    pub fn load_session(&mut self, path: &Path) -> Result<(), LoadError> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let mut session = SessionReader(self.vm.decode_state(&buf)?);
+        if session.take(SESSION_MAGIC.len())? != SESSION_MAGIC {
+            return Err(LoadError::InvalidSnapshot);
+        }
+        let memory_cursor = session.u16()?;
+        let breakpoints = session.addresses()?;
+        let watchpoints = session.addresses()?;
+        let mut op_history = Vec::new();
+        for _ in 0..session.u32()? {
+            op_history.push((session.u16()?, session.string()?));
+        }
+        let output = session.string()?;
+
+        // The upcoming instruction is always the last entry
+        if op_history.is_empty() {
+            op_history.push(next_instruction(self.vm));
+        }
+        self.memory_cursor = memory_cursor;
+        self.breakpoints = breakpoints.into_iter().collect();
+        self.watchpoints = watchpoints;
+        self.op_history = op_history;
+        self.output = output;
+        self.watchpoint_hit = None;
+        self.playing = false;
+        self.undo_history.clear();
+        Ok(())
+    }
+
+    /// Write the instructions executed so far as an assembly listing, oldest first
+    ///
+    /// Each line holds the address of an instruction and its disassembly. Instructions that were
+    /// undone aren't included, and neither is the upcoming instruction, which hasn't run yet.
+    pub fn export_history<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let executed = &self.op_history[..self.op_history.len().saturating_sub(1)];
+        writeln!(writer, "; {} executed instructions", executed.len())?;
+        for (addr, instr) in executed {
+            writeln!(writer, "x{:04X}  {}", addr, instr)?;
+        }
+        Ok(())
+    }
+
+    /// Overwrite a word of memory, such as to patch an instruction in place
+    ///
+    /// The write goes through `mem_write`, so it is subject to the same checks as a store.
+    pub fn write_memory(&mut self, addr: u16, value: u16) {
+        self.vm.mem_write(addr, value);
+        // The upcoming instruction may have been patched
+        let next_op = next_instruction(self.vm);
+        if let Some(last) = self.op_history.last_mut() {
+            *last = next_op;
+        }
+    }
+
+    /// Whether the PC sits on a breakpoint
+    pub fn at_breakpoint(&self) -> bool {
+        self.breakpoints
+            .contains(&self.vm.registers[Register::PC as usize])
+    }
+
+    /// Whether execution should stop because of a breakpoint or a watchpoint
+    fn should_stop(&self) -> bool {
+        self.at_breakpoint() || self.watchpoint_hit.is_some() || self.waiting_for_input
+    }
+
+    /// Reload the program image while keeping the registers and the rest of memory
+    ///
+    /// The outcome is reported through `message`, including a warning if the reload changed the
+    /// code at the PC.
+    pub fn reload(&mut self, image: &[u8]) {
+        self.message = Some(match self.vm.reload_image(image) {
+            Ok(false) => String::from("reloaded image"),
+            Ok(true) => String::from("reloaded image, but the code at the PC changed"),
+            Err(e) => format!("reload failed: {}", e),
+        });
+        // The upcoming instruction may be different now
+        let next_op = next_instruction(self.vm);
+        if let Some(last) = self.op_history.last_mut() {
+            *last = next_op;
+        }
+    }
+
+    /// Tick the debugger up to `count` times, stopping early if the VM halts, the PC reaches a
+    /// breakpoint, or a watchpoint fires
+    ///
+    /// Every intermediate step is recorded in the op history, but nothing is drawn, so the caller
+    /// only has to redraw once at the end. Returns the number of instructions that were executed.
+    pub fn tick_n(&mut self, tables: &DispatchTables, count: usize) -> usize {
+        let mut executed = 0;
+        while executed < count && self.vm.is_running() {
+            if !self.tick(tables) {
+                break;
+            }
+            executed += 1;
+
+            if self.should_stop() {
+                break;
+            }
+        }
+        executed
+    }
+
+    /// Start or stop automatically stepping through the program
+    ///
+    /// Play mode can't be started once the program has halted.
+    pub fn toggle_play(&mut self) {
+        self.playing = !self.playing && self.vm.is_running();
+    }
+
+    /// Execute one instruction if the debugger is in play mode
+    ///
+    /// Play mode stops by itself once the program halts. The caller is responsible for calling
+    /// this at the desired rate. Returns whether an instruction was executed.
+    pub fn play_tick(&mut self, tables: &DispatchTables) -> bool {
+        if !self.playing {
+            return false;
+        }
+        let executed = self.tick(tables);
+        if !self.vm.is_running() || self.should_stop() {
+            self.playing = false;
+        }
+        executed
+    }
+
+    /// Run until the PC hits a breakpoint, a watchpoint fires, or the VM halts, executing at most
+    /// `max_steps` instructions
+    ///
+    /// At least one instruction is always executed, so continuing from a breakpoint moves on to
+    /// the next one. Returns the number of instructions that were executed.
+    pub fn continue_to_breakpoint(&mut self, tables: &DispatchTables, max_steps: usize) -> usize {
+        let mut executed = 0;
+        while executed < max_steps && self.vm.is_running() {
+            if !self.tick(tables) {
+                return executed;
+            }
+            executed += 1;
+
+            if self.should_stop() {
+                return executed;
+            }
+        }
+        if self.vm.is_running() {
+            self.message = Some(format!("paused after {} instructions", executed));
+        }
+        executed
+    }
+
+    /// Run until the next TRAP instruction, executing at most `max_steps` instructions
+    ///
+    /// If `stop_after` is false, execution stops right before the next trap so the state leading
+    /// up to it can be inspected. Otherwise the trap is executed and execution stops right after
+    /// it. Execution also stops at a breakpoint or watchpoint. At least one instruction is always
+    /// executed, so repeating this moves from one trap to the next. Returns the number of
+    /// instructions that were executed.
+    pub fn run_to_trap(
+        &mut self,
+        tables: &DispatchTables,
+        stop_after: bool,
+        max_steps: usize,
+    ) -> usize {
+        let mut executed = 0;
+        while executed < max_steps && self.vm.is_running() {
+            let was_at_trap = self.at_trap();
+            if !self.tick(tables) {
+                break;
+            }
+            executed += 1;
+
+            if (stop_after && was_at_trap) || (!stop_after && self.at_trap()) || self.should_stop()
+            {
+                break;
+            }
+        }
+        executed
+    }
+
+    /// Whether the PC sits in a region of zeroed memory, meaning no program is loaded there
+    ///
+    /// Zeroed memory decodes to BR instructions that never branch, so without this the VM would
+    /// silently step through it as if it were a program.
+    pub fn in_empty_region(&self) -> bool {
+        let pc = self.vm.registers[Register::PC as usize] as usize;
+        self.vm
+            .memory
+            .iter()
+            .skip(pc)
+            .take(EMPTY_REGION_WORDS)
+            .all(|&word| word == 0)
+    }
+
+    /// Whether the instruction at the program counter is a TRAP
+    fn at_trap(&self) -> bool {
+        match self.vm.parse_next_op() {
+            Operation::Trap(_) => true,
+            Operation::Op(_) => false,
+        }
     }
 }
 
+/// Append a string to a saved session, preceded by its length
+fn push_string(buf: &mut Vec<u8>, text: &str) {
+    buf.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    buf.extend_from_slice(text.as_bytes());
+}
+
+/// Reads the values written by `Debugger::save_session` from the front of a buffer
+struct SessionReader<'a>(&'a [u8]);
+
+impl<'a> SessionReader<'a> {
+    /// Split `len` bytes off of the front of the buffer
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if self.0.len() < len {
+            return Err(LoadError::InvalidSnapshot);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    /// A big-endian 16-bit number
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A big-endian 32-bit number
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A list of addresses, preceded by its length
+    fn addresses(&mut self) -> Result<Vec<u16>, LoadError> {
+        (0..self.u32()?).map(|_| self.u16()).collect()
+    }
+
+    /// A string, preceded by its length
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| LoadError::InvalidSnapshot)
+    }
+}
+
+/// Parse a 16-bit value typed into the prompt, storing negative numbers in two's complement
+fn parse_word(text: &str) -> Option<u16> {
+    parse_number(text)
+        .filter(|&value| i32::from(i16::MIN) <= value && value <= i32::from(u16::MAX))
+        .map(|value| value as u16)
+}
+
+/// The address and disassembly of the instruction at the PC, for the instruction history
+fn next_instruction(vm: &LC3) -> (u16, String) {
+    let pc = vm.registers[Register::PC as usize];
+    (pc, disassemble(vm.memory[pc as usize]))
+}
+
 /// The main drawing routine for the UI
 pub fn draw<B: Backend>(terminal: &mut Terminal<B>, app: &Debugger) -> Result<(), io::Error> {
     terminal.draw(|mut f| {
@@ -47,16 +783,61 @@ pub fn draw<B: Backend>(terminal: &mut Terminal<B>, app: &Debugger) -> Result<()
             .margin(1)
             .constraints([Constraint::Percentage(20), Constraint::Percentage(80)].as_ref())
             .split(f.size());
+        let registers_title = match app.vm.stack_usage {
+            Some(usage) => format!(
+                "Registers ({} instructions, stack depth {}, peak {})",
+                app.vm.instruction_count(),
+                usage.depth,
+                usage.peak
+            ),
+            None => format!("Registers ({} instructions)", app.vm.instruction_count()),
+        };
         Block::default()
-            .title("Registers")
+            .title(&registers_title)
             .borders(Borders::ALL)
             .render(&mut f, chunks[0]);
+        let bottom_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(
+                [
+                    Constraint::Percentage(35),
+                    Constraint::Percentage(35),
+                    Constraint::Percentage(30),
+                ]
+                .as_ref(),
+            )
+            .split(chunks[1]);
+        let execution_title = match app.vm.effective_address() {
+            _ if app.in_empty_region() => String::from("Execution (no program at PC)"),
+            Some(EffectiveAddress::Direct(addr)) => format!("Execution (address {:#06x})", addr),
+            Some(EffectiveAddress::Indirect(ptr, addr)) => {
+                format!("Execution (address {:#06x} -> {:#06x})", ptr, addr)
+            }
+            None => String::from("Execution"),
+        };
+        let execution_title = match (&app.prompt, &app.message) {
+            (Some(prompt), _) => format!(
+                "{} - {}: {}_",
+                execution_title,
+                prompt.kind.label(),
+                prompt.text
+            ),
+            (None, Some(message)) => format!("{} - {}", execution_title, message),
+            (None, None) => execution_title,
+        };
         Block::default()
-            .title("Execution")
+            .title(&execution_title)
             .borders(Borders::ALL)
-            .render(&mut f, chunks[1]);
-        draw_registers(&mut f, &app, chunks[0]);
-        draw_instr_history(&mut f, &app, chunks[1]);
+            .render(&mut f, bottom_chunks[0]);
+        draw_registers(&mut f, app, chunks[0]);
+        draw_instr_history(&mut f, app, bottom_chunks[0]);
+        draw_memory(&mut f, app, bottom_chunks[1]);
+        let side_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(70), Constraint::Percentage(30)].as_ref())
+            .split(bottom_chunks[2]);
+        draw_output(&mut f, app, side_chunks[0]);
+        draw_breakpoints(&mut f, app, side_chunks[1]);
     })
 }
 
@@ -81,7 +862,8 @@ fn draw_registers<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect) {
 /// Draw an individual register debugging block
 ///
 /// `register_idx` is the index of the register to print. This UI element prints the value of the
-/// register in binary, hex, and as a regular integer in a vertical table.
+/// register in binary, hex, and as a regular integer in a vertical table. COND also shows which of
+/// the N, Z, and P flags are set.
 fn draw_register<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect, register_idx: usize) {
     let register_value = app.vm.registers[register_idx];
 
@@ -93,17 +875,43 @@ fn draw_register<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect, regis
         format!("{}", register_value),
     ];
     let register_enum: Register = FromPrimitive::from_usize(register_idx).unwrap();
-    let register_name = format!("{:?}", register_enum);
+    let register_name = if app.vm.frozen_registers.contains(&register_enum) {
+        format!("{:?} (frozen)", register_enum)
+    } else {
+        format!("{:?}", register_enum)
+    };
 
-    SelectableList::default()
-        .block(
-            Block::default()
-                .title(&register_name)
-                .title_style(Style::default().modifier(Modifier::BOLD))
-                .borders(Borders::ALL),
-        )
-        .items(&register_strings)
-        .render(f, area);
+    let block = Block::default()
+        .title(&register_name)
+        .title_style(Style::default().modifier(Modifier::BOLD))
+        .borders(Borders::ALL);
+
+    if register_enum != Register::COND {
+        SelectableList::default()
+            .block(block)
+            .items(&register_strings)
+            .render(f, area);
+        return;
+    }
+
+    // Highlight the flags that are set, which are the ones a branch tests
+    let mut text: Vec<Text> = register_strings
+        .iter()
+        .map(|line| Text::raw(format!("{}\n", line)))
+        .collect();
+    for &(flag, name) in &[
+        (ConditionFlag::NEG, "N"),
+        (ConditionFlag::ZRO, "Z"),
+        (ConditionFlag::POS, "P"),
+    ] {
+        let style = if register_value & flag as u16 != 0 {
+            Style::default().fg(Color::Green).modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(Color::DarkGray)
+        };
+        text.push(Text::styled(format!("{} ", name), style));
+    }
+    Paragraph::new(text.iter()).block(block).render(f, area);
 }
 
 /// Maintains a list of the instruction/opcode history and displays the next one to the user
@@ -116,7 +924,7 @@ fn draw_instr_history<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect)
         .op_history
         .iter()
         .enumerate()
-        .map(|(idx, item)| vec![format!("{}", idx), item.clone()])
+        .map(|(idx, (addr, instr))| vec![format!("{}", idx), format!("x{:04X} {}", addr, instr)])
         .collect();
     // Make the last instruction (the current one) bold
     let rows = row_data.iter().rev().enumerate().map(|(idx, item)| {
@@ -134,7 +942,464 @@ fn draw_instr_history<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect)
     Table::new(headers.iter(), rows)
         .header_style(Style::default().modifier(Modifier::BOLD))
         .block(Block::default().borders(Borders::NONE))
-        .widths(&[10, 10])
+        .widths(&[10, 26])
         .column_spacing(2)
         .render(f, rects[0]);
 }
+
+/// Displays a window of memory around the memory cursor as hex words and their ASCII characters
+///
+/// The word under the cursor is shown in reverse video and the word at the PC in green. Each
+/// ASCII character comes from the low byte of a word, since that's how strings are stored.
+fn draw_memory<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect) {
+    let memory = &app.vm.memory;
+    let pc = app.vm.registers[Register::PC as usize] as usize;
+    let cursor = app.memory_cursor as usize;
+    // Account for the borders on each side
+    let visible_rows = area.height.saturating_sub(2) as usize;
+    let total_rows = memory.len().div_ceil(MEMORY_ROW_WORDS);
+    // Keep the cursor in the middle of the panel unless it's near either end of memory
+    let first_row = (cursor / MEMORY_ROW_WORDS)
+        .saturating_sub(visible_rows / 2)
+        .min(total_rows.saturating_sub(visible_rows));
+    let last_row = (first_row + visible_rows).min(total_rows);
+
+    let mut text = Vec::new();
+    for row in first_row..last_row {
+        let start = row * MEMORY_ROW_WORDS;
+        let words = &memory[start..(start + MEMORY_ROW_WORDS).min(memory.len())];
+        text.push(Text::raw(format!("{:04x}:", start)));
+        for (offset, word) in words.iter().enumerate() {
+            let style = match start + offset {
+                addr if addr == cursor => Style::default().modifier(Modifier::REVERSED),
+                addr if addr == pc => Style::default().fg(Color::Green),
+                _ => Style::default(),
+            };
+            text.push(Text::raw(" "));
+            text.push(Text::styled(format!("{:04x}", word), style));
+        }
+        let ascii: String = words
+            .iter()
+            .map(|&word| match word as u8 {
+                c if c.is_ascii_graphic() || c == b' ' => c as char,
+                _ => '.',
+            })
+            .collect();
+        text.push(Text::raw(format!("  {}\n", ascii)));
+    }
+
+    let title = format!("Memory (cursor {:#06x})", app.memory_cursor);
+    Paragraph::new(text.iter())
+        .block(Block::default().title(&title).borders(Borders::ALL))
+        .render(f, area);
+}
+
+/// Displays the output the program has written so far
+///
+/// Only the most recent lines that fit in the panel are shown, so the panel scrolls along with the
+/// output as the program runs.
+fn draw_output<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect) {
+    // Account for the borders on each side
+    let visible_lines = area.height.saturating_sub(2) as usize;
+    let width = area.width.saturating_sub(2) as usize;
+    let lines = if app.wrap_output {
+        wrap_lines(&app.output, width)
+    } else {
+        // The paragraph truncates lines that are too long to fit
+        app.output.lines().map(String::from).collect()
+    };
+    let start = lines.len().saturating_sub(visible_lines);
+    let text = [Text::raw(lines[start..].join("\n"))];
+
+    Paragraph::new(text.iter())
+        .block(Block::default().title("Output").borders(Borders::ALL))
+        .render(f, area);
+}
+
+/// Lists the breakpoints in address order along with the instruction at each one, followed by the
+/// watchpoints and their current values
+///
+/// The breakpoint the PC is currently stopped at or the watchpoint that just fired is highlighted.
+fn draw_breakpoints<B: Backend>(f: &mut Frame<B>, app: &Debugger, area: Rect) {
+    let pc = app.vm.registers[Register::PC as usize];
+    let mut addresses: Vec<u16> = app.breakpoints.iter().cloned().collect();
+    addresses.sort();
+    let mut items: Vec<String> = addresses
+        .iter()
+        .map(|&addr| {
+            format!(
+                "{:#06x}  {}",
+                addr,
+                disassemble(app.vm.memory[addr as usize])
+            )
+        })
+        .collect();
+    items.extend(app.watchpoints.iter().map(|&addr| {
+        format!(
+            "{:#06x}  watch = {:#06x}",
+            addr, app.vm.memory[addr as usize]
+        )
+    }));
+    let selected = match app.watchpoint_hit {
+        Some(hit) => app
+            .watchpoints
+            .iter()
+            .position(|&addr| addr == hit.addr)
+            .map(|idx| addresses.len() + idx),
+        None => addresses.iter().position(|&addr| addr == pc),
+    };
+
+    SelectableList::default()
+        .block(
+            Block::default()
+                .title("Breakpoints and watchpoints")
+                .borders(Borders::ALL),
+        )
+        .items(&items)
+        .select(selected)
+        .highlight_style(Style::default().fg(Color::Red).modifier(Modifier::BOLD))
+        .render(f, area);
+}
+
+/// Parse a condition flag typed as `n`, `z`, or `p`, or as its name such as `NEG`
+fn parse_condition_flag(text: &str) -> Option<ConditionFlag> {
+    match text.to_uppercase().as_str() {
+        "N" | "NEG" => Some(ConditionFlag::NEG),
+        "Z" | "ZRO" => Some(ConditionFlag::ZRO),
+        "P" | "POS" => Some(ConditionFlag::POS),
+        _ => None,
+    }
+}
+
+/// Split text into display rows that are at most `width` characters wide
+///
+/// Lines longer than `width` are broken into several rows at character boundaries, while empty
+/// lines are kept as empty rows.
+pub fn wrap_lines(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+
+    for line in text.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            rows.push(String::new());
+        }
+        rows.extend(
+            chars
+                .chunks(width)
+                .map(|row| row.iter().collect::<String>()),
+        );
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, lc3::test_support::load};
+    use std::fs;
+
+    #[test]
+    fn tick_captures_output_into_panel() {
+        let mut vm = load(".ORIG x3000\nLD R0, CHAR\nOUT\nHALT\nCHAR .FILL x41\n.END");
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.tick(&tables);
+        assert_eq!(debugger.output, "");
+        debugger.tick(&tables);
+        assert_eq!(debugger.output, "A");
+        // Nothing is left behind in the VM for anything else to write to the terminal
+        assert!(debugger.vm.take_captured_output().is_empty());
+    }
+
+    /// A program that counts up in R1 forever
+    const COUNTER: &str = ".ORIG x3000\nLOOP ADD R1, R1, #1\nBRnzp LOOP\n.END";
+
+    #[test]
+    fn tick_n_steps_exactly_count() {
+        let mut vm = load(COUNTER);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        assert_eq!(debugger.tick_n(&tables, 5), 5);
+        assert_eq!(debugger.vm.instruction_count(), 5);
+        assert_eq!(debugger.vm.registers[Register::R1 as usize], 3);
+    }
+
+    #[test]
+    fn tick_n_stops_at_breakpoint() {
+        let mut vm = load(COUNTER);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.add_breakpoint(0x3001);
+        assert_eq!(debugger.tick_n(&tables, 5), 1);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3001);
+    }
+
+    #[test]
+    fn starts_with_given_breakpoints() {
+        let mut vm = load(COUNTER);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::with_breakpoints(&mut vm, vec![0x3001, 0x4000]);
+        let expected: HashSet<u16> = [0x3001, 0x4000].iter().copied().collect();
+        assert_eq!(debugger.breakpoints, expected);
+        debugger.continue_to_breakpoint(&tables, 100);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3001);
+    }
+
+    #[test]
+    fn run_to_trap_stops_at_puts() {
+        let mut vm =
+            load(".ORIG x3000\nADD R1, R1, #1\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"Hi\"\n.END");
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        assert_eq!(debugger.run_to_trap(&tables, false, 100), 2);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3002);
+        assert_eq!(debugger.output, "");
+
+        assert_eq!(debugger.run_to_trap(&tables, true, 100), 1);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3003);
+        assert_eq!(debugger.output, "Hi");
+    }
+
+    #[test]
+    fn wrap_lines_breaks_long_lines() {
+        let rows = wrap_lines(&format!("{}\n\nend", "x".repeat(25)), 10);
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0], "x".repeat(10));
+        assert_eq!(rows[2], "xxxxx");
+        assert_eq!(rows[3], "");
+        assert_eq!(rows[4], "end");
+    }
+
+    /// Type `text` into the open prompt and submit it
+    fn submit(debugger: &mut Debugger, text: &str) {
+        for key in text.chars().chain(Some('\n')) {
+            debugger.prompt_key(key);
+        }
+    }
+
+    #[test]
+    fn editing_cond_takes_a_flag() {
+        let mut vm = load(COUNTER);
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.open_prompt(PromptKind::Register(Register::COND, true));
+        submit(&mut debugger, "n");
+        assert_eq!(debugger.vm.condition_flags(), ConditionFlag::NEG);
+
+        // A raw value isn't a flag, so COND is left alone
+        debugger.open_prompt(PromptKind::Register(Register::COND, true));
+        submit(&mut debugger, "6");
+        assert_eq!(debugger.vm.condition_flags(), ConditionFlag::NEG);
+        assert!(debugger.message.is_some());
+    }
+
+    #[test]
+    fn editing_pc_keeps_flags() {
+        let mut vm = load(COUNTER);
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.open_prompt(PromptKind::Register(Register::PC, true));
+        submit(&mut debugger, "x3005");
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3005);
+        assert_eq!(debugger.vm.condition_flags(), ConditionFlag::ZRO);
+    }
+
+    #[test]
+    fn empty_image_is_flagged() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.load_words(&[0x3000]).unwrap();
+        let debugger = Debugger::new(&mut vm);
+        assert!(debugger.in_empty_region());
+
+        let mut vm = load(COUNTER);
+        let debugger = Debugger::new(&mut vm);
+        assert!(!debugger.in_empty_region());
+    }
+
+    #[test]
+    fn reload_replaces_code_and_keeps_registers() {
+        let mut vm = load(COUNTER);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.tick_n(&tables, 4);
+        assert_eq!(debugger.vm.registers[Register::R1 as usize], 2);
+
+        let image = assembler::assemble(".ORIG x3000\nLOOP ADD R1, R1, #2\nBRnzp LOOP\n.END");
+        debugger.reload(&assembler::to_bytes(&image.unwrap()));
+        assert_eq!(
+            debugger.message.as_deref(),
+            Some("reloaded image, but the code at the PC changed")
+        );
+        assert_eq!(debugger.vm.registers[Register::R1 as usize], 2);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3000);
+
+        debugger.tick(&tables);
+        assert_eq!(debugger.vm.registers[Register::R1 as usize], 4);
+    }
+
+    #[test]
+    fn session_round_trips() {
+        let path = std::env::temp_dir().join(format!("lc3-session-{}.state", std::process::id()));
+        let mut vm = load(COUNTER);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::with_breakpoints(&mut vm, vec![0x3001, 0x3005]);
+        debugger.toggle_watchpoint(0x4000);
+        debugger.tick(&tables);
+        debugger.tick(&tables);
+        debugger.tick(&tables);
+        debugger.memory_cursor = 0x3100;
+        debugger.save_session(&path).unwrap();
+
+        let mut restored_vm = load(COUNTER);
+        let mut restored = Debugger::new(&mut restored_vm);
+        restored.load_session(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.breakpoints, debugger.breakpoints);
+        assert_eq!(restored.watchpoints, [0x4000]);
+        assert_eq!(restored.op_history, debugger.op_history);
+        assert_eq!(restored.op_history.len(), 4);
+        assert_eq!(restored.memory_cursor, 0x3100);
+        assert_eq!(restored.vm.registers, debugger.vm.registers);
+        assert_eq!(restored.vm.instruction_count(), 3);
+    }
+
+    #[test]
+    fn frozen_register_keeps_its_value() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #5\nADD R1, R1, #1\nADD R0, R0, #2\n.END");
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.toggle_freeze(Register::R0);
+        debugger.tick(&tables);
+        debugger.tick(&tables);
+        assert_eq!(debugger.vm.registers[Register::R0 as usize], 0);
+        assert_eq!(debugger.vm.registers[Register::R1 as usize], 1);
+
+        debugger.toggle_freeze(Register::R0);
+        assert!(debugger.vm.frozen_registers.is_empty());
+        debugger.tick(&tables);
+        assert_eq!(debugger.vm.registers[Register::R0 as usize], 2);
+    }
+
+    #[test]
+    fn steps_wait_for_typed_input() {
+        let mut vm = load(".ORIG x3000\nGETC\nOUT\nHALT\n.END");
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.own_keyboard();
+        assert_eq!(debugger.continue_to_breakpoint(&tables, 100), 0);
+        assert!(debugger.waiting_for_input);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3000);
+
+        debugger.open_prompt(PromptKind::Input);
+        submit(&mut debugger, "y");
+        assert_eq!(debugger.continue_to_breakpoint(&tables, 100), 3);
+        assert!(!debugger.waiting_for_input);
+        assert_eq!(debugger.output, "yHALT");
+    }
+
+    #[test]
+    fn play_toggles_and_stops_at_breakpoint() {
+        let mut vm = load(COUNTER);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        assert!(!debugger.play_tick(&tables));
+        debugger.toggle_play();
+        assert!(debugger.playing);
+        debugger.toggle_play();
+        assert!(!debugger.playing);
+
+        debugger.add_breakpoint(0x3001);
+        debugger.toggle_play();
+        assert!(debugger.play_tick(&tables));
+        assert!(!debugger.playing);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3001);
+        assert!(!debugger.play_tick(&tables));
+    }
+
+    #[test]
+    fn export_history_lists_executed_instructions() {
+        let mut vm = load(COUNTER);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.tick_n(&tables, 3);
+        debugger.step_back();
+
+        let mut listing = Vec::new();
+        debugger.export_history(&mut listing).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "; 2 executed instructions\nx3000  ADD R1, R1, #1\nx3001  BRnzp #-2\n"
+        );
+    }
+
+    #[test]
+    fn memory_prompt_patches_words() {
+        let mut vm = load(COUNTER);
+        let mut debugger = Debugger::new(&mut vm);
+        // Patch the branch into a HALT
+        debugger.open_prompt(PromptKind::MemoryWrite);
+        submit(&mut debugger, "x3001 xF025");
+        assert_eq!(debugger.vm.memory[0x3001], 0xF025);
+        assert_eq!(debugger.message, None);
+
+        // A lone value is written at the memory cursor, and negative values are two's complement
+        debugger.memory_cursor = 0x4000;
+        debugger.open_prompt(PromptKind::MemoryWrite);
+        submit(&mut debugger, "#-1");
+        assert_eq!(debugger.vm.memory[0x4000], 0xFFFF);
+
+        debugger.open_prompt(PromptKind::MemoryWrite);
+        submit(&mut debugger, "x10000 #1");
+        assert_eq!(debugger.message.as_deref(), Some("invalid address: x10000"));
+
+        debugger.open_prompt(PromptKind::MemoryWrite);
+        submit(&mut debugger, "x3000 #70000");
+        assert_eq!(debugger.message.as_deref(), Some("invalid value: #70000"));
+        assert_eq!(debugger.vm.memory[0x3000], 0x1261);
+    }
+
+    #[test]
+    fn step_back_undoes_registers_memory_and_output() {
+        let source =
+            ".ORIG x3000\nLD R0, CHAR\nST R0, COPY\nOUT\nHALT\nCHAR .FILL x41\nCOPY .FILL 0\n.END";
+        let mut vm = load(source);
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        for _ in 0..3 {
+            debugger.tick(&tables);
+        }
+        assert_eq!(debugger.vm.memory[0x3005], 0x41);
+        assert_eq!(debugger.output, "A");
+
+        assert!(debugger.step_back());
+        assert!(debugger.step_back());
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3001);
+        assert_eq!(debugger.vm.registers[Register::R0 as usize], 0x41);
+        assert_eq!(debugger.vm.memory[0x3005], 0);
+        assert_eq!(debugger.output, "");
+        assert_eq!(debugger.op_history.len(), 2);
+
+        // Stepping forward again replays the same instructions
+        debugger.tick(&tables);
+        assert_eq!(debugger.vm.memory[0x3005], 0x41);
+    }
+
+    #[test]
+    fn steps_wait_for_typed_input_read_from_kbdr() {
+        let mut vm = load(".ORIG x3000\nLDI R0, KBDR\nHALT\nKBDR .FILL xFE02\n.END");
+        let tables = DispatchTables::new();
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.own_keyboard();
+        assert_eq!(debugger.continue_to_breakpoint(&tables, 100), 0);
+        assert!(debugger.waiting_for_input);
+        assert_eq!(debugger.vm.registers[Register::PC as usize], 0x3000);
+
+        debugger.open_prompt(PromptKind::Input);
+        submit(&mut debugger, "y");
+        assert_eq!(debugger.continue_to_breakpoint(&tables, 100), 2);
+        assert!(!debugger.waiting_for_input);
+        assert_eq!(
+            debugger.vm.registers[Register::R0 as usize],
+            u16::from(b'y')
+        );
+    }
+}
@@ -0,0 +1,167 @@
+/// A disassembler that renders memory words as LC3 assembly.
+///
+/// The output uses the same syntax the assembler accepts, with PC relative operands written as
+/// offsets (`BRnz #-3`) since there are no labels to refer to. Words that aren't valid
+/// instructions are rendered as `.FILL` directives.
+use crate::lc3::{
+    consts::{Register, Trap},
+    instruction::decode::{decode, Instruction, Operand},
+};
+use num_traits::FromPrimitive;
+use std::ops::RangeInclusive;
+
+/// Render a single word as an LC3 instruction
+pub fn disassemble(word: u16) -> String {
+    match decode(word) {
+        // A branch without condition flags is never taken, and only the zero word has a mnemonic
+        Instruction::BR {
+            n: false,
+            z: false,
+            p: false,
+            offset: 0,
+        } => String::from("NOP"),
+        Instruction::BR {
+            n: false,
+            z: false,
+            p: false,
+            ..
+        } => format!(".FILL x{:04X}", word),
+        Instruction::BR { n, z, p, offset } => {
+            let flags: String = [(n, 'n'), (z, 'z'), (p, 'p')]
+                .iter()
+                .filter(|&&(set, _)| set)
+                .map(|&(_, flag)| flag)
+                .collect();
+            format!("BR{} #{}", flags, offset)
+        }
+        Instruction::ADD { dr, sr1, operand } => {
+            format!("ADD {:?}, {:?}, {}", dr, sr1, render_operand(operand))
+        }
+        Instruction::AND { dr, sr1, operand } => {
+            format!("AND {:?}, {:?}, {}", dr, sr1, render_operand(operand))
+        }
+        Instruction::NOT { dr, sr } => format!("NOT {:?}, {:?}", dr, sr),
+        Instruction::LD { dr, offset } => format!("LD {:?}, #{}", dr, offset),
+        Instruction::LDI { dr, offset } => format!("LDI {:?}, #{}", dr, offset),
+        Instruction::LEA { dr, offset } => format!("LEA {:?}, #{}", dr, offset),
+        Instruction::ST { sr, offset } => format!("ST {:?}, #{}", sr, offset),
+        Instruction::STI { sr, offset } => format!("STI {:?}, #{}", sr, offset),
+        Instruction::LDR { dr, base, offset } => {
+            format!("LDR {:?}, {:?}, #{}", dr, base, offset)
+        }
+        Instruction::STR { sr, base, offset } => {
+            format!("STR {:?}, {:?}, #{}", sr, base, offset)
+        }
+        Instruction::JMP { base: Register::R7 } => String::from("RET"),
+        Instruction::JMP { base } => format!("JMP {:?}", base),
+        Instruction::JSR { offset } => format!("JSR #{}", offset),
+        Instruction::JSRR { base } => format!("JSRR {:?}", base),
+        Instruction::RTI => String::from("RTI"),
+        Instruction::TRAP { vector } => match Trap::from_u16(vector) {
+            Some(trap) => format!("{:?}", trap),
+            None => format!("TRAP x{:02X}", vector),
+        },
+        Instruction::RES => format!(".FILL x{:04X}", word),
+    }
+}
+
+/// Render the second operand of `ADD` or `AND`
+fn render_operand(operand: Operand) -> String {
+    match operand {
+        Operand::Register(register) => format!("{:?}", register),
+        Operand::Immediate(value) => format!("#{}", value),
+    }
+}
+
+/// Render every word in a range of memory, paired with its address
+pub fn disassemble_range(memory: &[u16], range: RangeInclusive<u16>) -> Vec<(u16, String)> {
+    range
+        .filter_map(|addr| {
+            memory
+                .get(addr as usize)
+                .map(|&word| (addr, disassemble(word)))
+        })
+        .collect()
+}
+
+/// Render every word of an image, paired with its address
+///
+/// The first word of an image is normally its origin, which is where the rest of it is loaded.
+/// Raw dumps without that header can be disassembled by passing the address of their first word
+/// as `base`, in which case every word is rendered.
+pub fn disassemble_image(words: &[u16], base: Option<u16>) -> Vec<(u16, String)> {
+    let (base, body) = match (base, words.split_first()) {
+        (Some(base), _) => (base, words),
+        (None, Some((&origin, body))) => (origin, body),
+        (None, None) => return Vec::new(),
+    };
+    body.iter()
+        .enumerate()
+        .map(|(idx, &word)| (base.wrapping_add(idx as u16), disassemble(word)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn branch_mnemonics_follow_condition_bits() {
+        assert_eq!(disassemble(0x0800), "BRn #0");
+        assert_eq!(disassemble(0x0400), "BRz #0");
+        assert_eq!(disassemble(0x0200), "BRp #0");
+        assert_eq!(disassemble(0x0C00), "BRnz #0");
+        assert_eq!(disassemble(0x0600), "BRzp #0");
+        assert_eq!(disassemble(0x0A00), "BRnp #0");
+        assert_eq!(disassemble(0x0FFE), "BRnzp #-2");
+    }
+
+    #[test]
+    fn branch_without_flags_is_nop() {
+        assert_eq!(disassemble(0x0000), "NOP");
+        // There is no mnemonic for a never-taken branch with an offset
+        assert_eq!(disassemble(0x0005), ".FILL x0005");
+    }
+
+    #[test]
+    fn headerless_image_starts_at_base() {
+        let words = [0x1021, 0xF025];
+        assert_eq!(
+            disassemble_image(&words, Some(0x4000)),
+            [
+                (0x4000, String::from("ADD R0, R0, #1")),
+                (0x4001, String::from("HALT"))
+            ]
+        );
+        // With a header the first word is the origin instead
+        assert_eq!(
+            disassemble_image(&words, None),
+            [(0x1021, String::from("HALT"))]
+        );
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_same_words() {
+        let words = [
+            0x1265, // ADD R1, R1, #5
+            0x5042, // AND R0, R1, R2
+            0x927F, // NOT R1, R1
+            0x0DFD, // BRnz #-3
+            0x2FFF, // LD R7, #-1
+            0x6C81, // LDR R6, R2, #1
+            0xC1C0, // RET
+            0x4802, // JSR #2
+            0x8000, // RTI
+            0xF025, // HALT
+            0xF026, // PUTN
+            0xF030, // MEMCPY
+            0xF031, // RAND
+            0xF032, // GETN
+            0xF040, // TRAP x40
+        ];
+        let source: Vec<_> = words.iter().map(|&word| disassemble(word)).collect();
+        let image = assemble(&format!(".ORIG x3000\n{}\n.END", source.join("\n"))).unwrap();
+        assert_eq!(image[1..], words);
+    }
+}
@@ -0,0 +1,99 @@
+/// Generators for synthetic programs of a chosen size.
+///
+/// Benchmarks and tests can generate the workload they need instead of shipping images. Every
+/// generator returns the words of an image, origin first, which can be loaded with
+/// `LC3::load_words`. The programs halt once they are done, and each documents exactly how many
+/// instructions it executes so that runs can be checked against a step cap.
+use crate::assembler;
+
+/// A loop that adds 1 to R0 `iterations` times and then halts
+///
+/// The program executes `3 * iterations + 3` instructions. Since the loop counter is a signed
+/// word, `iterations` must be between 1 and x7FFF.
+pub fn add_loop(iterations: u16) -> Vec<u16> {
+    assert!(
+        (1..=0x7FFF).contains(&iterations),
+        "add loop needs between 1 and 0x7FFF iterations, got {}",
+        iterations
+    );
+    generate(&format!(
+        ".ORIG x3000\n\
+         AND R0, R0, #0\n\
+         LD R1, COUNT\n\
+         LOOP ADD R0, R0, #1\n\
+         ADD R1, R1, #-1\n\
+         BRp LOOP\n\
+         HALT\n\
+         COUNT .FILL #{}\n\
+         .END",
+        iterations
+    ))
+}
+
+/// A loop that stores into `words` consecutive addresses just past the program and then halts
+///
+/// Each address is set to the number of words that were left to store when it was written. The
+/// program executes `4 * words + 3` instructions, and `words` must be between 1 and x7FFF.
+pub fn memory_sweep(words: u16) -> Vec<u16> {
+    assert!(
+        (1..=0x7FFF).contains(&words),
+        "memory sweep needs between 1 and 0x7FFF words, got {}",
+        words
+    );
+    generate(&format!(
+        ".ORIG x3000\n\
+         LEA R1, DATA\n\
+         LD R2, COUNT\n\
+         LOOP STR R2, R1, #0\n\
+         ADD R1, R1, #1\n\
+         ADD R2, R2, #-1\n\
+         BRp LOOP\n\
+         HALT\n\
+         COUNT .FILL #{}\n\
+         DATA .BLKW 1\n\
+         .END",
+        words
+    ))
+}
+
+/// Assemble a generated program, which is always valid
+fn generate(source: &str) -> Vec<u16> {
+    assembler::assemble(source).expect("generated program doesn't assemble")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::{error::VmError, DispatchTables, LC3};
+    use std::io;
+
+    /// Run a generated program with a step cap, returning the VM once it stops
+    fn run(words: &[u16], max_steps: u64) -> (LC3, Result<(), VmError>) {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.captured_output = Some(Vec::new());
+        vm.load_words(words).unwrap();
+        vm.max_instructions = Some(max_steps);
+        let result = vm.run_loop(&DispatchTables::new());
+        (vm, result)
+    }
+
+    #[test]
+    fn add_loop_runs_expected_instruction_count() {
+        let (vm, result) = run(&add_loop(1000), 10_000);
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.instruction_count(), 3 * 1000 + 3);
+        assert_eq!(vm.registers[0], 1000);
+
+        let (_, result) = run(&add_loop(1000), 100);
+        assert_eq!(result, Err(VmError::InstructionLimit { limit: 100 }));
+    }
+
+    #[test]
+    fn memory_sweep_writes_every_word() {
+        let (vm, result) = run(&memory_sweep(50), 10_000);
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.instruction_count(), 4 * 50 + 3);
+        assert_eq!(vm.memory[0x3008], 50);
+        assert_eq!(vm.memory[0x3008 + 49], 1);
+    }
+}
@@ -5,21 +5,43 @@
 /// This module contains the struct representing the machine's state as well as methods
 /// for actually running the program.
 pub mod consts;
+pub mod error;
+pub mod format;
+mod keyboard;
+pub mod phases;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod trace;
 
 #[macro_use]
-mod instruction;
+pub mod instruction;
 
-use consts::{MemoryMappedRegister, Op, OpDispatchTable, Register, Trap};
-use instruction::getchar;
+use crate::disassembler::disassemble;
+use consts::{
+    ConditionFlag, EffectiveAddress, LoadReport, MemoryMappedRegister, Op, OpDispatchTable,
+    Register, StackUsage, Trap, TrapDispatchTable,
+};
+use error::{LoadError, RunError, VmError};
+use instruction::decode::{decode, Instruction};
 use itertools::Itertools;
-use log::{debug, info};
+use keyboard::Keyboard;
+use log::{debug, info, warn};
 use num_traits::FromPrimitive;
 use std::{
-    collections::HashMap,
+    fmt,
     fs::File,
-    io::{self, Read},
-    path::PathBuf,
+    io::{self, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    time::{Duration, Instant},
 };
+use trace::TraceRecord;
+
+/// The first bytes of a file written by `LC3::save_state`, including the format version
+const SNAPSHOT_MAGIC: &[u8; 5] = b"LC3S\x01";
 
 /// The lookup tables for the VM
 ///
@@ -27,6 +49,12 @@ use std::{
 pub struct DispatchTables {
     /// The dispatch table for various opcodes
     pub opcodes: OpDispatchTable,
+
+    /// The dispatch table for trap routines
+    ///
+    /// The standard trap routines (GETC, OUT, PUTS, IN, PUTSP, and HALT) are registered when the
+    /// tables are created, and can be overridden with `register_trap`.
+    pub traps: TrapDispatchTable,
 }
 
 impl DispatchTables {
@@ -36,7 +64,6 @@ impl DispatchTables {
     pub fn new() -> Self {
         let op_dispatch_table = op_dispatch_table![
             (Op::BR, instruction::op::br),
-            (Op::LD, instruction::op::ld),
             (Op::ADD, instruction::op::add),
             (Op::LD, instruction::op::ld),
             (Op::ST, instruction::op::st),
@@ -53,19 +80,107 @@ impl DispatchTables {
             (Op::LEA, instruction::op::lea),
             (Op::TRAP, instruction::op::trap)
         ];
-        Self {
-            opcodes: op_dispatch_table,
+
+        // The opcode field is 4 bits wide, so every possible value must have a handler
+        let mut opcodes: OpDispatchTable = [instruction::op::res; 16];
+        for (raw_op, (entry, op)) in op_dispatch_table.iter().zip(&Op::ALL).enumerate() {
+            opcodes[raw_op] =
+                entry.unwrap_or_else(|| panic!("no dispatch entry for opcode {:?}", op));
+        }
+
+        let mut traps: TrapDispatchTable = [None; 256];
+        for &trap in &[
+            Trap::GETC,
+            Trap::OUT,
+            Trap::PUTS,
+            Trap::IN,
+            Trap::PUTSP,
+            Trap::HALT,
+        ] {
+            traps[trap as usize] = Some(instruction::op::trap_routine(trap));
         }
+
+        Self { opcodes, traps }
+    }
+
+    /// Add a trap routine for the 8-bit trap vector `code`, replacing any routine it already had
+    ///
+    /// The routine runs after R7 has been set to the return address. Every VM that runs with these
+    /// tables uses it, so build separate tables to give VMs different routines.
+    pub fn register_trap(&mut self, code: u8, handler: fn(&mut LC3)) {
+        self.traps[usize::from(code)] = Some(handler);
     }
 }
 
-/// The data pertaining to the state of the LC3 VM
+impl Default for DispatchTables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An input stream that is always exhausted and an output stream that discards everything
+///
+/// Use this with `LC3::with_io` to run programs silently, such as in benchmarks. GETC and IN read
+/// 0 instead of blocking.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NullIo;
+
+impl Read for NullIo {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for NullIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The parts of the VM's state besides memory that executing an instruction can change
+///
+/// This is used to undo steps. Memory is left out because copying all of it on every step would
+/// be too slow; the writes journaled with `write_journal` are undone instead.
 #[derive(Clone, Debug)]
+pub struct CpuState {
+    registers: Vec<u16>,
+    running: bool,
+    rng_state: u32,
+    instruction_count: u64,
+    supervisor_mode: bool,
+    saved_stack_pointer: u16,
+    priority: u16,
+}
+
+/// What a single executed instruction did to the registers
+///
+/// This is returned by `LC3::step_record` so that tests can check invariants after every step
+/// without reaching into the VM.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StepRecord {
+    /// The address the instruction was fetched from
+    pub pc: u16,
+
+    /// The instruction word
+    pub instr: u16,
+
+    /// The registers before the instruction ran, indexed by `Register`
+    pub registers_before: Vec<u16>,
+
+    /// The registers after the instruction ran, indexed by `Register`
+    pub registers_after: Vec<u16>,
+}
+
+/// The data pertaining to the state of the LC3 VM
 pub struct LC3 {
     /// A vector representing the memory locations available to the virtual machine.
     ///
-    /// The memory addresses are bounded by the limit for the unsigned 16 bit integer, which is
-    /// 65536. There are `U16_MAX` addressable locations in memory.
+    /// The memory addresses are bounded by the limit for the unsigned 16 bit integer, so there are
+    /// `MEMORY_LIMIT` (65536) addressable locations in memory.
     pub memory: Vec<u16>,
 
     /// A vector of the available registers in the VM. The registers are defined in the `Register`
@@ -77,6 +192,147 @@ pub struct LC3 {
 
     /// Whether the VM is currently executing a trap code
     trap: bool,
+
+    /// The number of trap routines that are currently running, counting routines that trapped
+    /// again from inside a routine
+    trap_depth: u32,
+
+    /// The most trap routines that can be running at once before the VM halts with
+    /// `VmError::TrapRecursionLimit`
+    pub max_trap_depth: u32,
+
+    /// Registers whose value changes are logged after every step
+    pub watched_registers: Vec<Register>,
+
+    /// Memory addresses whose value changes are logged after every step
+    pub watched_addresses: Vec<u16>,
+
+    /// Registers that instructions can't change. Writes to them are undone after each step and
+    /// logged under the `freeze` target.
+    pub frozen_registers: Vec<Register>,
+
+    /// Program output that has been captured instead of being written to stdout
+    ///
+    /// Output is only captured when this is `Some`, which lets the debugger display program output
+    /// in its own panel rather than writing over the TUI.
+    pub captured_output: Option<Vec<u8>>,
+
+    /// The address and previous value of every write made with `mem_write`, in order
+    ///
+    /// Writes are only journaled when this is `Some`, which lets the debugger undo them.
+    pub write_journal: Option<Vec<(u16, u16)>>,
+
+    /// Where a line describing every executed instruction is written, if tracing is enabled
+    ///
+    /// Each line holds the PC and the raw word of the instruction, its disassembly, and the
+    /// registers after it executed.
+    pub trace: Option<Box<dyn Write + Send>>,
+
+    /// Where a `trace::TraceRecord` for every executed instruction is written, if binary tracing
+    /// is enabled
+    pub binary_trace: Option<Box<dyn Write + Send>>,
+
+    /// A bitset of the memory addresses that have been written, with one bit per address
+    ///
+    /// This is only tracked after `track_uninitialized_reads` is called, and is used to warn when
+    /// the program reads memory that was never written or loaded from an image.
+    written_memory: Option<Vec<u64>>,
+
+    /// Whether stores into the code segment halt the VM
+    ///
+    /// This catches accidental self-modifying code. It is off by default because some programs
+    /// modify their own code on purpose.
+    pub protect_code: bool,
+
+    /// The addresses occupied by the most recently loaded image, which are treated as code
+    code_segment: Option<RangeInclusive<u16>>,
+
+    /// Whether the non-standard extended trap routines, such as `MEMCPY`, are available
+    ///
+    /// When this is off, extended trap codes are treated like any other unknown trap code.
+    pub extended_traps: bool,
+
+    /// The state of the xorshift random number generator behind the `RAND` trap
+    rng_state: u32,
+
+    /// Whether a forbidden memory mapped register access halts the VM instead of logging a warning
+    pub strict_mmio: bool,
+
+    /// The usage of the R6 stack, if it is being tracked
+    pub stack_usage: Option<StackUsage>,
+
+    /// Whether the `CLOCK` memory mapped register is enabled. When it is disabled its address is
+    /// ordinary memory.
+    pub clock_register: bool,
+
+    /// The number of instructions that have been executed
+    instruction_count: u64,
+
+    /// A bitset of the addresses instructions have been fetched from, if coverage is being tracked
+    covered_addresses: Option<Vec<u64>>,
+
+    /// Whether the `GETN` trap halts the VM when it reads a number that doesn't fit in 16 bits,
+    /// rather than wrapping it
+    pub strict_getn: bool,
+
+    /// The most instructions `run_loop` executes before stopping the VM, counting from the last
+    /// reset. There is no limit when this is `None`.
+    pub max_instructions: Option<u64>,
+
+    /// A file that the state of the VM is saved to when it faults, for post-mortem debugging
+    ///
+    /// The dump is written by `save_state` right after the faulting instruction, so it holds the
+    /// memory, registers, and PC at the fault and can be inspected with `load_state`.
+    pub fault_dump: Option<PathBuf>,
+
+    /// The address the PC starts at, which is `consts::PC_START` unless it was overridden
+    pc_start: u16,
+
+    /// Whether the processor is in supervisor mode rather than user mode
+    supervisor_mode: bool,
+
+    /// R6 for whichever of the supervisor and user stacks isn't in use
+    ///
+    /// R6 is the stack pointer for the current mode, so switching modes swaps it with this.
+    saved_stack_pointer: u16,
+
+    /// The priority level the processor is running at, from 0 through 7. An interrupt is only
+    /// taken if its priority is higher.
+    priority: u16,
+
+    /// The fault that stopped the VM during the current step, which `step` returns
+    error: Option<VmError>,
+
+    /// The keyboard, which reads keys from the input stream
+    input: Keyboard,
+
+    /// The stream that program output is written to when it isn't being captured
+    output: Box<dyn Write + Send>,
+
+    /// Whether newlines in program output are written as `\r\n`
+    ///
+    /// A terminal in raw mode doesn't return to the start of the line on a bare `\n`, so this
+    /// should be set while stdout is in raw mode.
+    pub crlf_newlines: bool,
+}
+
+impl Default for LC3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The input and output streams can't be printed, and memory is too big to be useful in a debug
+// message, so only the execution state is shown
+impl fmt::Debug for LC3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LC3")
+            .field("registers", &&self.registers[..=Register::R7 as usize])
+            .field("pc", &self.registers[Register::PC as usize])
+            .field("running", &self.running)
+            .field("instruction_count", &self.instruction_count)
+            .finish()
+    }
 }
 
 impl LC3 {
@@ -86,25 +342,196 @@ impl LC3 {
     /// currently has very poor support for them. These should be switched to arrays once const
     /// generics are stabilized. You can play around with them in nightly builds.
     pub fn new() -> Self {
+        Self::with_pc_start(consts::PC_START)
+    }
+
+    /// Create a new LC3 virtual machine state that starts executing at `pc_start`
+    pub fn with_pc_start(pc_start: u16) -> Self {
         let mut lc3 = Self {
             memory: vec![0; consts::MEMORY_LIMIT],
             registers: vec![0; consts::Register::COUNT as usize],
             running: false,
             trap: false,
+            trap_depth: 0,
+            max_trap_depth: consts::DEFAULT_MAX_TRAP_DEPTH,
+            watched_registers: Vec::new(),
+            watched_addresses: Vec::new(),
+            frozen_registers: Vec::new(),
+            captured_output: None,
+            write_journal: None,
+            trace: None,
+            binary_trace: None,
+            written_memory: None,
+            protect_code: false,
+            code_segment: None,
+            extended_traps: false,
+            rng_state: consts::DEFAULT_RNG_SEED,
+            strict_mmio: false,
+            stack_usage: None,
+            clock_register: false,
+            instruction_count: 0,
+            covered_addresses: None,
+            strict_getn: false,
+            max_instructions: None,
+            fault_dump: None,
+            pc_start,
+            supervisor_mode: true,
+            saved_stack_pointer: 0,
+            priority: 0,
+            error: None,
+            input: Keyboard::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            crlf_newlines: false,
         };
-        lc3.registers[Register::PC as usize] = consts::PC_START;
+        lc3.reset();
+        lc3
+    }
+
+    /// Create a new LC3 virtual machine state that reads keyboard input from `input` and writes
+    /// program output to `output` instead of using stdin and stdout
+    pub fn with_io<R, W>(input: R, output: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let mut lc3 = Self::new();
+        lc3.set_input(input);
+        lc3.output = Box::new(output);
         lc3
     }
 
+    /// Read keyboard input from `input` instead of the current input stream
+    ///
+    /// Once `input` runs out, GETC and IN read 0 and the keyboard status register never reports
+    /// another key, so a recorded session can be replayed without a terminal.
+    pub fn set_input<R: Read + Send + 'static>(&mut self, input: R) {
+        self.input = Keyboard::new(input);
+    }
+
+    /// Read keyboard input from a channel instead of the current input stream, and return the
+    /// sender that keys are typed into
+    ///
+    /// Nothing is read from a stream in the background, so this is how to give input to the VM
+    /// when something else owns stdin. Once the sender is dropped, the VM behaves as if its input
+    /// stream ran out.
+    pub fn input_sender(&mut self) -> Sender<u8> {
+        let (sender, receiver) = mpsc::channel();
+        self.input = Keyboard::from_channel(receiver);
+        sender
+    }
+
+    /// Put the processor back in its initial state
+    ///
+    /// The registers are cleared, the PC is set to the start address, the processor is put in user
+    /// mode with the supervisor stack pointer saved for exceptions and interrupts, and the VM is
+    /// stopped. Memory is left alone so that the loaded program can be run again.
+    pub fn reset(&mut self) {
+        for register in self.registers.iter_mut() {
+            *register = 0;
+        }
+        self.registers[Register::PC as usize] = self.pc_start;
+        // Programs run in user mode, and R6 is theirs to set up
+        self.supervisor_mode = false;
+        self.saved_stack_pointer = consts::SUPERVISOR_STACK_START;
+        self.priority = 0;
+        // Exactly one condition flag should be set at any time, so start with Z
+        self.set_condition(ConditionFlag::ZRO);
+        self.running = false;
+        self.trap = false;
+        self.trap_depth = 0;
+        self.instruction_count = 0;
+    }
+
+    /// The address the PC is set to when the VM is reset
+    pub fn pc_start(&self) -> u16 {
+        self.pc_start
+    }
+
+    /// Change the address the PC is set to when the VM is reset
+    ///
+    /// This doesn't move the PC until the next `reset`.
+    pub fn set_pc_start(&mut self, pc_start: u16) {
+        self.pc_start = pc_start;
+    }
+
+    /// Start executing from `pc` now and after every reset
+    pub fn start_at(&mut self, pc: u16) {
+        self.pc_start = pc;
+        self.registers[Register::PC as usize] = pc;
+    }
+
     /// Execute the VM
     ///
     /// This will start a run-loop that processes instructions until the stop instruction is
-    /// encountered.
-    pub fn run_loop(&mut self, tables: &DispatchTables) {
+    /// encountered. It stops with an error if the program faults or runs for more than
+    /// `max_instructions` instructions.
+    pub fn run_loop(&mut self, tables: &DispatchTables) -> Result<(), VmError> {
+        self.running = true;
+        while self.running {
+            self.check_instruction_limit()?;
+            self.step(tables)?;
+        }
+        Ok(())
+    }
+
+    /// Execute the VM like `run_loop`, but stop with a timeout once `limit` has passed
+    // `is_multiple_of` is newer than the compilers this crate supports
+    #[allow(clippy::manual_is_multiple_of)]
+    fn run_for(&mut self, tables: &DispatchTables, limit: Duration) -> Result<(), RunError> {
+        let deadline = Instant::now() + limit;
         self.running = true;
         while self.running {
-            self.step(tables);
+            self.check_instruction_limit()?;
+            if self.instruction_count % SANDBOX_CLOCK_INTERVAL == 0 && Instant::now() >= deadline {
+                self.running = false;
+                return Err(RunError::Timeout { limit });
+            }
+            self.step(tables)?;
         }
+        Ok(())
+    }
+
+    /// Stop the VM with a fault once it has executed `max_instructions` instructions
+    fn check_instruction_limit(&mut self) -> Result<(), VmError> {
+        match self.max_instructions {
+            Some(limit) if self.instruction_count >= limit => {
+                self.running = false;
+                let error = VmError::InstructionLimit { limit };
+                self.dump_fault(&error);
+                Err(error)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop the VM because of a fault, which the current `step` will return
+    ///
+    /// Only the first fault in a step is kept, since later ones are usually caused by it.
+    pub(crate) fn fail(&mut self, error: VmError) {
+        self.error.get_or_insert(error);
+        self.running = false;
+    }
+
+    /// Returns whether the VM is running
+    ///
+    /// The VM is running once `run_loop` or `start` has been called, until the program halts or
+    /// encounters an illegal instruction.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// The number of instructions executed since the VM was created or last reset
+    ///
+    /// Every `step` counts as one instruction, including a TRAP along with the routine it runs.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// Mark the VM as running without entering the run loop
+    ///
+    /// This is for callers that drive the VM one `step` at a time, such as the debugger.
+    pub fn start(&mut self) {
+        self.running = true;
     }
 
     /// Get the next opcode for execution
@@ -112,12 +539,10 @@ impl LC3 {
     /// This method will find the register index pointed to by the program counter, which returns a
     /// memory address. This method parses the value at the memory address to figure out the next
     /// operation.
-    ///
-    /// This will panic if the register is invalid
     pub fn parse_next_op(&self) -> consts::Operation {
         let register_index = self.registers[Register::PC as usize];
         let raw_op = self.memory[register_index as usize];
-        let op = FromPrimitive::from_u16(raw_op >> 12).unwrap();
+        let op = Op::of(raw_op);
 
         // If the opcode points to a trapcode, then display the trapcode
         if op == Op::TRAP {
@@ -127,36 +552,345 @@ impl LC3 {
         consts::Operation::Op(op)
     }
 
+    /// Compute the effective address of the instruction pointed to by the program counter
+    ///
+    /// This returns `None` unless the next instruction is a load, a store, or `LEA`. Memory is read
+    /// directly instead of going through `mem_read`, so resolving an indirect address never
+    /// triggers the side effects of a memory mapped register.
+    pub fn effective_address(&self) -> Option<EffectiveAddress> {
+        let pc = self.registers[Register::PC as usize];
+        // PC relative offsets are relative to the incremented program counter
+        let pc_relative = |offset: i16| pc.wrapping_add(1).wrapping_add(offset as u16);
+
+        match decode(self.memory[pc as usize]) {
+            Instruction::LD { offset, .. }
+            | Instruction::ST { offset, .. }
+            | Instruction::LEA { offset, .. } => {
+                Some(EffectiveAddress::Direct(pc_relative(offset)))
+            }
+            Instruction::LDI { offset, .. } | Instruction::STI { offset, .. } => {
+                let pointer = pc_relative(offset);
+                Some(EffectiveAddress::Indirect(
+                    pointer,
+                    self.memory[pointer as usize],
+                ))
+            }
+            Instruction::LDR { base, offset, .. } | Instruction::STR { base, offset, .. } => Some(
+                EffectiveAddress::Direct(self.registers[base as usize].wrapping_add(offset as u16)),
+            ),
+            _ => None,
+        }
+    }
+
     /// Read an instruction from the register pointed to by the program counter and execute it
     ///
     /// This is one step of execution in the VM. The VM should continuously run steps in a loop,
     /// though it is split out into a function for easy debugging. This method will read the
     /// instruction, dispatch the appropriate function, and increment the program counter.
-    pub fn step(&mut self, tables: &DispatchTables) {
-        let op_dispatch_table = &tables.opcodes;
-        let instr = self.mem_read(self.registers[Register::PC as usize]);
-        self.registers[Register::PC as usize] += 1;
-        if let Some(op) = FromPrimitive::from_u16(instr >> 12) {
-            info!("read op {:?} ({}) at PC", op, instr);
-            let op_fn = op_dispatch_table[&op];
-            op_fn(self, instr);
-        } else {
-            self.running = false;
-            println!("PANIC: Unknown opcode encountered");
+    ///
+    /// If the instruction faults, the VM is stopped and the fault is returned.
+    pub fn step(&mut self, tables: &DispatchTables) -> Result<(), VmError> {
+        let pc = self.registers[Register::PC as usize];
+        let watched_values = self.watched_values();
+        let frozen_values: Vec<u16> = self
+            .frozen_registers
+            .iter()
+            .map(|&r| self.registers[r as usize])
+            .collect();
+        let instr = self.mem_read(pc);
+        if let Some(covered) = &mut self.covered_addresses {
+            covered[pc as usize / 64] |= 1 << (pc % 64);
+        }
+        self.registers[Register::PC as usize] = pc.wrapping_add(1);
+        self.instruction_count += 1;
+        // The opcode is the top 4 bits, so it always indexes the table
+        let op = Op::of(instr);
+        info!("read op {:?} ({}) at PC", op, instr);
+        tables.opcodes[op as usize](self, tables, instr);
+        self.restore_frozen_registers(pc, &frozen_values);
+        self.log_watch_changes(pc, &watched_values);
+
+        if let Some(stack_usage) = &mut self.stack_usage {
+            stack_usage.update(self.registers[Register::R6 as usize]);
+        }
+        if self.trace.is_some() {
+            self.write_trace(pc, instr);
+        }
+        if let Some(trace) = &mut self.binary_trace {
+            let record = TraceRecord {
+                step: self.instruction_count - 1,
+                pc,
+                instr,
+            };
+            if let Err(e) = record.write_to(trace) {
+                warn!("stopped binary tracing: {}", e);
+                self.binary_trace = None;
+            }
+        }
+        // Like the hardware, interrupts are checked between instructions, so the next step runs
+        // the handler
+        if self.running {
+            self.check_keyboard_interrupt();
+        }
+        match self.error.take() {
+            Some(error) => {
+                self.dump_fault(&error);
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Save the state of the VM to `fault_dump`, if it is set, after the VM faulted
+    fn dump_fault(&self, error: &VmError) {
+        if let Some(path) = &self.fault_dump {
+            match self.save_state(path) {
+                Ok(()) => info!("saved the state at \"{}\" to {}", error, path.display()),
+                Err(e) => warn!("could not save the state to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Execute one instruction like `step`, returning a record of what it did
+    pub fn step_record(&mut self, tables: &DispatchTables) -> Result<StepRecord, VmError> {
+        let pc = self.registers[Register::PC as usize];
+        // Read the instruction directly so that memory mapped registers aren't triggered twice
+        let instr = self.memory[pc as usize];
+        let registers_before = self.registers.clone();
+        self.step(tables)?;
+        Ok(StepRecord {
+            pc,
+            instr,
+            registers_before,
+            registers_after: self.registers.clone(),
+        })
+    }
+
+    /// Write the trace line for the instruction `instr` that was just executed at `pc`
+    ///
+    /// Tracing is turned off if the trace can't be written, rather than failing every step.
+    fn write_trace(&mut self, pc: u16, instr: u16) {
+        let registers = self
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                let register: Register = FromPrimitive::from_usize(idx).unwrap();
+                format!("{:?}={:04x}", register, value)
+            })
+            .join(" ");
+        let line = format!(
+            "{:04x} {:04x} {:<20} {}",
+            pc,
+            instr,
+            disassemble(instr),
+            registers
+        );
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = writeln!(trace, "{}", line) {
+                warn!("stopped tracing: {}", e);
+                self.trace = None;
+            }
+        }
+    }
+
+    /// Undo any change an instruction made to a frozen register
+    ///
+    /// `pc` is the address of the instruction that was just executed and `before` holds the values
+    /// of the frozen registers from before it ran.
+    fn restore_frozen_registers(&mut self, pc: u16, before: &[u16]) {
+        for (&register, &value) in self.frozen_registers.iter().zip(before) {
+            let attempted = self.registers[register as usize];
+            if attempted != value {
+                info!(
+                    target: "freeze",
+                    "dropped write of {:#06x} to frozen {:?} (PC {:#06x})",
+                    attempted,
+                    register,
+                    pc
+                );
+                self.registers[register as usize] = value;
+            }
+        }
+    }
+
+    /// Snapshot the values of the watched registers followed by the watched memory addresses
+    ///
+    /// This reads memory directly rather than going through `mem_read` so that watching a memory
+    /// mapped register doesn't have any side effects.
+    fn watched_values(&self) -> Vec<u16> {
+        self.watched_registers
+            .iter()
+            .map(|&r| self.registers[r as usize])
+            .chain(
+                self.watched_addresses
+                    .iter()
+                    .map(|&addr| self.memory[addr as usize]),
+            )
+            .collect()
+    }
+
+    /// Log every watched location whose value differs from the snapshot taken before a step
+    ///
+    /// `pc` is the address of the instruction that was just executed. Changes are logged under the
+    /// `watch` target so they can be enabled on their own with `RUST_LOG=watch=info`.
+    fn log_watch_changes(&self, pc: u16, before: &[u16]) {
+        for change in self.watch_changes(pc, before) {
+            info!(target: "watch", "{}", change);
+        }
+    }
+
+    /// Describe every watched location whose value differs from the snapshot `before`
+    fn watch_changes(&self, pc: u16, before: &[u16]) -> Vec<String> {
+        if before.is_empty() {
+            return Vec::new();
         }
+        let (reg_before, mem_before) = before.split_at(self.watched_registers.len());
+        let registers = self
+            .watched_registers
+            .iter()
+            .zip(reg_before)
+            .filter(|&(&r, &old)| self.registers[r as usize] != old)
+            .map(|(&r, &old)| {
+                let new = self.registers[r as usize];
+                format!("{:?}: {:#06x} -> {:#06x} (PC {:#06x})", r, old, new, pc)
+            });
+        let addresses = self
+            .watched_addresses
+            .iter()
+            .zip(mem_before)
+            .filter(|&(&addr, &old)| self.memory[addr as usize] != old)
+            .map(|(&addr, &old)| {
+                let new = self.memory[addr as usize];
+                format!(
+                    "[{:#06x}]: {:#06x} -> {:#06x} (PC {:#06x})",
+                    addr, old, new, pc
+                )
+            });
+        registers.chain(addresses).collect()
     }
 
-    /// Read a VM image and load it into memory
+    /// Read a VM image file and load it into memory
     ///
-    /// This will read an LC3 image and load it into memory with the specified origin offset.
-    pub fn read_image_file(&mut self, filename: &PathBuf) -> io::Result<()> {
-        let mut f = File::open(filename)?;
+    /// This opens the file and loads it with `read_image`.
+    pub fn read_image_file(&mut self, filename: &PathBuf) -> Result<LoadReport, LoadError> {
+        self.read_image(File::open(filename)?)
+    }
 
-        // The memory limit defines how many 16-bit memory pointers we can have, so we multiply the
-        // memory limit by two because we read 8-bit integers.
-        let mut buf = Vec::<u8>::with_capacity(consts::MEMORY_LIMIT * 2);
-        let read_bytes = f.read_to_end(&mut buf)?;
+    /// Read a VM image from any reader and load it into memory
+    ///
+    /// This will read an LC3 image and load it into memory with the specified origin offset. The
+    /// image can be in any of the formats in `format`, which is detected from its contents. Since
+    /// `&[u8]` implements `Read`, this also loads images that are already in memory.
+    pub fn read_image<R: Read>(&mut self, mut reader: R) -> Result<LoadReport, LoadError> {
+        // The memory limit defines how many 16-bit memory pointers we can have, and the largest
+        // image is the origin word followed by a word for each of them. We multiply by two because
+        // we read 8-bit integers.
+        let mut buf = Vec::<u8>::with_capacity((consts::MEMORY_LIMIT + 1) * 2);
+        let read_bytes = reader.read_to_end(&mut buf)?;
         debug!("Read {} bytes from the provided image", read_bytes);
+        self.load_image(&format::decode(&buf, None)?)
+    }
+
+    /// Save the runtime state of the VM to a file so it can be resumed with `load_state`
+    ///
+    /// Unlike an image, this captures everything a running program can change: memory, the
+    /// registers, and whether the VM is running, in a trap, or in supervisor mode. Configuration
+    /// such as watches and strictness flags isn't saved. Every value is stored big-endian.
+    pub fn save_state(&self, path: &Path) -> io::Result<()> {
+        File::create(path)?.write_all(&self.encode_state())
+    }
+
+    /// Encode the state that `save_state` saves
+    pub(crate) fn encode_state(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(SNAPSHOT_MAGIC.len() + 32 + self.memory.len() * 2);
+        buf.extend_from_slice(SNAPSHOT_MAGIC);
+        let flags = self.running as u8
+            | (self.trap as u8) << 1
+            | (self.supervisor_mode as u8) << 2
+            | (self.priority as u8) << 3;
+        buf.push(flags);
+        buf.extend_from_slice(&self.saved_stack_pointer.to_be_bytes());
+        buf.extend_from_slice(&self.instruction_count.to_be_bytes());
+        buf.extend_from_slice(&self.rng_state.to_be_bytes());
+        buf.extend_from_slice(&(self.registers.len() as u16).to_be_bytes());
+        buf.extend(self.registers.iter().flat_map(|word| word.to_be_bytes()));
+        buf.extend_from_slice(&(self.memory.len() as u32).to_be_bytes());
+        buf.extend(self.memory.iter().flat_map(|word| word.to_be_bytes()));
+        buf
+    }
+
+    /// Restore the runtime state of the VM from a file written by `save_state`
+    ///
+    /// The VM is left untouched if the file can't be read or isn't a saved state.
+    pub fn load_state(&mut self, path: &Path) -> Result<(), LoadError> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        self.decode_state(&buf).map(|_| ())
+    }
+
+    /// Restore the state encoded by `encode_state` from the front of `buf`, and return whatever
+    /// follows it
+    pub(crate) fn decode_state<'b>(&mut self, buf: &'b [u8]) -> Result<&'b [u8], LoadError> {
+        let mut rest = buf
+            .strip_prefix(SNAPSHOT_MAGIC.as_ref())
+            .ok_or(LoadError::InvalidSnapshot)?;
+        // Split `len` bytes off of the front of the remaining data
+        let mut take = |len: usize| -> Result<&[u8], LoadError> {
+            if rest.len() < len {
+                return Err(LoadError::InvalidSnapshot);
+            }
+            let (bytes, remaining) = rest.split_at(len);
+            rest = remaining;
+            Ok(bytes)
+        };
+        let words = |bytes: &[u8]| -> Vec<u16> {
+            bytes
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect()
+        };
+
+        let flags = take(1)?[0];
+        let saved_stack_pointer = words(take(2)?)[0];
+        let mut count = [0; 8];
+        count.copy_from_slice(take(8)?);
+        let mut rng_state = [0; 4];
+        rng_state.copy_from_slice(take(4)?);
+        let register_count = words(take(2)?)[0] as usize;
+        let registers = words(take(register_count * 2)?);
+        let mut memory_len = [0; 4];
+        memory_len.copy_from_slice(take(4)?);
+        let memory = words(take(u32::from_be_bytes(memory_len) as usize * 2)?);
+        if registers.len() != self.registers.len() || memory.len() != self.memory.len() {
+            return Err(LoadError::InvalidSnapshot);
+        }
+
+        self.running = flags & 1 != 0;
+        self.trap = flags & 1 << 1 != 0;
+        self.supervisor_mode = flags & 1 << 2 != 0;
+        self.priority = u16::from(flags >> 3 & 0x7);
+        self.saved_stack_pointer = saved_stack_pointer;
+        self.instruction_count = u64::from_be_bytes(count);
+        self.rng_state = u32::from_be_bytes(rng_state);
+        self.registers = registers;
+        self.memory = memory;
+        Ok(rest)
+    }
+
+    /// Load the raw bytes of a VM image into memory
+    ///
+    /// The first word of the image is the origin, which is where the rest of the image is loaded.
+    /// An image that would run past the end of memory is rejected before anything is loaded.
+    /// Returns where the image ended up.
+    ///
+    /// Loading doesn't move the PC, which stays at the start address given to `with_pc_start`
+    /// (x3000 by default) whatever the origin is. Use `start_at` with the returned origin to run
+    /// an image from where it was loaded.
+    pub fn load_image(&mut self, buf: &[u8]) -> Result<LoadReport, LoadError> {
+        if buf.len() < 2 {
+            return Err(LoadError::MissingOrigin);
+        }
 
         // Rust reads one byte (8 bits) at a time. We will have to account for this and combine two
         // 8-bit integers to one 16-bit integers
@@ -166,51 +900,1639 @@ impl LC3 {
         debug!("Image origin offset: {}", origin);
         let mut mem_idx = origin as usize;
 
+        // Every two bytes after the origin form one word, and a trailing odd byte still takes up
+        // a whole word
+        let words = (buf.len() - 1) / 2;
+        if mem_idx + words > consts::MEMORY_LIMIT {
+            return Err(LoadError::ImageExceedsMemory { origin, words });
+        }
+
         // Take two bytes at a time and reverse the endian-ness, placing the final 16-bit integer
         // into a memory location
-        for mut chunk in &buf.into_iter().skip(2).chunks(2) {
+        for mut chunk in &buf.iter().skip(2).chunks(2) {
             // Reverse the endian-ness of the incoming 16-bit instruction
-            let p: u16 = u16::from(chunk.next().unwrap_or_default()) << 8
-                | u16::from(chunk.next().unwrap_or_default());
+            let p: u16 = u16::from(chunk.next().copied().unwrap_or_default()) << 8
+                | u16::from(chunk.next().copied().unwrap_or_default());
             self.memory[mem_idx] = p;
+            self.mark_written(mem_idx as u16);
             mem_idx += 1;
         }
-        Ok(())
+
+        let end_addr = origin + words.saturating_sub(1) as u16;
+        self.code_segment = if words > 0 {
+            Some(origin..=end_addr)
+        } else {
+            None
+        };
+        Ok(LoadReport {
+            origin,
+            words_loaded: words,
+            end_addr,
+        })
     }
 
-    /// Update the condition flag
+    /// Load an image given as words, with the origin first, such as the output of the assembler
     ///
-    /// This method must be used any time a value is written to a register. It will find the
-    /// condition used at the updated register and update the condition register to reflect that
-    /// value.
-    fn update_cond_flag(&mut self, r: u16) {
-        // The raw bit value of the condition register
-        let raw_cond = self.registers[r as usize];
-        // The converted condition value
-        let cond_flag = instruction::get_cond_flag(raw_cond);
-        self.registers[Register::COND as usize] = cond_flag as u16;
+    /// This behaves exactly like `load_image` with the words converted to big-endian bytes.
+    pub fn load_words(&mut self, words: &[u16]) -> Result<LoadReport, LoadError> {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        self.load_image(&bytes)
     }
 
-    /// Write a value to some memory location
+    /// Load an image over the current one without resetting the rest of the machine
     ///
-    /// This will write a value to the VM's memory bank given the value and the pointer address.
-    pub fn mem_write(&mut self, addr: u16, val: u16) {
-        self.memory[addr as usize] = val;
+    /// Registers and memory outside of the image are left alone so that execution can continue
+    /// with the updated code. Returns true if the PC is no longer inside the image or the
+    /// instruction at the PC changed, since continuing may then not do what the user expects.
+    pub fn reload_image(&mut self, buf: &[u8]) -> Result<bool, LoadError> {
+        let pc = self.registers[Register::PC as usize];
+        let old_instr = self.memory[pc as usize];
+        self.load_image(buf)?;
+        let pc_in_image = self
+            .code_segment
+            .as_ref()
+            .is_some_and(|segment| segment.contains(&pc));
+        Ok(!pc_in_image || self.memory[pc as usize] != old_instr)
     }
 
-    /// Returns the value at a particular memory address
+    /// Seed the random number generator used by the `RAND` trap
     ///
-    /// This also has support for memory mapped registers, such as for the keyboard.
-    pub fn mem_read(&mut self, addr: u16) -> u16 {
-        if addr == MemoryMappedRegister::KBSR as u16 {
-            if false {
-                // TODO implement `check_key`
-                self.memory[MemoryMappedRegister::KBSR as usize] = 1 << 15;
-                self.memory[MemoryMappedRegister::KBDR as usize] = getchar().into();
-            } else {
-                self.memory[MemoryMappedRegister::KBSR as usize] = 0;
-            }
+    /// Two VMs seeded with the same value produce the same sequence of random numbers, which makes
+    /// programs that use randomness reproducible.
+    pub fn seed_rng(&mut self, seed: u32) {
+        // xorshift gets stuck at zero, so zero is replaced by the default seed
+        self.rng_state = if seed == 0 {
+            consts::DEFAULT_RNG_SEED
+        } else {
+            seed
+        };
+    }
+
+    /// Returns the next pseudo-random 16-bit value
+    pub fn next_random(&mut self) -> u16 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+        // The high bits of xorshift are better distributed than the low bits
+        (x >> 16) as u16
+    }
+
+    /// Returns the condition flag currently stored in the `COND` register
+    ///
+    /// If the register has been overwritten with something that isn't a single flag, this falls
+    /// back to `ZRO`.
+    pub fn condition_flags(&self) -> ConditionFlag {
+        FromPrimitive::from_u16(self.registers[Register::COND as usize])
+            .unwrap_or(ConditionFlag::ZRO)
+    }
+
+    /// Force the `COND` register to a particular condition flag
+    ///
+    /// This is useful for testing branch logic without executing an instruction that sets the flag.
+    pub fn set_condition(&mut self, flag: ConditionFlag) {
+        self.registers[Register::COND as usize] = flag as u16;
+    }
+
+    /// Whether the processor is in supervisor mode
+    pub fn is_supervisor_mode(&self) -> bool {
+        self.supervisor_mode
+    }
+
+    /// Switch between supervisor and user mode, swapping R6 to the other mode's stack
+    pub fn set_supervisor_mode(&mut self, supervisor_mode: bool) {
+        if self.supervisor_mode != supervisor_mode {
+            let r6 = &mut self.registers[Register::R6 as usize];
+            std::mem::swap(r6, &mut self.saved_stack_pointer);
+            self.supervisor_mode = supervisor_mode;
+        }
+    }
+
+    /// The processor status register, which holds the privilege mode, the priority level, and the
+    /// condition flags
+    pub fn psr(&self) -> u16 {
+        let mode = if self.supervisor_mode {
+            0
+        } else {
+            consts::PSR_USER_MODE
+        };
+        mode | self.priority << 8 | self.registers[Register::COND as usize]
+    }
+
+    /// Restore the processor status register, switching stacks if the privilege mode changes
+    pub fn set_psr(&mut self, psr: u16) {
+        self.set_supervisor_mode(psr & consts::PSR_USER_MODE == 0);
+        self.priority = psr >> 8 & 0x7;
+        self.registers[Register::COND as usize] = psr & 0x7;
+    }
+
+    /// Save the PSR and PC on the supervisor stack and switch to supervisor mode, as the processor
+    /// does before running an exception or interrupt handler
+    fn push_context(&mut self) {
+        let psr = self.psr();
+        let pc = self.registers[Register::PC as usize];
+        self.set_supervisor_mode(true);
+        self.push_supervisor(psr);
+        self.push_supervisor(pc);
+    }
+
+    /// Start handling an exception, such as a privilege mode violation
+    ///
+    /// The PSR and PC are pushed onto the supervisor stack and execution continues at the handler
+    /// listed in the interrupt vector table. The handler returns with RTI. If no handler has been
+    /// loaded for the vector, the VM stops with `unhandled` instead.
+    pub fn raise_exception(&mut self, vector: u16, unhandled: VmError) {
+        self.push_context();
+        let handler = self.mem_read(consts::INTERRUPT_VECTOR_TABLE.wrapping_add(vector));
+        if handler == 0 {
+            self.fail(unhandled);
+        } else {
+            self.registers[Register::PC as usize] = handler;
+        }
+    }
+
+    /// Interrupt the program to run the handler for `vector` at the given priority level
+    ///
+    /// The interrupt is only taken if its priority is higher than the processor's and a handler
+    /// has been loaded into the interrupt vector table, which is reported by returning true. The
+    /// handler returns with RTI, which restores the previous priority.
+    pub fn raise_interrupt(&mut self, vector: u16, priority: u16) -> bool {
+        let handler = self.memory[consts::INTERRUPT_VECTOR_TABLE.wrapping_add(vector) as usize];
+        if priority <= self.priority || handler == 0 {
+            return false;
+        }
+        self.push_context();
+        self.priority = priority;
+        self.registers[Register::PC as usize] = handler;
+        true
+    }
+
+    /// Take the keyboard interrupt if the program enabled it in KBSR and a key is waiting
+    fn check_keyboard_interrupt(&mut self) {
+        let kbsr = self.memory[MemoryMappedRegister::KBSR as usize];
+        if kbsr & consts::KBSR_INTERRUPT_ENABLE != 0
+            && self.priority < consts::KEYBOARD_PRIORITY
+            && self.poll_keyboard()
+        {
+            self.raise_interrupt(consts::KEYBOARD_INTERRUPT, consts::KEYBOARD_PRIORITY);
         }
-        self.memory[addr as usize]
+    }
+
+    /// Move the next key into KBDR if there isn't already one waiting there, returning whether a
+    /// key is waiting
+    ///
+    /// This doesn't block. The key stays in KBDR until the program reads it.
+    fn poll_keyboard(&mut self) -> bool {
+        let kbsr = &mut self.memory[MemoryMappedRegister::KBSR as usize];
+        if *kbsr & consts::KBSR_READY != 0 {
+            return true;
+        }
+        match self.input.poll_key() {
+            Some(key) => {
+                *kbsr |= consts::KBSR_READY;
+                self.memory[MemoryMappedRegister::KBDR as usize] = key.into();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Push a value onto the supervisor stack, which must be the current stack
+    fn push_supervisor(&mut self, value: u16) {
+        let sp = self.registers[Register::R6 as usize].wrapping_sub(1);
+        self.registers[Register::R6 as usize] = sp;
+        self.mem_write(sp, value);
+    }
+
+    /// Read the general purpose register `index`, which is one of R0 through R7
+    ///
+    /// These are the only registers instructions can address, so an index past R7 is reported as
+    /// an error rather than reading PC or COND.
+    pub fn register(&self, index: u16) -> Result<u16, VmError> {
+        assert_register_in_range(index)?;
+        Ok(self.registers[index as usize])
+    }
+
+    /// Write the general purpose register `index`, which is one of R0 through R7
+    ///
+    /// This doesn't touch the condition flags. See `register` for the errors.
+    pub fn set_register(&mut self, index: u16, value: u16) -> Result<(), VmError> {
+        assert_register_in_range(index)?;
+        self.registers[index as usize] = value;
+        Ok(())
+    }
+
+    /// Write the destination register of an instruction and set the condition flags from it
+    ///
+    /// An out of range register stops the VM with the error instead.
+    pub(crate) fn write_register(&mut self, index: u16, value: u16) {
+        match self.set_register(index, value) {
+            Ok(()) => self.update_cond_flag(index),
+            Err(error) => self.fail(error),
+        }
+    }
+
+    /// Update the condition flag
+    ///
+    /// This method must be used any time a value is written to a register. It will find the
+    /// condition used at the updated register and update the condition register to reflect that
+    /// value.
+    fn update_cond_flag(&mut self, r: u16) {
+        // The raw bit value of the condition register
+        let raw_cond = self.registers[r as usize];
+        // The converted condition value
+        let cond_flag = instruction::get_cond_flag(raw_cond);
+        self.set_condition(cond_flag);
+    }
+
+    /// Write program output
+    ///
+    /// The trap routines send all of their output through this method. The output is appended to
+    /// the capture buffer if capturing is enabled, otherwise it is written to the output stream.
+    /// The stream is flushed right away so prompts show up before the program waits for input.
+    ///
+    /// The LC3 has no way to report a failed write to the program, so a write or flush error
+    /// stops the VM instead, and the current `step` returns it.
+    pub fn write_output(&mut self, s: &str) {
+        let translated;
+        let s = if self.crlf_newlines {
+            translated = s.replace('\n', "\r\n");
+            &translated
+        } else {
+            s
+        };
+        match &mut self.captured_output {
+            Some(buf) => buf.extend_from_slice(s.as_bytes()),
+            None => {
+                let written = self
+                    .output
+                    .write_all(s.as_bytes())
+                    .and_then(|_| self.output.flush());
+                if let Err(e) = written {
+                    self.fail(VmError::OutputFailed { kind: e.kind() });
+                }
+            }
+        }
+    }
+
+    /// Read one byte of keyboard input, like C's `getchar()`
+    ///
+    /// A key that polling KBSR already moved into KBDR is read first. This returns 0 once the
+    /// input stream is exhausted.
+    pub fn getchar(&mut self) -> u8 {
+        let kbsr = &mut self.memory[MemoryMappedRegister::KBSR as usize];
+        if *kbsr & consts::KBSR_READY != 0 {
+            *kbsr &= !consts::KBSR_READY;
+            return self.memory[MemoryMappedRegister::KBDR as usize] as u8;
+        }
+        self.input.read_key()
+    }
+
+    /// Whether the next instruction reads a key, and no key has been typed yet
+    ///
+    /// Both traps that read a key and loads from KBDR are checked. This is for callers that type
+    /// keys into the sender from `input_sender` themselves, and so can't let the VM block waiting
+    /// for one. GETN may still wait for the rest of a number.
+    pub fn waiting_for_key(&mut self) -> bool {
+        let instr = self.memory[self.registers[Register::PC as usize] as usize];
+        let kbdr = MemoryMappedRegister::KBDR as u16;
+        let reads_key = match decode(instr) {
+            Instruction::TRAP { vector } => matches!(
+                FromPrimitive::from_u16(vector),
+                Some(Trap::GETC) | Some(Trap::IN) | Some(Trap::GETN)
+            ),
+            Instruction::LD { .. } | Instruction::LDI { .. } | Instruction::LDR { .. } => {
+                match self.effective_address() {
+                    Some(EffectiveAddress::Direct(addr)) => addr == kbdr,
+                    Some(EffectiveAddress::Indirect(pointer, addr)) => {
+                        pointer == kbdr || addr == kbdr
+                    }
+                    None => false,
+                }
+            }
+            _ => false,
+        };
+        reads_key && !self.poll_keyboard()
+    }
+
+    /// Take all of the output captured so far, leaving the capture buffer empty
+    ///
+    /// This returns an empty vector if output capturing is disabled.
+    pub fn take_captured_output(&mut self) -> Vec<u8> {
+        self.captured_output
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Take all of the writes journaled so far, leaving the journal empty
+    ///
+    /// This returns an empty vector if journaling is disabled.
+    pub fn take_write_journal(&mut self) -> Vec<(u16, u16)> {
+        self.write_journal
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Save the state that executing an instruction can change, other than memory
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers.clone(),
+            running: self.running,
+            rng_state: self.rng_state,
+            instruction_count: self.instruction_count,
+            supervisor_mode: self.supervisor_mode,
+            saved_stack_pointer: self.saved_stack_pointer,
+            priority: self.priority,
+        }
+    }
+
+    /// Go back to a state saved with `cpu_state`
+    pub fn restore_cpu_state(&mut self, state: CpuState) {
+        self.registers = state.registers;
+        self.running = state.running;
+        self.rng_state = state.rng_state;
+        self.instruction_count = state.instruction_count;
+        self.supervisor_mode = state.supervisor_mode;
+        self.saved_stack_pointer = state.saved_stack_pointer;
+        self.priority = state.priority;
+    }
+
+    /// Write a value to some memory location
+    ///
+    /// This will write a value to the VM's memory bank given the value and the pointer address.
+    /// If `protect_code` is set, writes into the loaded image are refused and stop the VM.
+    pub fn mem_write(&mut self, addr: u16, val: u16) {
+        if self.protect_code
+            && self
+                .code_segment
+                .as_ref()
+                .is_some_and(|code| code.contains(&addr))
+        {
+            self.fail(VmError::CodeSegmentWrite { addr });
+            return;
+        }
+        if !self.check_mmio_access(addr, true) {
+            return;
+        }
+        if let Some(journal) = &mut self.write_journal {
+            journal.push((addr, self.memory[addr as usize]));
+        }
+        // Only the keyboard can change whether a key is waiting
+        let val = if addr == MemoryMappedRegister::KBSR as u16 {
+            self.memory[addr as usize] & consts::KBSR_READY | val & consts::KBSR_INTERRUPT_ENABLE
+        } else {
+            val
+        };
+        self.memory[addr as usize] = val;
+        self.mark_written(addr);
+        if addr == MemoryMappedRegister::DDR as u16 {
+            let c = (val & 0xFF) as u8 as char;
+            self.write_output(&c.to_string());
+        }
+    }
+
+    /// Get the memory mapped register at `addr`, if there is an enabled one
+    fn mmio_register(&self, addr: u16) -> Option<MemoryMappedRegister> {
+        match FromPrimitive::from_u16(addr) {
+            Some(MemoryMappedRegister::CLOCK) if !self.clock_register => None,
+            register => register,
+        }
+    }
+
+    /// Check whether an access to `addr` is permitted if it is a memory mapped register
+    ///
+    /// A forbidden access is logged under the `mmio` target, or stops the VM if `strict_mmio` is
+    /// set. Either way the access should be skipped, which is signaled by returning false.
+    fn check_mmio_access(&mut self, addr: u16, write: bool) -> bool {
+        let register = match self.mmio_register(addr) {
+            Some(register) => register,
+            None => return true,
+        };
+        let allowed = if write {
+            register.is_writable()
+        } else {
+            register.is_readable()
+        };
+
+        if !allowed {
+            let access = if write { "write to" } else { "read from" };
+            let pc = self.registers[Register::PC as usize];
+            if self.strict_mmio {
+                self.fail(VmError::ForbiddenMmioAccess { register, write });
+            } else {
+                warn!(target: "mmio", "forbidden {} {:?} (PC {:#06x})", access, register, pc);
+            }
+        }
+        allowed
+    }
+
+    /// Start tracking how deep the R6 stack gets, assuming it grows downward from `base`
+    pub fn track_stack(&mut self, base: u16) {
+        self.stack_usage = Some(StackUsage::new(base));
+    }
+
+    /// Start recording every address that an instruction is fetched from
+    pub fn track_coverage(&mut self) {
+        self.covered_addresses = Some(vec![0; consts::MEMORY_LIMIT / 64]);
+    }
+
+    /// The addresses that instructions have been fetched from, in ascending order
+    ///
+    /// This is empty unless `track_coverage` was called.
+    pub fn coverage(&self) -> impl Iterator<Item = u16> + '_ {
+        self.covered_addresses.iter().flat_map(|covered| {
+            (0..=u16::MAX)
+                .filter(move |&addr| covered[addr as usize / 64] & (1 << (addr % 64)) != 0)
+        })
+    }
+
+    /// The number of distinct addresses that instructions have been fetched from
+    pub fn coverage_count(&self) -> usize {
+        self.covered_addresses.as_ref().map_or(0, |covered| {
+            covered.iter().map(|word| word.count_ones() as usize).sum()
+        })
+    }
+
+    /// The range of addresses occupied by the loaded image, if one has been loaded
+    pub fn code_segment(&self) -> Option<RangeInclusive<u16>> {
+        self.code_segment.clone()
+    }
+
+    /// Start warning about reads from memory that has never been written
+    ///
+    /// Warnings are logged under the `uninit` target and written to the trace, if there is one.
+    /// This should be called before the image is loaded so that the image's words count as
+    /// initialized.
+    pub fn track_uninitialized_reads(&mut self) {
+        self.written_memory = Some(vec![0; consts::MEMORY_LIMIT / 64]);
+    }
+
+    /// Record that a memory address has been written if uninitialized reads are being tracked
+    fn mark_written(&mut self, addr: u16) {
+        if let Some(written) = &mut self.written_memory {
+            written[addr as usize / 64] |= 1 << (addr % 64);
+        }
+    }
+
+    /// Warn if uninitialized reads are being tracked and `addr` has never been written
+    ///
+    /// Memory mapped registers are skipped because their values come from devices.
+    fn check_initialized(&mut self, addr: u16) {
+        let is_written = match &self.written_memory {
+            Some(written) => written[addr as usize / 64] & (1 << (addr % 64)) != 0,
+            None => return,
+        };
+        if is_written || self.mmio_register(addr).is_some() {
+            return;
+        }
+        let message = format!(
+            "read of uninitialized address {:#06x} (PC {:#06x})",
+            addr,
+            self.registers[Register::PC as usize]
+        );
+        warn!(target: "uninit", "{}", message);
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = writeln!(trace, "warning: {}", message) {
+                warn!("stopped tracing: {}", e);
+                self.trace = None;
+            }
+        }
+    }
+
+    /// Returns the value at a particular memory address
+    ///
+    /// This also has support for memory mapped registers, such as for the keyboard.
+    pub fn mem_read(&mut self, addr: u16) -> u16 {
+        self.check_initialized(addr);
+        if !self.check_mmio_access(addr, false) {
+            return 0;
+        }
+        if addr == MemoryMappedRegister::KBSR as u16 {
+            // Polling the status register must not block, so it is only ready if a key has
+            // already been typed
+            self.poll_keyboard();
+        } else if addr == MemoryMappedRegister::KBDR as u16 {
+            // Reading the data register takes exactly one key, waiting for one if the program
+            // didn't poll the status register first
+            let key = self.getchar();
+            self.memory[MemoryMappedRegister::KBDR as usize] = key.into();
+        }
+        match self.mmio_register(addr) {
+            Some(MemoryMappedRegister::CLOCK) => self.instruction_count as u16,
+            // Output is written immediately, so the display is always ready
+            Some(MemoryMappedRegister::DSR) => consts::DSR_READY,
+            _ => self.memory[addr as usize],
+        }
+    }
+}
+
+/// Check that `index` is one of the general purpose registers R0 through R7
+///
+/// Register fields in instructions are 3 bits wide, so this never fails for a correctly decoded
+/// instruction. It exists to catch a field being decoded with the wrong width.
+pub fn assert_register_in_range(index: u16) -> Result<(), VmError> {
+    if index <= Register::R7 as u16 {
+        Ok(())
+    } else {
+        Err(VmError::RegisterOutOfRange { index })
+    }
+}
+
+/// Load an image from memory, run it until it halts, and return everything it printed
+///
+/// This is a convenience for running programs without a terminal, such as when grading or testing
+/// them. The image can be in any format that `LC3::read_image` accepts. The program reads its
+/// keyboard input from `input` and may execute at most `max_steps` instructions. Execution starts
+/// at the default PC, and the output includes the `HALT` message that the VM prints when the
+/// program halts.
+///
+/// ```
+/// use lc3_vm::{assembler, run_to_halt};
+///
+/// let image = assembler::assemble(".ORIG x3000\nGETC\nOUT\nHALT\n.END").unwrap();
+/// let output = run_to_halt(&assembler::to_bytes(&image), b"x", 100).unwrap();
+/// assert_eq!(output, "xHALT");
+/// ```
+pub fn run_to_halt(image: &[u8], input: &[u8], max_steps: u64) -> Result<String, RunError> {
+    let mut vm = LC3::with_io(io::Cursor::new(input.to_vec()), io::sink());
+    vm.captured_output = Some(Vec::new());
+    vm.max_instructions = Some(max_steps);
+    vm.read_image(image)?;
+    vm.run_loop(&DispatchTables::new())?;
+    Ok(String::from_utf8_lossy(&vm.take_captured_output()).into_owned())
+}
+
+/// The limits that `run_sandboxed` runs an untrusted program under
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SandboxConfig {
+    /// The most instructions the program may execute
+    pub max_steps: u64,
+
+    /// The longest the program may run for
+    pub timeout: Duration,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            max_steps: 1_000_000,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What a program run by `run_sandboxed` printed and why it stopped
+#[derive(Debug)]
+pub struct RunResult {
+    /// Everything the program printed before it stopped
+    pub output: String,
+
+    /// The number of instructions that were executed
+    pub instructions: u64,
+
+    /// Why the program stopped, or `None` if it halted
+    pub error: Option<RunError>,
+}
+
+/// The number of instructions executed between checks of the sandbox's clock
+const SANDBOX_CLOCK_INTERVAL: u64 = 1024;
+
+/// Run an untrusted image with every limit engaged, such as for a playground backend
+///
+/// The program may execute at most `config.max_steps` instructions and run for at most
+/// `config.timeout`. Forbidden accesses to memory mapped registers and stores into the image stop
+/// it with a fault. It reads its keyboard input from `input`, and its output is only captured, so
+/// it never touches the terminal.
+pub fn run_sandboxed(image: &[u8], input: &[u8], config: SandboxConfig) -> RunResult {
+    let mut vm = LC3::with_io(io::Cursor::new(input.to_vec()), io::sink());
+    vm.captured_output = Some(Vec::new());
+    vm.max_instructions = Some(config.max_steps);
+    vm.strict_mmio = true;
+    vm.protect_code = true;
+    let error = vm
+        .read_image(image)
+        .map_err(RunError::from)
+        .and_then(|_| vm.run_for(&DispatchTables::new(), config.timeout))
+        .err();
+    RunResult {
+        output: String::from_utf8_lossy(&vm.take_captured_output()).into_owned(),
+        instructions: vm.instruction_count(),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_support::load, *};
+    use crate::assembler;
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    /// An output stream whose contents can still be read after it is handed to the VM
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn watch_changes_describe_each_write() {
+        let mut vm = load(
+            ".ORIG x3000\nADD R1, R1, #2\nADD R2, R2, #1\nST R1, SLOT\nADD R1, R1, #-1\nHALT\n\
+             SLOT .FILL x0\n.END",
+        );
+        let tables = DispatchTables::new();
+        vm.watched_registers = vec![Register::R1];
+        vm.watched_addresses = vec![0x3005];
+        vm.start();
+        let mut log = Vec::new();
+        while vm.is_running() {
+            let pc = vm.registers[Register::PC as usize];
+            let before = vm.watched_values();
+            vm.step(&tables).unwrap();
+            log.extend(vm.watch_changes(pc, &before));
+        }
+        assert_eq!(
+            log,
+            [
+                "R1: 0x0000 -> 0x0002 (PC 0x3000)",
+                "[0x3005]: 0x0000 -> 0x0002 (PC 0x3002)",
+                "R1: 0x0002 -> 0x0001 (PC 0x3003)",
+            ]
+        );
+    }
+
+    #[test]
+    fn register_accessors_reject_out_of_range_index() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        assert_eq!(vm.set_register(7, 0x1234), Ok(()));
+        assert_eq!(vm.register(7), Ok(0x1234));
+
+        let pc = Register::PC as u16;
+        let error = VmError::RegisterOutOfRange { index: pc };
+        assert_eq!(vm.register(pc), Err(error));
+        assert_eq!(vm.set_register(pc, 0), Err(error));
+        assert_eq!(vm.registers[Register::PC as usize], consts::PC_START);
+
+        // Instructions that hit the check stop the VM instead of panicking
+        vm.start();
+        vm.write_register(8, 1);
+        assert!(!vm.is_running());
+        assert_eq!(
+            vm.error.take(),
+            Some(VmError::RegisterOutOfRange { index: 8 })
+        );
+    }
+
+    #[test]
+    fn effective_address_of_ldr_adds_offset_to_base() {
+        let mut vm = load(".ORIG x3000\nLDR R0, R2, #-3\n.END");
+        vm.registers[Register::R2 as usize] = 0x4000;
+        assert_eq!(
+            vm.effective_address(),
+            Some(EffectiveAddress::Direct(0x3FFD))
+        );
+    }
+
+    #[test]
+    fn effective_address_of_ldi_follows_pointer() {
+        let mut vm = load(".ORIG x3000\nLDI R0, PTR\nHALT\nPTR .FILL xFE02\n.END");
+        vm.set_input(io::Cursor::new(b"k".to_vec()));
+        assert_eq!(
+            vm.effective_address(),
+            Some(EffectiveAddress::Indirect(0x3002, 0xFE02))
+        );
+        // Resolving the address must not consume a key
+        assert_eq!(vm.getchar(), b'k');
+    }
+
+    #[test]
+    fn image_past_end_of_memory_is_rejected() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        let mut image = vec![0xFF, 0xF0];
+        image.extend(&[0x12; 32 * 2]);
+        match vm.load_image(&image) {
+            Err(LoadError::ImageExceedsMemory { origin, words }) => {
+                assert_eq!(origin, 0xFFF0);
+                assert_eq!(words, 32);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(vm.memory[0xFFF0..].iter().all(|&word| word == 0));
+
+        // An image that ends on the last address still fits
+        image.truncate(2 + 16 * 2);
+        assert!(vm.load_image(&image).is_ok());
+        assert_eq!(vm.memory[0xFFFF], 0x1212);
+    }
+
+    #[test]
+    fn keyboard_interrupt_runs_handler() {
+        let mut vm = load(
+            ".ORIG x3000\nLD R0, IE\nSTI R0, KBSR\nLOOP BRnzp LOOP\nIE .FILL x4000\n\
+             KBSR .FILL xFE00\n.END",
+        );
+        // The handler reads the key into R1 and returns
+        vm.memory[0x1000..0x1003].copy_from_slice(&[0xA201, 0x8000, 0xFE02]);
+        vm.memory[(consts::INTERRUPT_VECTOR_TABLE + consts::KEYBOARD_INTERRUPT) as usize] = 0x1000;
+        // Type the key up front so it's already waiting when interrupts are enabled
+        vm.input_sender().send(b'k').unwrap();
+        let tables = DispatchTables::new();
+        vm.start();
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::PC as usize], 0x3001);
+
+        // The interrupt is taken as soon as the store enabling it finishes
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.instruction_count(), 2);
+        assert_eq!(vm.registers[Register::PC as usize], 0x1000);
+        assert_eq!(vm.psr() >> 8 & 0x7, consts::KEYBOARD_PRIORITY);
+        let interrupted_pc = vm.memory[vm.registers[Register::R6 as usize] as usize];
+        assert_eq!(interrupted_pc, 0x3002);
+
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::R1 as usize], u16::from(b'k'));
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::PC as usize], 0x3002);
+        assert_eq!(vm.psr() >> 8 & 0x7, 0);
+        // The key was taken, so the handler doesn't run again
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::PC as usize], 0x3002);
+    }
+
+    #[test]
+    fn uninitialized_read_is_traced() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.captured_output = Some(Vec::new());
+        vm.track_uninitialized_reads();
+        let image = assembler::assemble(
+            ".ORIG x3000\nLD R0, SET\nLDI R1, UNSET\nHALT\nSET .FILL x5\nUNSET .FILL x4000\n.END",
+        )
+        .unwrap();
+        vm.load_image(&assembler::to_bytes(&image)).unwrap();
+        let trace = SharedBuffer::default();
+        vm.trace = Some(Box::new(trace.clone()));
+        vm.run_loop(&DispatchTables::new()).unwrap();
+
+        let trace = String::from_utf8(trace.contents()).unwrap();
+        let warnings: Vec<&str> = trace
+            .lines()
+            .filter(|line| line.starts_with("warning:"))
+            .collect();
+        assert_eq!(
+            warnings,
+            ["warning: read of uninitialized address 0x4000 (PC 0x3002)"]
+        );
+    }
+
+    #[test]
+    fn protected_code_rejects_writes_to_image() {
+        let mut vm = load(".ORIG x3000\nST R0, TARGET\nHALT\nTARGET .FILL x0\n.END");
+        vm.protect_code = true;
+        assert_eq!(
+            vm.run_loop(&DispatchTables::new()),
+            Err(VmError::CodeSegmentWrite { addr: 0x3002 })
+        );
+
+        // Memory outside of the image can still be written
+        let mut vm = load(".ORIG x3000\nSTI R0, TARGET\nHALT\nTARGET .FILL x4000\n.END");
+        vm.protect_code = true;
+        vm.registers[Register::R0 as usize] = 7;
+        assert_eq!(vm.run_loop(&DispatchTables::new()), Ok(()));
+        assert_eq!(vm.memory[0x4000], 7);
+    }
+
+    #[test]
+    fn forced_negative_condition_takes_brn() {
+        // A taken BRn #2 skips over the HALT
+        let mut vm = load(".ORIG x3000\nBRn #2\nHALT\n.END");
+        vm.start();
+        vm.set_condition(ConditionFlag::NEG);
+        assert_eq!(vm.condition_flags(), ConditionFlag::NEG);
+        vm.step(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.registers[Register::PC as usize], 0x3003);
+    }
+
+    #[test]
+    fn fault_dumps_state() {
+        let path = std::env::temp_dir().join(format!("lc3-fault-{}.state", std::process::id()));
+        let mut vm = load(".ORIG x3000\nADD R1, R1, #5\nRTI\n.END");
+        vm.fault_dump = Some(path.clone());
+        vm.set_supervisor_mode(false);
+        assert_eq!(
+            vm.run_loop(&DispatchTables::new()),
+            Err(VmError::PrivilegeViolation { pc: 0x3001 })
+        );
+
+        let mut dumped = LC3::with_io(io::empty(), io::sink());
+        let loaded = dumped.load_state(&path);
+        fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(dumped.registers, vm.registers);
+        assert_eq!(dumped.registers[Register::R1 as usize], 5);
+        assert_eq!(dumped.registers[Register::PC as usize], 0x3002);
+        assert_eq!(dumped.memory, vm.memory);
+    }
+
+    #[test]
+    fn r7_only_changes_on_subroutine_calls_and_traps() {
+        // Every opcode that can't fault on its own, so that the programs keep running
+        const OPS: [Op; 13] = [
+            Op::BR,
+            Op::ADD,
+            Op::LD,
+            Op::ST,
+            Op::JSR,
+            Op::AND,
+            Op::LDR,
+            Op::STR,
+            Op::NOT,
+            Op::LDI,
+            Op::STI,
+            Op::JMP,
+            Op::LEA,
+        ];
+        let tables = DispatchTables::new();
+        let mut rng = consts::DEFAULT_RNG_SEED;
+        let mut random = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            (rng >> 16) as u16
+        };
+
+        for _ in 0..200 {
+            let mut vm = LC3::with_io(NullIo, NullIo);
+            vm.captured_output = Some(Vec::new());
+            for addr in 0x3000..0x3040 {
+                let op = OPS[random() as usize % OPS.len()];
+                vm.memory[addr] = (op as u16) << 12 | random() & 0x0FFF;
+            }
+            vm.memory[0x3040] = 0xF025;
+            vm.start();
+
+            for _ in 0..500 {
+                let record = match vm.step_record(&tables) {
+                    Ok(record) => record,
+                    Err(_) => break,
+                };
+                let r7_before = record.registers_before[Register::R7 as usize];
+                let r7_after = record.registers_after[Register::R7 as usize];
+                match decode(record.instr) {
+                    Instruction::JSR { .. }
+                    | Instruction::JSRR { .. }
+                    | Instruction::TRAP { .. } => {
+                        assert_eq!(r7_after, record.pc.wrapping_add(1), "{:x?}", record)
+                    }
+                    Instruction::ADD { dr, .. }
+                    | Instruction::AND { dr, .. }
+                    | Instruction::NOT { dr, .. }
+                    | Instruction::LD { dr, .. }
+                    | Instruction::LDI { dr, .. }
+                    | Instruction::LDR { dr, .. }
+                    | Instruction::LEA { dr, .. }
+                        if dr == Register::R7 => {}
+                    _ => assert_eq!(r7_after, r7_before, "{:x?}", record),
+                }
+                if !vm.is_running() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_op_and_trap_has_a_handler() {
+        // Building the tables panics if an opcode is missing or listed twice
+        let tables = DispatchTables::new();
+        for (raw_op, &op) in Op::ALL.iter().enumerate() {
+            assert_eq!(op as usize, raw_op);
+        }
+
+        let traps: Vec<Trap> = (0..=0xFF).filter_map(Trap::from_u16).collect();
+        assert_eq!(traps.len(), 10);
+        for trap in traps {
+            // Extended traps have no table entry, and use `trap_routine` once they are enabled
+            assert_eq!(
+                tables.traps[trap as usize].is_some(),
+                !trap.is_extended(),
+                "{:?}",
+                trap
+            );
+        }
+    }
+
+    #[test]
+    fn every_opcode_dispatches() {
+        let tables = DispatchTables::new();
+        for raw_op in 0..16u16 {
+            let op = Op::of(raw_op << 12);
+            assert_eq!(op as u16, raw_op);
+
+            let mut vm = LC3::with_io(io::empty(), io::sink());
+            vm.captured_output = Some(Vec::new());
+            vm.memory[0x3000] = raw_op << 12;
+            vm.start();
+            let result = vm.step(&tables);
+            assert_eq!(vm.instruction_count(), 1, "{:?}", op);
+            let expected = match op {
+                Op::RES => Err(VmError::IllegalOpcode { pc: 0x3000 }),
+                Op::RTI => Err(VmError::PrivilegeViolation { pc: 0x3000 }),
+                Op::TRAP => Err(VmError::BadTrap {
+                    pc: 0x3000,
+                    vector: 0,
+                }),
+                _ => Ok(()),
+            };
+            assert_eq!(result, expected, "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn binary_trace_decodes_to_executed_instructions() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nBRp SKIP\nNOT R0, R0\nSKIP HALT\n.END");
+        let trace = SharedBuffer::default();
+        vm.binary_trace = Some(Box::new(trace.clone()));
+        vm.run_loop(&DispatchTables::new()).unwrap();
+
+        let bytes = trace.contents();
+        assert_eq!(bytes.len(), 3 * trace::RECORD_SIZE);
+        let records = trace::read_records(bytes.as_slice()).unwrap();
+        let lines: Vec<String> = records.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "       0 3000 1021 ADD R0, R0, #1",
+                "       1 3001 0201 BRp #1",
+                "       2 3003 f025 HALT",
+            ]
+        );
+        assert!(trace::read_records(&bytes[..5]).is_err());
+    }
+
+    #[test]
+    fn null_io_runs_without_blocking() {
+        let mut vm = LC3::with_io(NullIo, NullIo);
+        let image = assembler::assemble(
+            ".ORIG x3000\nGETC\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"hi\"\n.END",
+        )
+        .unwrap();
+        vm.load_image(&assembler::to_bytes(&image)).unwrap();
+        assert_eq!(vm.run_loop(&DispatchTables::new()), Ok(()));
+        assert!(vm.take_captured_output().is_empty());
+    }
+
+    #[test]
+    fn kbdr_read_takes_one_key() {
+        let mut vm = load(
+            ".ORIG x3000\nLDI R1, KBDR\nGETC\nLDI R2, KBSR\nLDI R3, KBDR\nHALT\n\
+             KBDR .FILL xFE02\nKBSR .FILL xFE00\n.END",
+        );
+        vm.set_input(io::Cursor::new(b"abc".to_vec()));
+        let tables = DispatchTables::new();
+        vm.start();
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::R1 as usize], u16::from(b'a'));
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::R0 as usize], u16::from(b'b'));
+
+        // The key found by polling KBSR is the one KBDR returns
+        while vm.registers[Register::R2 as usize] & consts::KBSR_READY == 0 {
+            vm.registers[Register::PC as usize] = 0x3002;
+            vm.step(&tables).unwrap();
+        }
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::R3 as usize], u16::from(b'c'));
+        assert_eq!(vm.memory[MemoryMappedRegister::KBSR as usize], 0);
+    }
+
+    #[test]
+    fn mmio_permissions_per_register() {
+        use MemoryMappedRegister::*;
+        // Each register with whether it can be read and written
+        let permissions = [
+            (KBSR, true, true),
+            (KBDR, true, false),
+            (DSR, true, false),
+            (DDR, false, true),
+            (CLOCK, true, false),
+        ];
+        for &(register, readable, writable) in &permissions {
+            assert_eq!(register.is_readable(), readable, "{:?}", register);
+            assert_eq!(register.is_writable(), writable, "{:?}", register);
+
+            for &write in &[false, true] {
+                let mut vm = LC3::with_io(io::empty(), io::sink());
+                vm.captured_output = Some(Vec::new());
+                vm.strict_mmio = true;
+                vm.clock_register = true;
+                if write {
+                    vm.mem_write(register as u16, u16::from(b'A'));
+                } else {
+                    vm.mem_read(register as u16);
+                }
+                let allowed = if write { writable } else { readable };
+                let expected = if allowed {
+                    None
+                } else {
+                    Some(VmError::ForbiddenMmioAccess { register, write })
+                };
+                assert_eq!(vm.error, expected, "{:?} write: {}", register, write);
+            }
+        }
+    }
+
+    #[test]
+    fn display_registers_write_output() {
+        let mut vm = load(
+            ".ORIG x3000\nPOLL LDI R1, DSR\nBRzp POLL\nLD R0, CHAR\nSTI R0, DDR\nHALT\n\
+             DSR .FILL xFE04\nDDR .FILL xFE06\nCHAR .FILL x41\n.END",
+        );
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.take_captured_output(), b"AHALT");
+    }
+
+    #[test]
+    fn stack_usage_tracks_depth_and_peak() {
+        let mut vm = load(
+            ".ORIG x3000\nLD R6, BASE\nADD R6, R6, #-1\nADD R6, R6, #-1\nADD R6, R6, #-1\n\
+             ADD R6, R6, #1\nHALT\nBASE .FILL x4000\n.END",
+        );
+        vm.track_stack(0x4000);
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        let usage = vm.stack_usage.unwrap();
+        assert_eq!(usage.depth, 2);
+        assert_eq!(usage.peak, 3);
+    }
+
+    #[test]
+    fn clock_register_counts_instructions() {
+        let source = ".ORIG x3000\nLDI R1, CLOCK\nADD R3, R3, #1\nADD R3, R3, #1\nLDI R2, CLOCK\n\
+                      HALT\nCLOCK .FILL xFE08\n.END";
+        let mut vm = load(source);
+        vm.clock_register = true;
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        let (first, second) = (vm.registers[1], vm.registers[2]);
+        assert_eq!(second.wrapping_sub(first), 3);
+
+        // Without the flag the address is ordinary memory
+        let mut vm = load(source);
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        assert_eq!((vm.registers[1], vm.registers[2]), (0, 0));
+    }
+
+    #[test]
+    fn crlf_newlines_translates_output() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.captured_output = Some(Vec::new());
+        vm.write_output("a\nb\n");
+        vm.crlf_newlines = true;
+        vm.write_output("c\nd");
+        assert_eq!(vm.take_captured_output(), b"a\nb\nc\r\nd");
+    }
+
+    #[test]
+    fn sandbox_stops_infinite_program() {
+        let image = assembler::assemble(".ORIG x3000\nLOOP BRnzp LOOP\n.END").unwrap();
+        let image = assembler::to_bytes(&image);
+
+        let config = SandboxConfig {
+            max_steps: 1000,
+            timeout: Duration::from_secs(60),
+        };
+        let result = run_sandboxed(&image, b"", config);
+        assert!(matches!(
+            result.error,
+            Some(RunError::Fault(VmError::InstructionLimit { limit: 1000 }))
+        ));
+        assert_eq!(result.instructions, 1000);
+        assert_eq!(result.output, "");
+
+        let config = SandboxConfig {
+            max_steps: u64::MAX,
+            timeout: Duration::from_millis(10),
+        };
+        let result = run_sandboxed(&image, b"", config);
+        assert!(matches!(result.error, Some(RunError::Timeout { .. })));
+        assert_eq!(result.output, "");
+    }
+
+    #[test]
+    fn sandbox_faults_on_writes_to_code_and_mmio() {
+        let image = assembler::assemble(".ORIG x3000\nST R0, #-1\nHALT\n.END").unwrap();
+        let result = run_sandboxed(&assembler::to_bytes(&image), b"", SandboxConfig::default());
+        assert!(matches!(
+            result.error,
+            Some(RunError::Fault(VmError::CodeSegmentWrite { addr: 0x3000 }))
+        ));
+
+        let image = assembler::assemble(".ORIG x3000\nSTI R0, DSR\nHALT\nDSR .FILL xFE04\n.END");
+        let image = assembler::to_bytes(&image.unwrap());
+        let result = run_sandboxed(&image, b"", SandboxConfig::default());
+        assert!(matches!(
+            result.error,
+            Some(RunError::Fault(VmError::ForbiddenMmioAccess {
+                register: MemoryMappedRegister::DSR,
+                write: true
+            }))
+        ));
+    }
+
+    #[test]
+    fn coverage_excludes_dead_block() {
+        let mut vm =
+            load(".ORIG x3000\nBRnzp SKIP\nADD R0, R0, #1\nADD R0, R0, #1\nSKIP HALT\n.END");
+        vm.track_coverage();
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.coverage().collect::<Vec<u16>>(), [0x3000, 0x3003]);
+        assert_eq!(vm.coverage_count(), 2);
+    }
+
+    #[test]
+    fn start_pc_survives_reset() {
+        let mut vm = LC3::with_pc_start(0x4000);
+        assert_eq!(vm.registers[Register::PC as usize], 0x4000);
+        vm.memory[0x4000] = 0x1021; // ADD R0, R0, #1
+        vm.start();
+        vm.step(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.registers[Register::PC as usize], 0x4001);
+        vm.reset();
+        assert_eq!(vm.registers[Register::PC as usize], 0x4000);
+
+        // A new start address only takes effect at the next reset
+        vm.set_pc_start(0x5000);
+        assert_eq!(vm.registers[Register::PC as usize], 0x4000);
+        vm.reset();
+        assert_eq!(vm.registers[Register::PC as usize], 0x5000);
+        assert_eq!(vm.pc_start(), 0x5000);
+    }
+
+    #[test]
+    fn load_report_describes_image() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        let report = vm.load_words(&[0x4000, 1, 2, 3]).unwrap();
+        assert_eq!(
+            report,
+            LoadReport {
+                origin: 0x4000,
+                words_loaded: 3,
+                end_addr: 0x4002,
+            }
+        );
+        assert_eq!(report.to_string(), "loaded 0x4000-0x4002 (3 words)");
+
+        let report = vm.load_words(&[0x3000]).unwrap();
+        assert_eq!(report.words_loaded, 0);
+        assert_eq!(report.end_addr, 0x3000);
+        assert_eq!(report.to_string(), "loaded nothing at 0x3000");
+
+        assert!(matches!(
+            vm.load_words(&[0xFFFF, 1, 2]),
+            Err(LoadError::ImageExceedsMemory {
+                origin: 0xFFFF,
+                words: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn with_io_reads_input_and_writes_output_streams() {
+        let output = SharedBuffer::default();
+        let mut vm = LC3::with_io(&b"hi"[..], output.clone());
+        let image = assembler::assemble(
+            ".ORIG x3000\nGETC\nOUT\nIN\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"!\"\n.END",
+        );
+        vm.load_image(&assembler::to_bytes(&image.unwrap()))
+            .unwrap();
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        let output = String::from_utf8(output.contents()).unwrap();
+        assert_eq!(output, "hEnter a character: i\n!HALT");
+    }
+
+    #[test]
+    fn image_words_are_big_endian() {
+        let path = std::env::temp_dir().join(format!("lc3-words-{}.obj", std::process::id()));
+        fs::write(&path, [0x30, 0x00, 0x12, 0x34, 0xAB, 0xCD, 0xFF, 0x00]).unwrap();
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.read_image_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(vm.memory[0x3000..0x3004], [0x1234, 0xABCD, 0xFF00, 0]);
+    }
+
+    #[test]
+    fn injected_key_makes_keyboard_ready() {
+        let mut vm = load(
+            ".ORIG x3000\nLDI R1, KBSR\nLDI R2, KBDR\nHALT\n\
+             KBSR .FILL xFE00\nKBDR .FILL xFE02\n.END",
+        );
+        let keys = vm.input_sender();
+        let tables = DispatchTables::new();
+        vm.start();
+        keys.send(b'k').unwrap();
+        vm.step(&tables).unwrap();
+        assert_ne!(vm.registers[Register::R1 as usize] & consts::KBSR_READY, 0);
+        assert_eq!(
+            vm.memory[MemoryMappedRegister::KBDR as usize],
+            u16::from(b'k')
+        );
+        vm.step(&tables).unwrap();
+        assert_eq!(vm.registers[Register::R2 as usize], u16::from(b'k'));
+        assert_eq!(vm.memory[MemoryMappedRegister::KBSR as usize], 0);
+    }
+
+    #[test]
+    fn instruction_cap_stops_infinite_loop() {
+        let mut vm = load(".ORIG x3000\nLOOP BRnzp LOOP\n.END");
+        vm.max_instructions = Some(50);
+        assert_eq!(
+            vm.run_loop(&DispatchTables::new()),
+            Err(VmError::InstructionLimit { limit: 50 })
+        );
+        assert_eq!(vm.instruction_count(), 50);
+        assert!(!vm.is_running());
+    }
+
+    #[test]
+    fn instruction_count_is_one_per_step() {
+        let mut vm =
+            load(".ORIG x3000\nLEA R0, MSG\nPUTS\nADD R1, R1, #2\nHALT\nMSG .STRINGZ \"hi\"\n.END");
+        assert_eq!(vm.instruction_count(), 0);
+        let tables = DispatchTables::new();
+        vm.start();
+        for count in 1..=2 {
+            vm.step(&tables).unwrap();
+            assert_eq!(vm.instruction_count(), count);
+        }
+        // The traps count as single instructions however much work their routines do
+        vm.run_loop(&tables).unwrap();
+        assert_eq!(vm.instruction_count(), 4);
+        assert_eq!(vm.take_captured_output(), b"hiHALT");
+    }
+
+    #[test]
+    fn trace_writes_a_line_per_instruction() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END");
+        let trace = SharedBuffer::default();
+        vm.trace = Some(Box::new(trace.clone()));
+        vm.run_loop(&DispatchTables::new()).unwrap();
+
+        let contents = String::from_utf8(trace.contents()).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "3000 1021 ADD R0, R0, #1       \
+             R0=0001 R1=0000 R2=0000 R3=0000 R4=0000 R5=0000 R6=0000 R7=0000 PC=3001 COND=0001"
+        );
+        assert!(lines[1].starts_with("3001 f025 HALT                 R0=0001"));
+    }
+
+    #[test]
+    fn saved_state_resumes_where_it_left_off() {
+        let path = std::env::temp_dir().join(format!("lc3-resume-{}.state", std::process::id()));
+        let source = ".ORIG x3000\nLD R1, N\nLOOP ADD R0, R0, #1\nADD R1, R1, #-1\nBRp LOOP\n\
+                      ST R0, N\nHALT\nN .FILL #4\n.END";
+        let mut vm = load(source);
+        let tables = DispatchTables::new();
+        vm.start();
+        for _ in 0..5 {
+            vm.step(&tables).unwrap();
+        }
+        vm.save_state(&path).unwrap();
+
+        let mut resumed = LC3::with_io(io::empty(), io::sink());
+        resumed.captured_output = Some(Vec::new());
+        let loaded = resumed.load_state(&path);
+        fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(resumed.registers, vm.registers);
+        assert_eq!(resumed.memory, vm.memory);
+        assert_eq!(resumed.instruction_count(), 5);
+        assert!(resumed.is_running());
+        assert_eq!(resumed.is_supervisor_mode(), vm.is_supervisor_mode());
+
+        vm.run_loop(&tables).unwrap();
+        resumed.run_loop(&tables).unwrap();
+        assert_eq!(resumed.registers, vm.registers);
+        assert_eq!(resumed.memory, vm.memory);
+        assert_eq!(resumed.mem_read(0x3006), 4);
+        assert_eq!(resumed.instruction_count(), vm.instruction_count());
+    }
+
+    #[test]
+    fn getc_loop_reads_input_until_it_runs_out() {
+        let mut vm = load(
+            ".ORIG x3000\nLOOP GETC\nADD R0, R0, #0\nBRz DONE\nOUT\nBRnzp LOOP\nDONE HALT\n.END",
+        );
+        vm.set_input(&b"abc"[..]);
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.take_captured_output(), b"abcHALT");
+        // Once the input runs out, the keyboard never reports another key
+        assert!(!vm.poll_keyboard());
+    }
+
+    #[test]
+    fn loading_leaves_the_start_address_until_asked() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.captured_output = Some(Vec::new());
+        assert_eq!(vm.registers[Register::PC as usize], consts::PC_START);
+        let image = assembler::assemble(".ORIG x4000\nADD R0, R0, #7\nHALT\n.END").unwrap();
+        let report = vm.load_image(&assembler::to_bytes(&image)).unwrap();
+        assert_eq!(report.origin, 0x4000);
+        assert_eq!(vm.registers[Register::PC as usize], consts::PC_START);
+
+        // Running from the origin, as `--start-at-origin` does
+        vm.start_at(report.origin);
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.registers[Register::R0 as usize], 7);
+        vm.reset();
+        assert_eq!(vm.registers[Register::PC as usize], 0x4000);
+    }
+
+    #[test]
+    fn last_address_is_addressable() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        assert_eq!(vm.memory.len(), 0x10000);
+        vm.mem_write(0xFFFF, 0xBEEF);
+        assert_eq!(vm.mem_read(0xFFFF), 0xBEEF);
+
+        // An image may fill memory right up to the last address, but not past it
+        vm.load_image(&[0xFF, 0xFE, 0x12, 0x34, 0x56, 0x78])
+            .unwrap();
+        assert_eq!(vm.mem_read(0xFFFF), 0x5678);
+        assert!(matches!(
+            vm.load_image(&[0xFF, 0xFF, 0x12, 0x34, 0x56, 0x78]),
+            Err(LoadError::ImageExceedsMemory {
+                origin: 0xFFFF,
+                words: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn each_fault_is_returned_from_run_loop() {
+        fn run(source: &str, configure: fn(&mut LC3)) -> Result<(), VmError> {
+            let mut vm = load(&format!(".ORIG x3000\n{}\n.END", source));
+            configure(&mut vm);
+            let result = vm.run_loop(&DispatchTables::new());
+            assert!(!vm.is_running());
+            result
+        }
+        let no_config = |_: &mut LC3| {};
+
+        assert_eq!(
+            run(".FILL xD000", no_config),
+            Err(VmError::IllegalOpcode { pc: 0x3000 })
+        );
+        assert_eq!(
+            run("TRAP x40", no_config),
+            Err(VmError::BadTrap {
+                pc: 0x3000,
+                vector: 0x40
+            })
+        );
+        assert_eq!(
+            run("RTI", no_config),
+            Err(VmError::PrivilegeViolation { pc: 0x3000 })
+        );
+        assert_eq!(
+            run(
+                "LD R1, END\nNOT R1, R1\nADD R2, R2, #2\nMEMCPY\nEND .FILL 0",
+                |vm| { vm.extended_traps = true }
+            ),
+            Err(VmError::AddressOutOfRange)
+        );
+        assert_eq!(
+            run("ST R0, #-1", |vm| vm.protect_code = true),
+            Err(VmError::CodeSegmentWrite { addr: 0x3000 })
+        );
+        assert_eq!(
+            run("STI R0, DSR\nDSR .FILL xFE04", |vm| vm.strict_mmio = true),
+            Err(VmError::ForbiddenMmioAccess {
+                register: MemoryMappedRegister::DSR,
+                write: true
+            })
+        );
+        assert_eq!(
+            run("GETN", |vm| {
+                vm.extended_traps = true;
+                vm.strict_getn = true;
+                vm.set_input(&b"70000\n"[..]);
+            }),
+            Err(VmError::NumberOutOfRange)
+        );
+        assert_eq!(
+            run("LOOP BRnzp LOOP", |vm| vm.max_instructions = Some(10)),
+            Err(VmError::InstructionLimit { limit: 10 })
+        );
+        assert_eq!(
+            run("HALT", |vm| vm.max_trap_depth = 0),
+            Err(VmError::TrapRecursionLimit {
+                pc: 0x3000,
+                limit: 0
+            })
+        );
+
+        /// An output stream whose reader has gone away
+        struct BrokenPipe;
+
+        impl Write for BrokenPipe {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut vm = LC3::with_io(io::empty(), BrokenPipe);
+        vm.memory[0x3000] = 0xF025; // HALT
+        assert_eq!(
+            vm.run_loop(&DispatchTables::new()),
+            Err(VmError::OutputFailed {
+                kind: io::ErrorKind::BrokenPipe
+            })
+        );
+
+        // No instruction can select a register outside of R0 through R7, so this is only reachable
+        // through a decoding bug
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.start();
+        vm.write_register(Register::PC as u16, 0);
+        assert!(!vm.is_running());
+        assert_eq!(
+            vm.error.take(),
+            Some(VmError::RegisterOutOfRange {
+                index: Register::PC as u16
+            })
+        );
+
+        let image = assembler::to_bytes(&[0x3000, 0x0FFF]); // BRnzp #-1
+        let config = SandboxConfig {
+            max_steps: u64::MAX,
+            timeout: Duration::ZERO,
+        };
+        assert!(matches!(
+            run_sandboxed(&image, b"", config).error,
+            Some(RunError::Timeout {
+                limit: Duration::ZERO
+            })
+        ));
+    }
+
+    #[test]
+    fn putn_prints_computed_sum() {
+        let mut vm = load(
+            ".ORIG x3000
+                    AND R0, R0, #0
+                    ADD R1, R0, #10
+            LOOP    ADD R0, R0, R1
+                    ADD R1, R1, #-1
+                    BRp LOOP
+                    PUTN
+                    NOT R0, R0
+                    ADD R0, R0, #1
+                    PUTN
+                    HALT
+            .END",
+        );
+        vm.extended_traps = true;
+        vm.run_loop(&DispatchTables::new()).unwrap();
+        assert_eq!(vm.take_captured_output(), b"55-55HALT");
+    }
+
+    #[test]
+    fn run_to_halt_echoes_a_line() {
+        let image = assembler::assemble(
+            ".ORIG x3000
+            LOOP    GETC
+                    OUT
+                    ADD R1, R0, #-10
+                    BRnp LOOP
+                    HALT
+            .END",
+        )
+        .unwrap();
+        let image = assembler::to_bytes(&image);
+        assert_eq!(
+            run_to_halt(&image, b"echo\nignored", 100).unwrap(),
+            "echo\nHALT"
+        );
+        // Without a newline the program waits for one, reading 0 forever
+        assert!(matches!(
+            run_to_halt(&image, b"echo", 100),
+            Err(RunError::Fault(VmError::InstructionLimit { limit: 100 }))
+        ));
+    }
+
+    #[test]
+    fn read_image_loads_from_a_slice() {
+        let image: &[u8] = &[0x40, 0x00, 0x12, 0x61, 0xF0, 0x25];
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        let report = vm.read_image(image).unwrap();
+        assert_eq!(report.origin, 0x4000);
+        assert_eq!(report.words_loaded, 2);
+        assert_eq!(vm.memory[0x3FFF..0x4003], [0, 0x1261, 0xF025, 0]);
+
+        // Any reader works, even one that hands the image over in pieces
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.read_image(image[..3].chain(&image[3..])).unwrap();
+        assert_eq!(vm.memory[0x4000..0x4002], [0x1261, 0xF025]);
+    }
+
+    #[test]
+    fn output_is_flushed_after_each_trap() {
+        /// An output stream that records every write and flush made to it
+        #[derive(Clone, Default)]
+        struct FlushRecorder(Arc<Mutex<Vec<Option<String>>>>);
+
+        impl Write for FlushRecorder {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let text = String::from_utf8_lossy(buf).into_owned();
+                self.0.lock().unwrap().push(Some(text));
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.lock().unwrap().push(None);
+                Ok(())
+            }
+        }
+
+        let recorder = FlushRecorder::default();
+        let mut vm = LC3::with_io(&b"k"[..], recorder.clone());
+        let image = assembler::assemble(
+            ".ORIG x3000
+                    IN
+                    LEA R0, WORDS
+                    PUTS
+                    LEA R0, BYTES
+                    PUTSP
+                    HALT
+            WORDS   .STRINGZ \"ab\"
+            BYTES   .FILL x6463
+                    .FILL 0
+            .END",
+        )
+        .unwrap();
+        vm.load_image(&assembler::to_bytes(&image)).unwrap();
+        vm.run_loop(&DispatchTables::new()).unwrap();
+
+        // Every write, including the prompt from IN, is flushed before the next one
+        let events = recorder.0.lock().unwrap();
+        let writes: Vec<_> = events.iter().step_by(2).cloned().collect();
+        assert!(events.iter().skip(1).step_by(2).all(Option::is_none));
+        assert_eq!(events.len(), writes.len() * 2);
+        assert_eq!(
+            writes.into_iter().map(Option::unwrap).collect::<String>(),
+            "Enter a character: k\nabcdHALT"
+        );
+    }
+
+    #[test]
+    fn run_for_dumps_state_at_instruction_limit() {
+        let path = std::env::temp_dir().join(format!("lc3-limit-{}.state", std::process::id()));
+        let mut vm = load(".ORIG x3000\nLOOP ADD R1, R1, #1\nBRnzp LOOP\n.END");
+        vm.fault_dump = Some(path.clone());
+        vm.max_instructions = Some(4);
+        assert!(matches!(
+            vm.run_for(&DispatchTables::new(), Duration::from_secs(60)),
+            Err(RunError::Fault(VmError::InstructionLimit { limit: 4 }))
+        ));
+
+        let mut dumped = LC3::with_io(io::empty(), io::sink());
+        let loaded = dumped.load_state(&path);
+        fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(dumped.registers[Register::R1 as usize], 2);
+    }
+
+    #[test]
+    fn debug_shows_execution_state() {
+        let mut vm = load(".ORIG x3000\nADD R1, R1, #2\nHALT\n.END");
+        vm.step(&DispatchTables::new()).unwrap();
+        assert_eq!(
+            format!("{:?}", vm),
+            "LC3 { registers: [0, 2, 0, 0, 0, 0, 0, 0], pc: 12289, running: false, \
+             instruction_count: 1 }"
+        );
     }
 }
@@ -1,13 +1,16 @@
-use crate::lc3::LC3;
+use crate::lc3::{DispatchTables, LC3};
 use num_derive::{FromPrimitive, ToPrimitive};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 /// Constant values that pertain to the LC3 virtual machine
-use std::collections::HashMap;
+use std::{fmt, str::FromStr};
 
 /// An enum representing the different types of registers
 ///
 /// The different opcodes are commented if they have special functionality. The R[N] registers are
 /// normal registers that can store memory.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, ToPrimitive, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Register {
     R0 = 0,
     R1,
@@ -30,8 +33,30 @@ pub enum Register {
     COUNT,
 }
 
+impl FromStr for Register {
+    type Err = String;
+
+    /// Parse a register from its name, such as `R0`, `PC`, or `COND` (case insensitive)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_ref() {
+            "R0" => Ok(Register::R0),
+            "R1" => Ok(Register::R1),
+            "R2" => Ok(Register::R2),
+            "R3" => Ok(Register::R3),
+            "R4" => Ok(Register::R4),
+            "R5" => Ok(Register::R5),
+            "R6" => Ok(Register::R6),
+            "R7" => Ok(Register::R7),
+            "PC" => Ok(Register::PC),
+            "COND" => Ok(Register::COND),
+            _ => Err(format!("unknown register \"{}\"", s)),
+        }
+    }
+}
+
 /// The available opcodes for the LC3 VM
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, ToPrimitive, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Op {
     /// Branch
     BR = 0,
@@ -82,8 +107,38 @@ pub enum Op {
     TRAP,
 }
 
+impl Op {
+    /// Every opcode, indexed by the value of its 4-bit opcode field
+    pub const ALL: [Op; 16] = [
+        Op::BR,
+        Op::ADD,
+        Op::LD,
+        Op::ST,
+        Op::JSR,
+        Op::AND,
+        Op::LDR,
+        Op::STR,
+        Op::RTI,
+        Op::NOT,
+        Op::LDI,
+        Op::STI,
+        Op::JMP,
+        Op::RES,
+        Op::LEA,
+        Op::TRAP,
+    ];
+
+    /// The opcode of an instruction word
+    ///
+    /// The opcode field is 4 bits wide and every value is an opcode, so this can't fail.
+    pub fn of(instr: u16) -> Op {
+        Op::ALL[(instr >> 12) as usize]
+    }
+}
+
 /// The trap routines available for LC3
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, ToPrimitive, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Trap {
     /// Get a character from the keyboard (not echoed onto the terminal)
     GETC = 0x20,
@@ -102,11 +157,36 @@ pub enum Trap {
 
     /// Halt the program
     HALT = 0x25,
+
+    /// Output the value in R0 as a signed decimal number (extended trap)
+    PUTN = 0x26,
+
+    /// Copy R2 words of memory from the address in R0 to the address in R1 (extended trap)
+    MEMCPY = 0x30,
+
+    /// Put a pseudo-random 16-bit value in R0 (extended trap)
+    RAND = 0x31,
+
+    /// Read a decimal number from the input into R0 (extended trap)
+    GETN = 0x32,
+}
+
+impl Trap {
+    /// Whether this is an extended trap that isn't part of the standard LC3 trap set
+    ///
+    /// Extended traps are only dispatched when the VM has opted into them.
+    pub fn is_extended(self) -> bool {
+        match self {
+            Trap::PUTN | Trap::MEMCPY | Trap::RAND | Trap::GETN => true,
+            Trap::GETC | Trap::OUT | Trap::PUTS | Trap::IN | Trap::PUTSP | Trap::HALT => false,
+        }
+    }
 }
 
 /// These are the different condition flags that can be used for an operation. These flags allow a
 /// program to check logical conditions.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, ToPrimitive, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ConditionFlag {
     /// Positive: P
     POS = 1, // 1 << 0
@@ -126,17 +206,96 @@ pub enum MemoryMappedRegister {
 
     /// Keyboard data
     KBDR = 0xFE02,
+
+    /// Display status
+    DSR = 0xFE04,
+
+    /// Display data
+    DDR = 0xFE06,
+
+    /// The low 16 bits of the number of instructions executed, if the clock register is enabled
+    CLOCK = 0xFE08,
+}
+
+impl MemoryMappedRegister {
+    /// Whether programs are allowed to read this register
+    ///
+    /// DDR only sends characters to the display, so reading it has no meaning.
+    pub fn is_readable(self) -> bool {
+        match self {
+            MemoryMappedRegister::KBSR
+            | MemoryMappedRegister::KBDR
+            | MemoryMappedRegister::DSR
+            | MemoryMappedRegister::CLOCK => true,
+            MemoryMappedRegister::DDR => false,
+        }
+    }
+
+    /// Whether programs are allowed to write to this register
+    ///
+    /// Programs can only write the interrupt enable bit of KBSR, and characters to DDR. The
+    /// keyboard data is only ever set by the keyboard, the display status by the display, and the
+    /// clock is only advanced by executing instructions, so writes to them have no effect.
+    pub fn is_writable(self) -> bool {
+        match self {
+            MemoryMappedRegister::KBSR | MemoryMappedRegister::DDR => true,
+            MemoryMappedRegister::KBDR
+            | MemoryMappedRegister::DSR
+            | MemoryMappedRegister::CLOCK => false,
+        }
+    }
 }
 
 /// The number of pointers that can be addressed. The LC3 virtual machine has 16-bit pointers, so
-/// the maximum addressable value is the max value of an unsigned 16 bit integer. 2 ^ 16 = 65536.
-pub const MEMORY_LIMIT: usize = std::u16::MAX as usize;
+/// every address from 0x0000 through 0xFFFF is valid. 2 ^ 16 = 65536.
+pub const MEMORY_LIMIT: usize = u16::MAX as usize + 1;
+
+/// The seed for the VM's random number generator when none is provided
+pub const DEFAULT_RNG_SEED: u32 = 0x2545_F491;
 
 /// The default start position for the program counter
 pub const PC_START: u16 = 0x3000;
 
-/// A type representing the dispatch table for opcodes
-pub type OpDispatchTable = HashMap<Op, fn(&mut LC3, instr: u16)>;
+/// The initial supervisor stack pointer. The supervisor stack grows down from the start of user
+/// space.
+pub const SUPERVISOR_STACK_START: u16 = 0x3000;
+
+/// The address of the interrupt vector table, which holds the address of the handler for each
+/// interrupt and exception vector
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+/// The exception vector for executing a privileged instruction in user mode
+pub const PRIVILEGE_MODE_VIOLATION: u16 = 0x00;
+
+/// The bit of the processor status register that is set in user mode
+pub const PSR_USER_MODE: u16 = 1 << 15;
+
+/// The most trap routines that can be running at once before the VM gives up on a runaway
+/// recursion
+pub const DEFAULT_MAX_TRAP_DEPTH: u32 = 64;
+
+/// The interrupt vector for the keyboard
+pub const KEYBOARD_INTERRUPT: u16 = 0x80;
+
+/// The priority level of keyboard interrupts
+pub const KEYBOARD_PRIORITY: u16 = 4;
+
+/// The bit of KBSR that is set while a key is waiting in KBDR
+pub const KBSR_READY: u16 = 1 << 15;
+
+/// The bit of KBSR that programs set to be interrupted when a key is typed
+pub const KBSR_INTERRUPT_ENABLE: u16 = 1 << 14;
+
+/// The bit of DSR that is set when the display can take another character, which is always
+pub const DSR_READY: u16 = 1 << 15;
+
+/// A type representing the dispatch table for opcodes, indexed by the 4-bit opcode field
+pub type OpDispatchTable = [fn(&mut LC3, tables: &DispatchTables, instr: u16); 16];
+
+/// A type representing the dispatch table for trap routines, indexed by the 8-bit trap vector
+///
+/// Vectors without a routine are `None`.
+pub type TrapDispatchTable = [Option<fn(&mut LC3)>; 256];
 
 /// An operation that can either be an opcode or a trap code
 #[derive(Debug)]
@@ -147,3 +306,73 @@ pub enum Operation {
     /// An opcode
     Op(Op),
 }
+
+/// The memory address that a load, store, or LEA instruction will use
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EffectiveAddress {
+    /// The instruction uses this address directly
+    Direct(u16),
+
+    /// The instruction reads a pointer from the first address and then accesses the second
+    Indirect(u16, u16),
+}
+
+/// A summary of where an image was loaded into memory
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LoadReport {
+    /// The address the image was loaded at
+    pub origin: u16,
+
+    /// The number of words that were loaded, not including the origin
+    pub words_loaded: usize,
+
+    /// The last address that was loaded, which is the origin if the image was empty
+    pub end_addr: u16,
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.words_loaded == 0 {
+            write!(f, "loaded nothing at {:#06x}", self.origin)
+        } else {
+            write!(
+                f,
+                "loaded {:#06x}-{:#06x} ({} words)",
+                self.origin, self.end_addr, self.words_loaded
+            )
+        }
+    }
+}
+
+/// How much of a downward growing stack, with R6 as the stack pointer, is in use
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StackUsage {
+    /// The address R6 holds when the stack is empty
+    pub base: u16,
+
+    /// The number of words currently on the stack
+    pub depth: u16,
+
+    /// The largest number of words that have been on the stack at once
+    pub peak: u16,
+}
+
+impl StackUsage {
+    /// Create a tracker for an empty stack starting at `base`
+    pub fn new(base: u16) -> Self {
+        StackUsage {
+            base,
+            depth: 0,
+            peak: 0,
+        }
+    }
+
+    /// Update the depth from the current value of the stack pointer
+    ///
+    /// A stack pointer above the base is treated as an empty stack, since R6 is often used for
+    /// other things before the program sets up its stack.
+    pub fn update(&mut self, sp: u16) {
+        self.depth = self.base.saturating_sub(sp);
+        self.peak = self.peak.max(self.depth);
+    }
+}
@@ -0,0 +1,239 @@
+/// Errors that can be produced by the LC3 virtual machine
+use super::consts::MemoryMappedRegister;
+use std::{error::Error, fmt, io, time::Duration};
+
+/// An error that occurred while loading an image into the VM's memory
+#[derive(Debug)]
+pub enum LoadError {
+    /// The image could not be read
+    Io(io::Error),
+
+    /// The image is too short to contain the origin word
+    MissingOrigin,
+
+    /// The image does not fit in memory when it is loaded at its origin
+    ImageExceedsMemory {
+        /// The address the image is loaded at
+        origin: u16,
+
+        /// The number of words in the image, not including the origin
+        words: usize,
+    },
+
+    /// A line of an Intel HEX image is malformed or unsupported
+    InvalidHex {
+        /// The line number, starting from 1
+        line: usize,
+    },
+
+    /// A gzip image decompresses to another gzip image
+    NestedGzip,
+
+    /// A saved VM state is malformed or was saved by an incompatible version
+    InvalidSnapshot,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "could not read image: {}", e),
+            LoadError::MissingOrigin => write!(f, "image is too short to contain an origin"),
+            LoadError::ImageExceedsMemory { origin, words } => write!(
+                f,
+                "image of {} words loaded at {:#06x} does not fit in memory",
+                words, origin
+            ),
+            LoadError::InvalidHex { line } => {
+                write!(f, "invalid Intel HEX record on line {}", line)
+            }
+            LoadError::NestedGzip => write!(f, "gzip image contains another gzip image"),
+            LoadError::InvalidSnapshot => write!(f, "not a valid saved VM state"),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::MissingOrigin
+            | LoadError::ImageExceedsMemory { .. }
+            | LoadError::InvalidHex { .. }
+            | LoadError::NestedGzip
+            | LoadError::InvalidSnapshot => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/// A fault that stopped the VM while it was executing a program
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VmError {
+    /// The reserved opcode (RES) was executed
+    IllegalOpcode {
+        /// The address of the instruction
+        pc: u16,
+    },
+
+    /// A TRAP used a vector that has no routine, or an extended trap that isn't enabled
+    BadTrap {
+        /// The address of the TRAP instruction
+        pc: u16,
+
+        /// The trap vector
+        vector: u16,
+    },
+
+    /// RTI was executed in user mode and no handler for the exception has been loaded
+    PrivilegeViolation {
+        /// The address of the instruction
+        pc: u16,
+    },
+
+    /// MEMCPY would have run past the end of memory
+    AddressOutOfRange,
+
+    /// The program stored into its own image while `protect_code` is set
+    CodeSegmentWrite {
+        /// The address that was written
+        addr: u16,
+    },
+
+    /// The program accessed a memory mapped register in a way it doesn't support while
+    /// `strict_mmio` is set
+    ForbiddenMmioAccess {
+        /// The register that was accessed
+        register: MemoryMappedRegister,
+
+        /// Whether the access was a write rather than a read
+        write: bool,
+    },
+
+    /// GETN read a number that doesn't fit in 16 bits while `strict_getn` is set
+    NumberOutOfRange,
+
+    /// `run_loop` executed `max_instructions` instructions without the program halting
+    InstructionLimit {
+        /// The number of instructions that were allowed
+        limit: u64,
+    },
+
+    /// Program output couldn't be written to or flushed from the output stream
+    OutputFailed {
+        /// The kind of IO error the stream returned
+        kind: io::ErrorKind,
+    },
+
+    /// An instruction field selected a register that instructions can't address
+    ///
+    /// Register fields are 3 bits wide, so this can only happen because of a decoding bug.
+    RegisterOutOfRange {
+        /// The register index that was used
+        index: u16,
+    },
+
+    /// A trap routine trapped again until more than `LC3::max_trap_depth` routines were running
+    TrapRecursionLimit {
+        /// The address of the TRAP instruction that went over the limit
+        pc: u16,
+
+        /// The number of routines that were allowed to run at once
+        limit: u32,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { pc } => {
+                write!(f, "illegal instruction (RES) at {:#06x}", pc)
+            }
+            VmError::BadTrap { pc, vector } => {
+                write!(f, "unknown trap vector {:#04x} at {:#06x}", vector, pc)
+            }
+            VmError::PrivilegeViolation { pc } => write!(
+                f,
+                "privilege mode violation at {:#06x} with no exception handler",
+                pc
+            ),
+            VmError::AddressOutOfRange => write!(f, "MEMCPY out of bounds"),
+            VmError::CodeSegmentWrite { addr } => {
+                write!(f, "write to code segment at {:#06x}", addr)
+            }
+            VmError::ForbiddenMmioAccess { register, write } => {
+                let access = if *write { "write to" } else { "read from" };
+                write!(f, "forbidden {} {:?}", access, register)
+            }
+            VmError::NumberOutOfRange => {
+                write!(f, "GETN read a number that doesn't fit in 16 bits")
+            }
+            VmError::InstructionLimit { limit } => {
+                write!(f, "reached the limit of {} instructions", limit)
+            }
+            VmError::OutputFailed { kind } => write!(f, "could not write output: {}", kind),
+            VmError::RegisterOutOfRange { index } => {
+                write!(f, "register index {} is out of range", index)
+            }
+            VmError::TrapRecursionLimit { pc, limit } => write!(
+                f,
+                "trap at {:#06x} nested more than {} trap routines",
+                pc, limit
+            ),
+        }
+    }
+}
+
+impl Error for VmError {}
+
+/// An error that stopped `run_to_halt` from running a program to completion
+#[derive(Debug)]
+pub enum RunError {
+    /// The image couldn't be loaded
+    Load(LoadError),
+
+    /// The program faulted or ran out of steps
+    Fault(VmError),
+
+    /// The program ran for longer than a sandbox allowed
+    Timeout {
+        /// The wall clock time that was allowed
+        limit: Duration,
+    },
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Load(e) => write!(f, "{}", e),
+            RunError::Fault(e) => write!(f, "{}", e),
+            RunError::Timeout { limit } => write!(f, "ran for longer than {:?}", limit),
+        }
+    }
+}
+
+impl Error for RunError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RunError::Load(e) => Some(e),
+            RunError::Fault(e) => Some(e),
+            RunError::Timeout { .. } => None,
+        }
+    }
+}
+
+impl From<LoadError> for RunError {
+    fn from(e: LoadError) -> Self {
+        RunError::Load(e)
+    }
+}
+
+impl From<VmError> for RunError {
+    fn from(e: VmError) -> Self {
+        RunError::Fault(e)
+    }
+}
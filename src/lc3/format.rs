@@ -0,0 +1,269 @@
+/// Detection and decoding of the image formats that can be loaded into the VM
+///
+/// Every format is decoded into the LC3 object format (a big-endian origin word followed by
+/// big-endian words) so that the VM only ever has to load one format.
+use super::{consts::MEMORY_LIMIT, error::LoadError};
+use flate2::read::GzDecoder;
+use std::{io::Read, str::FromStr};
+
+/// The first two bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The compression method byte that follows the gzip magic number, which is always deflate
+const GZIP_DEFLATE: u8 = 8;
+
+/// The length of the smallest gzip stream: a 10 byte header and an 8 byte trailer
+const GZIP_MIN_LEN: usize = 18;
+
+/// The most bytes a gzip image is decompressed to, which is one more than the largest object file
+/// that fits in memory, so that a small file can't expand to fill all of the host's memory
+const GZIP_MAX_DECOMPRESSED: u64 = 2 * MEMORY_LIMIT as u64 + 3;
+
+/// The formats an image file can be in
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ImageFormat {
+    /// An LC3 object file: the origin followed by the words to load there, all big-endian
+    Object,
+
+    /// An Intel HEX file. Byte addresses are halved to get word addresses, and each pair of bytes
+    /// is a big-endian word.
+    IntelHex,
+
+    /// A gzip compressed image in any of the other formats
+    Gzip,
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "obj" | "object" => Ok(ImageFormat::Object),
+            "hex" | "ihex" => Ok(ImageFormat::IntelHex),
+            "gz" | "gzip" => Ok(ImageFormat::Gzip),
+            _ => Err(format!("unknown image format: {}", s)),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// Guess the format of an image from its first bytes
+    ///
+    /// Anything that isn't gzip or Intel HEX is assumed to be an object file, since object files
+    /// have no magic number. That means an object file can look like either of them depending on
+    /// its origin: x3Axx starts with the `:` of a HEX record, and x1F8B starts with the gzip magic
+    /// number. So an image is only HEX if its whole first line is a valid record, and only gzip if
+    /// the rest of the gzip header is plausible too.
+    pub fn detect(buf: &[u8]) -> Self {
+        let first_line = buf.split(|&byte| byte == b'\n').next().unwrap_or_default();
+        if buf.len() >= GZIP_MIN_LEN
+            && buf.starts_with(&GZIP_MAGIC)
+            && buf[2] == GZIP_DEFLATE
+            // The top three flag bits are reserved
+            && buf[3] & 0xE0 == 0
+        {
+            ImageFormat::Gzip
+        } else if std::str::from_utf8(first_line)
+            .ok()
+            .and_then(|line| parse_hex_record(line.trim()))
+            .is_some()
+        {
+            ImageFormat::IntelHex
+        } else {
+            ImageFormat::Object
+        }
+    }
+}
+
+/// Convert an image to the object format
+///
+/// The format is detected from the contents unless one is given. The contents of a gzip image are
+/// always detected. A detected gzip image that doesn't decompress is loaded as an object file
+/// instead, since it could be one that happens to start with a gzip header.
+pub fn decode(buf: &[u8], format: Option<ImageFormat>) -> Result<Vec<u8>, LoadError> {
+    match format {
+        Some(format) => decode_as(buf, format),
+        None => match ImageFormat::detect(buf) {
+            ImageFormat::Gzip => match decode_as(buf, ImageFormat::Gzip) {
+                Err(LoadError::Io(_)) => Ok(buf.to_vec()),
+                decoded => decoded,
+            },
+            format => decode_as(buf, format),
+        },
+    }
+}
+
+/// Convert an image in a known format to the object format
+fn decode_as(buf: &[u8], format: ImageFormat) -> Result<Vec<u8>, LoadError> {
+    match format {
+        ImageFormat::Object => Ok(buf.to_vec()),
+        ImageFormat::IntelHex => decode_hex(buf),
+        ImageFormat::Gzip => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(buf)
+                .take(GZIP_MAX_DECOMPRESSED)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() as u64 == GZIP_MAX_DECOMPRESSED {
+                return Err(LoadError::ImageExceedsMemory {
+                    origin: u16::from_be_bytes([decompressed[0], decompressed[1]]),
+                    words: (decompressed.len() - 1) / 2,
+                });
+            }
+            match ImageFormat::detect(&decompressed) {
+                ImageFormat::Gzip => Err(LoadError::NestedGzip),
+                format => decode_as(&decompressed, format),
+            }
+        }
+    }
+}
+
+/// Convert an Intel HEX file to the object format
+///
+/// The origin is the lowest address with data, and any gaps between records are filled with
+/// zeros. Only the data, end of file, and extended address records are supported.
+fn decode_hex(buf: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let text = String::from_utf8_lossy(buf);
+    // Every byte of data paired with its address
+    let mut bytes = Vec::<(u32, u8)>::new();
+    let mut base = 0u32;
+
+    for (idx, line) in text.lines().enumerate() {
+        let line_number = idx + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = parse_hex_record(line).ok_or(LoadError::InvalidHex { line: line_number })?;
+        let address = u32::from(u16::from(record[1]) << 8 | u16::from(record[2]));
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            // Data
+            0x00 => bytes.extend(
+                data.iter()
+                    .enumerate()
+                    .map(|(i, &byte)| (base + address + i as u32, byte)),
+            ),
+            // End of file
+            0x01 => break,
+            // Extended segment address
+            0x02 if data.len() == 2 => {
+                base = u32::from(u16::from(data[0]) << 8 | u16::from(data[1])) << 4
+            }
+            // Extended linear address
+            0x04 if data.len() == 2 => {
+                base = u32::from(u16::from(data[0]) << 8 | u16::from(data[1])) << 16
+            }
+            _ => return Err(LoadError::InvalidHex { line: line_number }),
+        }
+    }
+
+    let start = match bytes.iter().map(|&(address, _)| address).min() {
+        Some(start) => start & !1,
+        None => return Err(LoadError::MissingOrigin),
+    };
+    let end = bytes
+        .iter()
+        .map(|&(address, _)| address)
+        .max()
+        .unwrap_or(start);
+    // Both the origin and every address in the image have to fit in 16-bit word addresses
+    if end / 2 > u32::from(u16::MAX) {
+        return Err(LoadError::ImageExceedsMemory {
+            origin: (start / 2) as u16,
+            words: ((end - start) / 2 + 1) as usize,
+        });
+    }
+
+    let mut object = vec![0; (end - start) as usize + 1 + 2];
+    object[0] = ((start / 2) >> 8) as u8;
+    object[1] = (start / 2) as u8;
+    for (address, byte) in bytes {
+        object[(address - start) as usize + 2] = byte;
+    }
+    Ok(object)
+}
+
+/// Parse one line of an Intel HEX file into its raw bytes
+///
+/// Returns `None` if the line is malformed, its length doesn't match the byte count, or its
+/// checksum is wrong.
+fn parse_hex_record(line: &str) -> Option<Vec<u8>> {
+    let digits = line.strip_prefix(':')?;
+    if digits.len() % 2 != 0 || digits.len() < 10 {
+        return None;
+    }
+    let record = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let checksum_ok = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0;
+    if !checksum_ok || record.len() != record[0] as usize + 5 {
+        return None;
+    }
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    /// `.ORIG x3A00` followed by `HALT`, whose first byte is a `:`
+    const COLON_OBJECT: [u8; 4] = [0x3A, 0x00, 0xF0, 0x25];
+
+    #[test]
+    fn detects_hex_only_from_a_valid_record() {
+        let hex = b":046000003000F02557\n:00000001FF\n";
+        assert_eq!(ImageFormat::detect(hex), ImageFormat::IntelHex);
+        assert_eq!(
+            decode(hex, None).unwrap(),
+            [0x30, 0x00, 0x30, 0x00, 0xF0, 0x25]
+        );
+
+        assert_eq!(ImageFormat::detect(&COLON_OBJECT), ImageFormat::Object);
+        assert_eq!(decode(&COLON_OBJECT, None).unwrap(), COLON_OBJECT);
+        // A line with a bad checksum isn't a record either
+        assert_eq!(
+            ImageFormat::detect(b":046000003000F02558\n"),
+            ImageFormat::Object
+        );
+    }
+
+    #[test]
+    fn detects_gzip_only_from_a_plausible_header() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&COLON_OBJECT).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert_eq!(ImageFormat::detect(&gzip), ImageFormat::Gzip);
+        assert_eq!(decode(&gzip, None).unwrap(), COLON_OBJECT);
+
+        // `.ORIG x1F8B` followed by `HALT`
+        let object = [0x1F, 0x8B, 0xF0, 0x25];
+        assert_eq!(ImageFormat::detect(&object), ImageFormat::Object);
+        assert_eq!(decode(&object, None).unwrap(), object);
+
+        // Followed by words that look like the rest of a header, but don't decompress
+        let mut object = vec![0x1F, 0x8B, 0x08, 0x00];
+        object.resize(GZIP_MIN_LEN, 0);
+        assert_eq!(ImageFormat::detect(&object), ImageFormat::Gzip);
+        assert_eq!(decode(&object, None).unwrap(), object);
+    }
+
+    #[test]
+    fn gzip_is_only_decompressed_up_to_the_memory_size() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&vec![0; 4 * MEMORY_LIMIT]).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert!(matches!(
+            decode(&gzip, None),
+            Err(LoadError::ImageExceedsMemory { origin: 0, .. })
+        ));
+
+        // The largest image that fits in memory still loads
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&vec![0; 2 * MEMORY_LIMIT + 2]).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert_eq!(decode(&gzip, None).unwrap().len(), 2 * MEMORY_LIMIT + 2);
+    }
+}
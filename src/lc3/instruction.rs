@@ -1,37 +1,29 @@
 /// This module contains helper methods to implement various instructions
 use crate::lc3::consts::ConditionFlag;
-use std::io::{stdin, Read};
 
 /// Generate a type-safe function dispatch table for opcodes
 ///
-/// This method generates a hashmap where the keys are opcode enums and the values are pointers to
-/// functions that can modify the VM. This method will generate an entry for every opcode -
-/// function pair.
+/// This method generates an array indexed by the 4-bit opcode field, where the values are pointers
+/// to functions that can modify the VM. This method will generate an entry for every opcode -
+/// function pair, and will panic if an opcode is listed more than once. Opcodes that aren't listed
+/// are left as `None`.
 macro_rules! op_dispatch_table {
     ( $( ($op:expr, $fn:expr) ),* ) => {
         {
-            let mut table: HashMap<consts::Op, fn(&mut LC3, u16)> = HashMap::new();
-            $( table.insert($op, $fn); )*
-            table
-        }
-    };
-}
-
-/// Generate a type-safe function dispatch table for trap codes
-///
-/// This method generates a hashmap where the keys are opcode enums and the values are pointers to
-/// functions that can modify the VM. This method will generate an entry for every trapcode -
-/// function pair.
-macro_rules! trap_dispatch_table {
-    ( $( ($op:expr, $fn:expr) ),* ) => {
-        {
-            let mut table: HashMap<consts::Trap, fn(&mut LC3)> = HashMap::new();
-            $( table.insert($op, $fn); )*
+            let mut table: [Option<fn(&mut LC3, &DispatchTables, u16)>; 16] = [None; 16];
+            $(
+                assert!(
+                    table[$op as usize].replace($fn).is_none(),
+                    "duplicate dispatch entry for opcode {:?}",
+                    $op
+                );
+            )*
             table
         }
     };
 }
 
+pub mod decode;
 pub mod op;
 pub mod trap;
 
@@ -77,12 +69,3 @@ pub fn bit_mask(num_bits: u16) -> u16 {
 pub fn get_arg(instruction: u16, start_pos: u16, length: u16) -> u16 {
     (instruction >> start_pos) & bit_mask(length)
 }
-
-/// Replicates the behavior of C's `getchar()`
-///
-/// This method will read one byte from STDIN
-pub fn getchar() -> u8 {
-    let mut buf = [0];
-    stdin().read(&mut buf).unwrap_or_default();
-    buf[0]
-}
@@ -0,0 +1,274 @@
+/// Decoding of instruction words into their operands
+///
+/// `decode` splits a word into an `Instruction` whose variants carry the registers, immediates,
+/// offsets, and flags that the opcode uses, so that tools like the disassembler don't have to pick
+/// the bit fields apart themselves. Offsets and immediates are sign extended.
+use super::{get_arg, sign_extend};
+use crate::lc3::consts::{Op, Register};
+use num_traits::FromPrimitive;
+
+/// The second operand of `ADD` and `AND`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Operand {
+    /// A source register
+    Register(Register),
+
+    /// A 5-bit immediate value
+    Immediate(i16),
+}
+
+/// A decoded instruction
+///
+/// The variants are named after the opcodes, except that `JSR` is split into `JSR` and `JSRR`
+/// since they take different operands.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    /// Branch by `offset` if any of the selected condition flags is set
+    BR {
+        n: bool,
+        z: bool,
+        p: bool,
+        offset: i16,
+    },
+
+    /// `dr = sr1 + operand`
+    ADD {
+        dr: Register,
+        sr1: Register,
+        operand: Operand,
+    },
+
+    /// `dr = sr1 & operand`
+    AND {
+        dr: Register,
+        sr1: Register,
+        operand: Operand,
+    },
+
+    /// `dr = !sr`
+    NOT { dr: Register, sr: Register },
+
+    /// Load the word at PC + `offset`
+    LD { dr: Register, offset: i16 },
+
+    /// Load the word at the address stored at PC + `offset`
+    LDI { dr: Register, offset: i16 },
+
+    /// Load the word at `base` + `offset`
+    LDR {
+        dr: Register,
+        base: Register,
+        offset: i16,
+    },
+
+    /// Load the address PC + `offset`
+    LEA { dr: Register, offset: i16 },
+
+    /// Store to PC + `offset`
+    ST { sr: Register, offset: i16 },
+
+    /// Store to the address stored at PC + `offset`
+    STI { sr: Register, offset: i16 },
+
+    /// Store to `base` + `offset`
+    STR {
+        sr: Register,
+        base: Register,
+        offset: i16,
+    },
+
+    /// Jump to the address in `base`, which is `RET` when `base` is R7
+    JMP { base: Register },
+
+    /// Jump to PC + `offset`, saving the return address in R7
+    JSR { offset: i16 },
+
+    /// Jump to the address in `base`, saving the return address in R7
+    JSRR { base: Register },
+
+    /// Return from an interrupt or exception
+    RTI,
+
+    /// Run the trap routine for the 8-bit `vector`
+    TRAP { vector: u16 },
+
+    /// The reserved opcode, which isn't a valid instruction
+    RES,
+}
+
+/// Decode an instruction word
+///
+/// Every word decodes to some instruction, since every 4-bit opcode has a variant. Bits that the
+/// opcode doesn't use are ignored.
+pub fn decode(word: u16) -> Instruction {
+    let op: Op = FromPrimitive::from_u16(word >> 12).expect("every 4-bit value is an opcode");
+    let register = |shift| {
+        let register: Register =
+            FromPrimitive::from_u16(get_arg(word, shift, 3)).expect("3-bit register index");
+        register
+    };
+    let offset = |bits| sign_extend(get_arg(word, 0, bits as u16), bits) as i16;
+    let operand = || {
+        if get_arg(word, 5, 1) != 0 {
+            Operand::Immediate(offset(5))
+        } else {
+            Operand::Register(register(0))
+        }
+    };
+
+    match op {
+        Op::BR => Instruction::BR {
+            n: get_arg(word, 11, 1) != 0,
+            z: get_arg(word, 10, 1) != 0,
+            p: get_arg(word, 9, 1) != 0,
+            offset: offset(9),
+        },
+        Op::ADD => Instruction::ADD {
+            dr: register(9),
+            sr1: register(6),
+            operand: operand(),
+        },
+        Op::AND => Instruction::AND {
+            dr: register(9),
+            sr1: register(6),
+            operand: operand(),
+        },
+        Op::NOT => Instruction::NOT {
+            dr: register(9),
+            sr: register(6),
+        },
+        Op::LD => Instruction::LD {
+            dr: register(9),
+            offset: offset(9),
+        },
+        Op::LDI => Instruction::LDI {
+            dr: register(9),
+            offset: offset(9),
+        },
+        Op::LDR => Instruction::LDR {
+            dr: register(9),
+            base: register(6),
+            offset: offset(6),
+        },
+        Op::LEA => Instruction::LEA {
+            dr: register(9),
+            offset: offset(9),
+        },
+        Op::ST => Instruction::ST {
+            sr: register(9),
+            offset: offset(9),
+        },
+        Op::STI => Instruction::STI {
+            sr: register(9),
+            offset: offset(9),
+        },
+        Op::STR => Instruction::STR {
+            sr: register(9),
+            base: register(6),
+            offset: offset(6),
+        },
+        Op::JMP => Instruction::JMP { base: register(6) },
+        Op::JSR if get_arg(word, 11, 1) != 0 => Instruction::JSR { offset: offset(11) },
+        Op::JSR => Instruction::JSRR { base: register(6) },
+        Op::RTI => Instruction::RTI,
+        Op::TRAP => Instruction::TRAP {
+            vector: get_arg(word, 0, 8),
+        },
+        Op::RES => Instruction::RES,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Register::*;
+
+    #[test]
+    fn decodes_each_opcode() {
+        let cases = [
+            (
+                0x0BFD, // BRnp #-3
+                Instruction::BR {
+                    n: true,
+                    z: false,
+                    p: true,
+                    offset: -3,
+                },
+            ),
+            (
+                0x1265, // ADD R1, R1, #5
+                Instruction::ADD {
+                    dr: R1,
+                    sr1: R1,
+                    operand: Operand::Immediate(5),
+                },
+            ),
+            (
+                0x5042, // AND R0, R1, R2
+                Instruction::AND {
+                    dr: R0,
+                    sr1: R1,
+                    operand: Operand::Register(R2),
+                },
+            ),
+            (
+                0x5270, // AND R1, R1, #-16
+                Instruction::AND {
+                    dr: R1,
+                    sr1: R1,
+                    operand: Operand::Immediate(-16),
+                },
+            ),
+            (0x96BF, Instruction::NOT { dr: R3, sr: R2 }), // NOT R3, R2
+            (0x2FFF, Instruction::LD { dr: R7, offset: -1 }), // LD R7, #-1
+            (
+                0xA8FF, // LDI R4, #255
+                Instruction::LDI {
+                    dr: R4,
+                    offset: 255,
+                },
+            ),
+            (
+                0x6DA0, // LDR R6, R6, #-32
+                Instruction::LDR {
+                    dr: R6,
+                    base: R6,
+                    offset: -32,
+                },
+            ),
+            (
+                0xEB00, // LEA R5, #-256
+                Instruction::LEA {
+                    dr: R5,
+                    offset: -256,
+                },
+            ),
+            (0x3401, Instruction::ST { sr: R2, offset: 1 }), // ST R2, #1
+            (0xBE10, Instruction::STI { sr: R7, offset: 16 }), // STI R7, #16
+            (
+                0x7FBF, // STR R7, R6, #-1
+                Instruction::STR {
+                    sr: R7,
+                    base: R6,
+                    offset: -1,
+                },
+            ),
+            (0xC1C0, Instruction::JMP { base: R7 }), // RET
+            (0x4C00, Instruction::JSR { offset: -1024 }), // JSR #-1024
+            (0x4080, Instruction::JSRR { base: R2 }), // JSRR R2
+            (0x8000, Instruction::RTI),
+            (0xF025, Instruction::TRAP { vector: 0x25 }), // HALT
+            (0xD123, Instruction::RES),
+        ];
+        for &(word, expected) in &cases {
+            assert_eq!(decode(word), expected, "{:#06x}", word);
+        }
+    }
+
+    #[test]
+    fn unused_bits_are_ignored() {
+        // NOT with its low bits cleared, and RET with the unused bits set
+        assert_eq!(decode(0x9280), Instruction::NOT { dr: R1, sr: R2 });
+        assert_eq!(decode(0xCFFF), Instruction::JMP { base: R7 });
+    }
+}
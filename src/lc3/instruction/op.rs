@@ -1,17 +1,18 @@
 /// Functions that implement opcodes for the LC3 vm
 ///
-/// All of these methods have the same structure: they take a mutable reference to the LC3 VM state
-/// and an instruction, which is a 16-bit integer.
+/// All of these methods have the same structure: they take a mutable reference to the LC3 VM state,
+/// the dispatch tables, and an instruction, which is a 16-bit integer. Only `trap` needs the
+/// tables, to look up the trap routine.
 use super::{bit_mask, get_arg, sign_extend};
 use crate::lc3::{
     consts::{self, Register, Trap},
+    error::VmError,
     instruction::trap,
-    LC3,
+    DispatchTables, LC3,
 };
 use num_traits::FromPrimitive;
-use std::collections::HashMap;
 
-pub fn add(vm: &mut LC3, instr: u16) {
+pub fn add(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     // destination register (DR)
     let r0 = (instr >> 9) & bit_mask(3);
 
@@ -21,129 +22,147 @@ pub fn add(vm: &mut LC3, instr: u16) {
     // indicates whether the program is in immediate mode
     let imm_flag = (instr >> 5) & bit_mask(1);
 
-    vm.registers[r0 as usize] = if imm_flag != 0 {
-        let imm5 = sign_extend(instr & 0x5, 5);
-        vm.registers[r1 as usize] + imm5
+    let value = if imm_flag != 0 {
+        let imm5 = sign_extend(instr & bit_mask(5), 5);
+        vm.registers[r1 as usize].wrapping_add(imm5)
     } else {
         let r2 = instr & bit_mask(3);
-        vm.registers[r1 as usize] + vm.registers[r2 as usize]
+        vm.registers[r1 as usize].wrapping_add(vm.registers[r2 as usize])
     };
-    vm.update_cond_flag(r0);
+    vm.write_register(r0, value);
 }
 
-pub fn ldi(vm: &mut LC3, instr: u16) {
+pub fn ldi(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = (instr >> 9) & bit_mask(3);
     let pc_offset = sign_extend(instr & 0x1ff, 9);
-    let r1 = vm.mem_read(vm.registers[Register::PC as usize] + pc_offset);
-    vm.registers[r0 as usize] = vm.mem_read(r1);
-    vm.update_cond_flag(r0);
+    let r1 = vm.mem_read(vm.registers[Register::PC as usize].wrapping_add(pc_offset));
+    let value = vm.mem_read(r1);
+    vm.write_register(r0, value);
 }
 
-pub fn and(vm: &mut LC3, instr: u16) {
+pub fn and(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = (instr >> 9) & bit_mask(3);
     let r1 = (instr >> 6) & bit_mask(3);
     let imm_mode = (instr >> 5) & bit_mask(1) != 0;
 
-    vm.registers[r0 as usize] = if imm_mode {
+    let value = if imm_mode {
         let imm5 = sign_extend(instr & bit_mask(5), 5);
-        vm.registers[r1 as usize] + imm5
+        vm.registers[r1 as usize] & imm5
     } else {
         let r2 = instr & bit_mask(3);
-        vm.registers[r1 as usize] + vm.registers[r2 as usize]
+        vm.registers[r1 as usize] & vm.registers[r2 as usize]
     };
-    vm.update_cond_flag(r0);
+    vm.write_register(r0, value);
 }
 
-/// This operation is unused and will abort the VM
-pub fn rti(vm: &mut LC3, _: u16) {
-    println!("PANIC: illegal instruction (RTI)");
-    vm.running = false;
+/// Return from an interrupt or exception
+///
+/// This pops the PC and then the PSR off of the supervisor stack, which switches back to the user
+/// stack if the PSR is for user mode. RTI is privileged, so running it in user mode raises a
+/// privilege mode violation instead.
+pub fn rti(vm: &mut LC3, _: &DispatchTables, _: u16) {
+    if !vm.is_supervisor_mode() {
+        let pc = vm.registers[Register::PC as usize].wrapping_sub(1);
+        vm.raise_exception(
+            consts::PRIVILEGE_MODE_VIOLATION,
+            VmError::PrivilegeViolation { pc },
+        );
+        return;
+    }
+    let sp = vm.registers[Register::R6 as usize];
+    vm.registers[Register::PC as usize] = vm.mem_read(sp);
+    let psr = vm.mem_read(sp.wrapping_add(1));
+    vm.registers[Register::R6 as usize] = sp.wrapping_add(2);
+    vm.set_psr(psr);
 }
 
 /// This operation is unused and will abort the VM
-pub fn res(vm: &mut LC3, _: u16) {
-    println!("PANIC: illegal instruction (RES)");
-    vm.running = false;
+pub fn res(vm: &mut LC3, _: &DispatchTables, _: u16) {
+    let pc = vm.registers[Register::PC as usize].wrapping_sub(1);
+    vm.fail(VmError::IllegalOpcode { pc });
 }
 
-pub fn not(vm: &mut LC3, instr: u16) {
+pub fn not(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = get_arg(instr, 9, 3);
     let r1 = get_arg(instr, 6, 3);
-    vm.registers[r0 as usize] = !vm.registers[r1 as usize];
-    vm.update_cond_flag(r0);
+    let value = !vm.registers[r1 as usize];
+    vm.write_register(r0, value);
 }
 
-pub fn br(vm: &mut LC3, instr: u16) {
+pub fn br(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let pc_offset = sign_extend(get_arg(instr, 0, 9), 9);
     let cond_flag = get_arg(instr, 9, 3);
     if cond_flag & vm.registers[Register::COND as usize] != 0 {
-        vm.registers[Register::PC as usize] += pc_offset;
+        vm.registers[Register::PC as usize] =
+            vm.registers[Register::PC as usize].wrapping_add(pc_offset);
     }
 }
 
-pub fn jmp(vm: &mut LC3, instr: u16) {
+pub fn jmp(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let base_register = get_arg(instr, 6, 3);
     vm.registers[Register::PC as usize] = vm.registers[base_register as usize];
 }
 
-pub fn jsr(vm: &mut LC3, instr: u16) {
+pub fn jsr(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r1 = get_arg(instr, 6, 3);
     let long_flag = get_arg(instr, 11, 1);
     let long_pc_offset = sign_extend(get_arg(instr, 0, 11), 11);
+    let pc = vm.registers[Register::PC as usize];
 
-    vm.registers[Register::PC as usize] = if long_flag != 0 {
-        long_pc_offset
+    // Read the target before saving the return address, since JSRR R7 jumps to the old R7
+    let target = if long_flag != 0 {
+        pc.wrapping_add(long_pc_offset)
     } else {
         vm.registers[r1 as usize]
     };
+    vm.registers[Register::R7 as usize] = pc;
+    vm.registers[Register::PC as usize] = target;
 }
 
-pub fn ld(vm: &mut LC3, instr: u16) {
+pub fn ld(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = get_arg(instr, 9, 3);
     let pc_offset = sign_extend(get_arg(instr, 0, 9), 9);
-    vm.registers[r0 as usize] = vm.mem_read(vm.registers[Register::PC as usize] + pc_offset);
-    vm.update_cond_flag(r0);
+    let value = vm.mem_read(vm.registers[Register::PC as usize].wrapping_add(pc_offset));
+    vm.write_register(r0, value);
 }
 
-pub fn ldr(vm: &mut LC3, instr: u16) {
+pub fn ldr(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = get_arg(instr, 9, 3);
     let base_register = get_arg(instr, 6, 3);
     let offset = sign_extend(get_arg(instr, 0, 6), 6);
-    vm.registers[r0 as usize] = vm.mem_read(vm.registers[base_register as usize] + offset);
-    vm.update_cond_flag(r0);
+    let value = vm.mem_read(vm.registers[base_register as usize].wrapping_add(offset));
+    vm.write_register(r0, value);
 }
 
-pub fn lea(vm: &mut LC3, instr: u16) {
+pub fn lea(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = get_arg(instr, 9, 3);
-    let pc_offset = get_arg(instr, 0, 9);
-    vm.mem_write(
-        vm.registers[Register::PC as usize] + pc_offset,
-        vm.registers[r0 as usize],
-    );
+    let pc_offset = sign_extend(get_arg(instr, 0, 9), 9);
+    let value = vm.registers[Register::PC as usize].wrapping_add(pc_offset);
+    vm.write_register(r0, value);
 }
 
-pub fn st(vm: &mut LC3, instr: u16) {
+pub fn st(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = get_arg(instr, 9, 3);
     let pc_offset = sign_extend(get_arg(instr, 0, 9), 9);
     vm.mem_write(
-        vm.registers[Register::PC as usize] + pc_offset,
+        vm.registers[Register::PC as usize].wrapping_add(pc_offset),
         vm.registers[r0 as usize],
     );
 }
 
-pub fn sti(vm: &mut LC3, instr: u16) {
+pub fn sti(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = get_arg(instr, 9, 3);
     let pc_offset = sign_extend(get_arg(instr, 0, 9), 9);
-    let dst = vm.mem_read(vm.registers[Register::PC as usize] + pc_offset);
+    let dst = vm.mem_read(vm.registers[Register::PC as usize].wrapping_add(pc_offset));
     vm.mem_write(dst, vm.registers[r0 as usize]);
 }
 
-pub fn str(vm: &mut LC3, instr: u16) {
+pub fn str(vm: &mut LC3, _: &DispatchTables, instr: u16) {
     let r0 = get_arg(instr, 9, 3);
     let r1 = get_arg(instr, 6, 3);
     let offset = sign_extend(get_arg(instr, 0, 6), 6);
     vm.mem_write(
-        vm.registers[r1 as usize] + offset,
+        vm.registers[r1 as usize].wrapping_add(offset),
         vm.registers[r0 as usize],
     );
 }
@@ -151,27 +170,461 @@ pub fn str(vm: &mut LC3, instr: u16) {
 /// This routine is dispatched when a trap code is encountered.
 ///
 /// This method will extract the trap code from the instruction and call the appropriate
-/// corresponding function.
-pub fn trap(vm: &mut LC3, instr: u16) {
+/// corresponding function from the trap dispatch table. The built-in extended traps are only used
+/// for vectors without a routine in the table, and only if the VM has opted into them.
+///
+/// Like the hardware, this saves the return address in R7 before the routine runs. A routine can
+/// trap again, but only `LC3::max_trap_depth` routines may run at once so that a routine that
+/// keeps retriggering itself halts the VM instead of overflowing the stack.
+pub fn trap(vm: &mut LC3, tables: &DispatchTables, instr: u16) {
+    if vm.trap_depth >= vm.max_trap_depth {
+        let pc = vm.registers[Register::PC as usize].wrapping_sub(1);
+        vm.fail(VmError::TrapRecursionLimit {
+            pc,
+            limit: vm.max_trap_depth,
+        });
+        return;
+    }
+    vm.trap_depth += 1;
     vm.trap = true;
-    let trap_dispatch_table = trap_dispatch_table![
-        (Trap::GETC, trap::getc),
-        (Trap::OUT, trap::out),
-        (Trap::PUTS, trap::puts),
-        (Trap::PUTSP, trap::putsp),
-        (Trap::IN, trap::r#in),
-        (Trap::HALT, trap::halt)
-    ];
+    vm.registers[Register::R7 as usize] = vm.registers[Register::PC as usize];
     let raw_trap_code = (instr as u16) & 0xFF;
 
-    // An invalid trap call will lead to the VM halting
-    let trap_code = FromPrimitive::from_u16(raw_trap_code).unwrap_or(Trap::HALT);
+    let registered = tables.traps[raw_trap_code as usize];
 
-    if let Some(trap_fn) = trap_dispatch_table.get(&trap_code) {
-        trap_fn(vm);
-        vm.trap = false;
-    } else {
-        println!("PANIC: Unknown or malformed trap code encountered");
-        vm.running = false;
+    // An invalid trap call stops the VM, and extended traps are only valid if the VM has opted
+    // into them
+    let extended = FromPrimitive::from_u16(raw_trap_code)
+        .filter(|trap: &Trap| vm.extended_traps && trap.is_extended())
+        .map(trap_routine);
+    match registered.or(extended) {
+        Some(trap_fn) => trap_fn(vm),
+        None => {
+            let pc = vm.registers[Register::PC as usize].wrapping_sub(1);
+            vm.fail(VmError::BadTrap {
+                pc,
+                vector: raw_trap_code,
+            });
+        }
+    }
+    vm.trap_depth -= 1;
+    vm.trap = vm.trap_depth > 0;
+}
+
+/// The built-in routine for a trap code
+///
+/// This is a `match` rather than a table so that nothing has to be built for each trap, and so
+/// that the compiler checks that every trap code has a routine.
+pub(crate) fn trap_routine(trap_code: Trap) -> fn(&mut LC3) {
+    match trap_code {
+        Trap::GETC => trap::getc,
+        Trap::OUT => trap::out,
+        Trap::PUTS => trap::puts,
+        Trap::PUTSP => trap::putsp,
+        Trap::IN => trap::r#in,
+        Trap::HALT => trap::halt,
+        Trap::PUTN => trap::putn,
+        Trap::MEMCPY => trap::memcpy,
+        Trap::RAND => trap::rand,
+        Trap::GETN => trap::getn,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::consts::ConditionFlag;
+    use std::io;
+
+    /// Create a VM without a terminal that is ready to step
+    fn vm() -> LC3 {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.captured_output = Some(Vec::new());
+        vm.start();
+        vm
+    }
+
+    /// Execute `instr` at the PC
+    fn execute(vm: &mut LC3, instr: u16) -> Result<(), VmError> {
+        execute_with(vm, &DispatchTables::new(), instr)
+    }
+
+    /// Execute `instr` at the PC with custom dispatch tables
+    fn execute_with(vm: &mut LC3, tables: &DispatchTables, instr: u16) -> Result<(), VmError> {
+        let pc = vm.registers[Register::PC as usize];
+        vm.memory[pc as usize] = instr;
+        vm.step(tables)
+    }
+
+    /// Read a register
+    fn reg(vm: &LC3, register: Register) -> u16 {
+        vm.registers[register as usize]
+    }
+
+    // Every PC relative offset below is -5 from an instruction at x3000, and is relative to the
+    // incremented PC, so the target is x3001 - 5 = x2FFC
+
+    #[test]
+    fn ld_negative_offset() {
+        let mut vm = vm();
+        vm.memory[0x2FFC] = 0x1234;
+        execute(&mut vm, 0x21FB).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0x1234);
+    }
+
+    #[test]
+    fn ldi_negative_offset() {
+        let mut vm = vm();
+        vm.memory[0x2FFC] = 0x4000;
+        vm.memory[0x4000] = 0x77;
+        execute(&mut vm, 0xA1FB).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0x77);
+    }
+
+    #[test]
+    fn st_negative_offset() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = 0x55;
+        execute(&mut vm, 0x31FB).unwrap();
+        assert_eq!(vm.memory[0x2FFC], 0x55);
+    }
+
+    #[test]
+    fn sti_negative_offset() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = 0x55;
+        vm.memory[0x2FFC] = 0x4000;
+        execute(&mut vm, 0xB1FB).unwrap();
+        assert_eq!(vm.memory[0x4000], 0x55);
+    }
+
+    #[test]
+    fn lea_negative_offset() {
+        let mut vm = vm();
+        execute(&mut vm, 0xE1FB).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0x2FFC);
+    }
+
+    #[test]
+    fn br_negative_offset() {
+        let mut vm = vm();
+        execute(&mut vm, 0x0FFB).unwrap();
+        assert_eq!(reg(&vm, Register::PC), 0x2FFC);
+    }
+
+    #[test]
+    fn negative_offset_wraps_below_zero() {
+        let mut vm = vm();
+        vm.registers[Register::PC as usize] = 0x0000;
+        execute(&mut vm, 0xE1FB).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0xFFFC);
+    }
+
+    // LDR and STR below use R1 as the base, with offsets #5 (x045), #-3 (x07D), and #2 (x042)
+
+    #[test]
+    fn ldr_offsets_are_signed_and_wrap() {
+        let mut vm = vm();
+        vm.registers[Register::R1 as usize] = 0x4000;
+        vm.memory[0x4005] = 0x1111;
+        vm.memory[0x3FFD] = 0x2222;
+        execute(&mut vm, 0x6045).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0x1111);
+        execute(&mut vm, 0x607D).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0x2222);
+
+        vm.registers[Register::R1 as usize] = 0xFFFE;
+        vm.memory[0x0000] = 0x3333;
+        execute(&mut vm, 0x6042).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0x3333);
+    }
+
+    #[test]
+    fn str_offsets_are_signed_and_wrap() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = 0xABCD;
+        vm.registers[Register::R1 as usize] = 0x4000;
+        execute(&mut vm, 0x7045).unwrap();
+        assert_eq!(vm.memory[0x4005], 0xABCD);
+        execute(&mut vm, 0x707D).unwrap();
+        assert_eq!(vm.memory[0x3FFD], 0xABCD);
+
+        vm.registers[Register::R1 as usize] = 0xFFFF;
+        execute(&mut vm, 0x7042).unwrap();
+        assert_eq!(vm.memory[0x0001], 0xABCD);
+    }
+
+    #[test]
+    fn trap_dispatches_to_custom_handler() {
+        fn handler(vm: &mut LC3) {
+            vm.registers[Register::R0 as usize] = 0x4040;
+        }
+        let mut tables = DispatchTables::new();
+        tables.register_trap(0x40, handler);
+        let mut vm = vm();
+        execute_with(&mut vm, &tables, 0xF040).unwrap();
+        assert_eq!(vm.registers[Register::R0 as usize], 0x4040);
+        assert_eq!(vm.registers[Register::R7 as usize], 0x3001);
+    }
+
+    #[test]
+    fn recursive_trap_hits_depth_limit() {
+        // A routine that traps to itself until the VM stops it
+        fn tables() -> DispatchTables {
+            let mut tables = DispatchTables::new();
+            tables.register_trap(0x40, retrigger);
+            tables
+        }
+        fn retrigger(vm: &mut LC3) {
+            if vm.is_running() {
+                trap(vm, &tables(), 0xF040);
+            }
+        }
+        let mut vm = vm();
+        vm.max_trap_depth = 8;
+        assert_eq!(
+            execute_with(&mut vm, &tables(), 0xF040),
+            Err(VmError::TrapRecursionLimit {
+                pc: 0x3000,
+                limit: 8
+            })
+        );
+        assert_eq!(vm.trap_depth, 0);
+    }
+
+    #[test]
+    fn and_is_bitwise() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = 0xF0F0;
+        vm.registers[Register::R1 as usize] = 0x3C3C;
+        // AND R2, R0, R1
+        execute(&mut vm, 0x5401).unwrap();
+        assert_eq!(reg(&vm, Register::R2), 0x3030);
+        assert_eq!(vm.condition_flags(), ConditionFlag::POS);
+
+        // AND R2, R0, #-1
+        execute(&mut vm, 0x543F).unwrap();
+        assert_eq!(reg(&vm, Register::R2), 0xF0F0);
+        assert_eq!(vm.condition_flags(), ConditionFlag::NEG);
+
+        // AND R2, R0, #0
+        execute(&mut vm, 0x5420).unwrap();
+        assert_eq!(reg(&vm, Register::R2), 0);
+        assert_eq!(vm.condition_flags(), ConditionFlag::ZRO);
+    }
+
+    #[test]
+    fn add_immediates_are_sign_extended() {
+        let mut vm = vm();
+        // ADD R1, R0, #15
+        execute(&mut vm, 0x122F).unwrap();
+        assert_eq!(reg(&vm, Register::R1), 15);
+        assert_eq!(vm.condition_flags(), ConditionFlag::POS);
+
+        // ADD R1, R0, #-16
+        execute(&mut vm, 0x1230).unwrap();
+        assert_eq!(reg(&vm, Register::R1), 0xFFF0);
+        assert_eq!(vm.condition_flags(), ConditionFlag::NEG);
+
+        // ADD R1, R0, #-1, with R0 = 1
+        vm.registers[Register::R0 as usize] = 1;
+        execute(&mut vm, 0x123F).unwrap();
+        assert_eq!(reg(&vm, Register::R1), 0);
+        assert_eq!(vm.condition_flags(), ConditionFlag::ZRO);
+
+        // ADD R1, R0, #6
+        execute(&mut vm, 0x1226).unwrap();
+        assert_eq!(reg(&vm, Register::R1), 7);
+    }
+
+    #[test]
+    fn add_and_pc_wrap_at_16_bits() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = 0xFFFF;
+        // ADD R0, R0, #1
+        execute(&mut vm, 0x1021).unwrap();
+        assert_eq!(reg(&vm, Register::R0), 0);
+        assert_eq!(vm.condition_flags(), ConditionFlag::ZRO);
+
+        vm.registers[Register::PC as usize] = 0xFFFF;
+        execute(&mut vm, 0x0000).unwrap();
+        assert_eq!(reg(&vm, Register::PC), 0);
+    }
+
+    #[test]
+    fn lea_loads_the_address() {
+        let mut vm = vm();
+        vm.memory[0x300B] = 0x1234;
+        // LEA R3, #10
+        execute(&mut vm, 0xE60A).unwrap();
+        assert_eq!(reg(&vm, Register::R3), 0x300B);
+        assert_eq!(vm.condition_flags(), ConditionFlag::POS);
+        // The address is loaded, not stored to
+        assert_eq!(vm.memory[0x300B], 0x1234);
+    }
+
+    #[test]
+    fn br_takes_branch_when_a_flag_matches() {
+        for &flag in &[ConditionFlag::NEG, ConditionFlag::ZRO, ConditionFlag::POS] {
+            for nzp in 0..8 {
+                let mut vm = vm();
+                vm.set_condition(flag);
+                // BR with these nzp bits and an offset of #5
+                execute(&mut vm, nzp << 9 | 5).unwrap();
+                let expected = if nzp & flag as u16 != 0 {
+                    0x3006
+                } else {
+                    0x3001
+                };
+                assert_eq!(
+                    reg(&vm, Register::PC),
+                    expected,
+                    "{:?} nzp={:03b}",
+                    flag,
+                    nzp
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn jsr_saves_return_address_for_ret() {
+        let mut vm = vm();
+        // JSR #4
+        execute(&mut vm, 0x4804).unwrap();
+        assert_eq!(reg(&vm, Register::PC), 0x3005);
+        assert_eq!(reg(&vm, Register::R7), 0x3001);
+        // RET (JMP R7)
+        execute(&mut vm, 0xC1C0).unwrap();
+        assert_eq!(reg(&vm, Register::PC), 0x3001);
+
+        // JSRR R2
+        vm.registers[Register::R2 as usize] = 0x4000;
+        execute(&mut vm, 0x4080).unwrap();
+        assert_eq!(reg(&vm, Register::PC), 0x4000);
+        assert_eq!(reg(&vm, Register::R7), 0x3002);
+        execute(&mut vm, 0xC1C0).unwrap();
+        assert_eq!(reg(&vm, Register::PC), 0x3002);
+    }
+
+    #[test]
+    fn flags_feed_matching_branches() {
+        // BRn, BRz, and BRp with an offset of #5
+        let cases = [
+            (0x8000, ConditionFlag::NEG, 0x0805),
+            (0, ConditionFlag::ZRO, 0x0405),
+            (0x7FFF, ConditionFlag::POS, 0x0205),
+        ];
+        for &(value, flag, branch) in &cases {
+            for &(_, _, other) in &cases {
+                let mut vm = vm();
+                vm.registers[Register::R1 as usize] = value;
+                // ADD R0, R1, #0
+                execute(&mut vm, 0x1060).unwrap();
+                assert_eq!(vm.condition_flags(), flag);
+                execute(&mut vm, other).unwrap();
+                let expected = if other == branch { 0x3007 } else { 0x3002 };
+                assert_eq!(
+                    reg(&vm, Register::PC),
+                    expected,
+                    "{:?} {:#06x}",
+                    flag,
+                    other
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trap_saves_return_address_in_r7() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = u16::from(b'A');
+        // TRAP x21 (OUT)
+        execute(&mut vm, 0xF021).unwrap();
+        assert_eq!(reg(&vm, Register::R7), 0x3001);
+        assert_eq!(reg(&vm, Register::PC), 0x3001);
+        assert_eq!(vm.take_captured_output(), b"A");
+    }
+
+    #[test]
+    fn rti_returns_from_pushed_frame() {
+        let mut vm = vm();
+        assert!(!vm.is_supervisor_mode());
+        vm.registers[Register::R6 as usize] = 0xFE00;
+        vm.registers[Register::COND as usize] = ConditionFlag::NEG as u16;
+        vm.push_context();
+        assert!(vm.is_supervisor_mode());
+        assert_eq!(reg(&vm, Register::R6), consts::SUPERVISOR_STACK_START - 2);
+
+        // Clobber the flags in the handler, then return with RTI
+        vm.registers[Register::PC as usize] = 0x0200;
+        vm.registers[Register::COND as usize] = ConditionFlag::POS as u16;
+        execute(&mut vm, 0x8000).unwrap();
+        assert!(!vm.is_supervisor_mode());
+        assert_eq!(reg(&vm, Register::PC), 0x3000);
+        assert_eq!(reg(&vm, Register::R6), 0xFE00);
+        assert_eq!(reg(&vm, Register::COND), ConditionFlag::NEG as u16);
+    }
+
+    #[test]
+    fn rti_in_user_mode_is_a_privilege_violation() {
+        let mut vm = vm();
+        assert_eq!(
+            execute(&mut vm, 0x8000),
+            Err(VmError::PrivilegeViolation { pc: 0x3000 })
+        );
+    }
+
+    #[test]
+    fn trap_flag_is_only_set_while_a_routine_runs() {
+        fn record_trap_flag(vm: &mut LC3) {
+            vm.registers[Register::R3 as usize] = vm.trap as u16;
+        }
+        let mut tables = DispatchTables::new();
+        tables.register_trap(0x40, record_trap_flag);
+        let mut vm = vm();
+        execute_with(&mut vm, &tables, 0xF040).unwrap();
+        assert_eq!(reg(&vm, Register::R3), 1);
+        assert!(!vm.trap);
+
+        // The built-in routines behave the same however often they run
+        for &c in b"ok" {
+            vm.registers[Register::R0 as usize] = u16::from(c);
+            execute(&mut vm, 0xF021).unwrap(); // OUT
+            assert!(!vm.trap);
+        }
+        execute(&mut vm, 0xF025).unwrap(); // HALT
+        assert!(!vm.trap);
+        assert!(!vm.is_running());
+        assert_eq!(vm.take_captured_output(), b"okHALT");
+    }
+
+    #[test]
+    fn registered_traps_add_and_override_vectors() {
+        fn sentinel(vm: &mut LC3) {
+            vm.registers[Register::R2 as usize] = 0xBEEF;
+        }
+        fn quiet_halt(vm: &mut LC3) {
+            vm.running = false;
+        }
+        // Tables start with only the standard routines
+        let mut plain = vm();
+        assert_eq!(
+            execute(&mut plain, 0xF027),
+            Err(VmError::BadTrap {
+                pc: 0x3000,
+                vector: 0x27
+            })
+        );
+
+        let mut tables = DispatchTables::new();
+        tables.register_trap(0x27, sentinel);
+        tables.register_trap(Trap::HALT as u8, quiet_halt);
+        let mut vm = vm();
+        execute_with(&mut vm, &tables, 0xF027).unwrap();
+        assert_eq!(reg(&vm, Register::R2), 0xBEEF);
+        execute_with(&mut vm, &tables, 0xF025).unwrap();
+        assert!(!vm.is_running());
+        assert!(vm.take_captured_output().is_empty());
     }
 }
@@ -1,81 +1,253 @@
-use crate::lc3::{consts::Register, LC3};
-use std::io::{self, Read};
+use crate::lc3::{
+    consts::{self, Register},
+    error::VmError,
+    LC3,
+};
 
 /// Implementations of the trap routines in the LC3 architecture.
 ///
-/// Every method has the same type: `fn(&mut LC3)`, which makes it easy to create function dispatch
-/// tables for trap codes.
+/// Every method has the same type: `fn(&mut LC3)`, which makes it easy to dispatch trap codes to
+/// them.
 
 pub fn puts(vm: &mut LC3) {
     // build up a string by looking for 16 bit integers until we hit the null terminator. The
     // starting location of the string is whatever is at the r0 register
     let start_pos = vm.registers[Register::R0 as usize] as usize;
-    let end_pos = vm
-        .memory
+    // An unterminated string runs to the end of memory
+    let end_pos = vm.memory[start_pos..]
         .iter()
-        .skip(start_pos)
         .position(|x| *x == 0)
-        .unwrap();
-    print!(
-        "{}",
-        String::from_utf16_lossy(&vm.memory[start_pos..end_pos])
-    );
+        .map_or(vm.memory.len(), |len| start_pos + len);
+    let s = String::from_utf16_lossy(&vm.memory[start_pos..end_pos]);
+    vm.write_output(&s);
 }
 
 pub fn getc(vm: &mut LC3) {
     // Get the next character from stdin and convert it to a 16 bit integer so we can store it
-    // in the R0 register
-    let c = io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .map(u16::from)
-        .unwrap_or(0);
-    vm.registers[Register::R0 as usize] = c;
+    // in the R0 register. This reads a single byte, so in raw mode it returns as soon as a key is
+    // pressed.
+    vm.registers[Register::R0 as usize] = u16::from(vm.getchar());
 }
 
 pub fn out(vm: &mut LC3) {
     let r0 = vm.registers[Register::R0 as usize];
     let character = String::from_utf16_lossy(&[r0]);
-    print!("{}", character);
+    vm.write_output(&character);
 }
 
 pub fn r#in(vm: &mut LC3) {
-    print!("Enter a character: ");
-    let raw_c = io::stdin()
-        .bytes()
-        .next()
-        // We use the `and_then` call because there is no native cast from a u8 to a u16, so we
-        // have to promote the type from a `Result` type and do a primitive cast from u8 ->
-        // u16.
-        .and_then(|result| result.ok())
-        .map(u16::from)
-        .unwrap_or(0);
+    vm.write_output("Enter a character: ");
+    let raw_c = u16::from(vm.getchar());
     let character = String::from_utf16_lossy(&[raw_c]);
-    println!("{}", character);
+    vm.write_output(&format!("{}\n", character));
     vm.registers[Register::R0 as usize] = raw_c;
 }
 
 pub fn putsp(vm: &mut LC3) {
+    // Each word holds two characters, the first in the low byte and the second in the high byte.
+    // A string with an odd length has a zero high byte in its last word.
     let start_pos = vm.registers[Register::R0 as usize] as usize;
-    let end_pos = vm
-        .memory
+    let end_pos = vm.memory[start_pos..]
         .iter()
-        .skip(start_pos)
         .position(|x| *x == 0)
-        .unwrap();
+        .map_or(vm.memory.len(), |len| start_pos + len);
 
-    for &c in &vm.registers[start_pos..end_pos] {
-        let char1 = vec![(c & 0xFF) as u8];
-        let s = String::from_utf8_lossy(char1.as_slice());
-        print!("{}", s);
-        let char2 = vec![(c >> 8) as u8];
-        let s = String::from_utf8_lossy(char2.as_slice());
-        print!("{}", s);
+    let mut s = String::new();
+    for &c in &vm.memory[start_pos..end_pos] {
+        s.push(char::from((c & 0xFF) as u8));
+        let char2 = (c >> 8) as u8;
+        if char2 != 0 {
+            s.push(char::from(char2));
+        }
     }
+    vm.write_output(&s);
 }
 
 pub fn halt(vm: &mut LC3) {
-    print!("HALT");
+    vm.write_output("HALT");
     vm.running = false;
 }
+
+/// Output the value in R0 as a signed decimal number (extended trap)
+///
+/// This is the counterpart of `GETN`, so the value is treated as two's complement.
+pub fn putn(vm: &mut LC3) {
+    let value = vm.registers[Register::R0 as usize] as i16;
+    vm.write_output(&value.to_string());
+}
+
+/// Copy a block of memory (extended trap)
+///
+/// R0 holds the source address, R1 the destination address, and R2 the number of words to copy.
+/// The regions may overlap. Every word is read and written like a load and a store would, so
+/// memory mapped registers behave the same. A copy that would run past the end of memory halts
+/// the VM without copying anything.
+pub fn memcpy(vm: &mut LC3) {
+    let src = vm.registers[Register::R0 as usize] as usize;
+    let dst = vm.registers[Register::R1 as usize];
+    let len = vm.registers[Register::R2 as usize] as usize;
+
+    if src + len > consts::MEMORY_LIMIT || dst as usize + len > consts::MEMORY_LIMIT {
+        vm.fail(VmError::AddressOutOfRange);
+        return;
+    }
+
+    // Read the whole source block first so overlapping regions behave like `memmove`
+    let words: Vec<u16> = (src..src + len)
+        .map(|addr| vm.mem_read(addr as u16))
+        .collect();
+    for (addr, word) in (dst..=u16::MAX).zip(words) {
+        vm.mem_write(addr, word);
+    }
+}
+
+/// Put a pseudo-random 16-bit value in R0 (extended trap)
+pub fn rand(vm: &mut LC3) {
+    vm.registers[Register::R0 as usize] = vm.next_random();
+}
+
+/// Read a decimal number, optionally signed, from the input into R0 (extended trap)
+///
+/// Leading whitespace is skipped and the number ends at the first character that isn't a digit,
+/// which is consumed. Numbers that don't fit in 16 bits wrap, unless `strict_getn` is set, in
+/// which case the VM halts.
+pub fn getn(vm: &mut LC3) {
+    let mut c = vm.getchar();
+    while c.is_ascii_whitespace() {
+        c = vm.getchar();
+    }
+    let negative = c == b'-';
+    if c == b'-' || c == b'+' {
+        c = vm.getchar();
+    }
+
+    let mut value: u16 = 0;
+    // The unwrapped magnitude, which is only used to detect overflow
+    let mut magnitude: u32 = 0;
+    while c.is_ascii_digit() {
+        let digit = c - b'0';
+        value = value.wrapping_mul(10).wrapping_add(u16::from(digit));
+        magnitude = magnitude
+            .saturating_mul(10)
+            .saturating_add(u32::from(digit));
+        c = vm.getchar();
+    }
+
+    let limit = if negative { 0x8000 } else { 0xFFFF };
+    if magnitude > limit && vm.strict_getn {
+        vm.fail(VmError::NumberOutOfRange);
+        return;
+    }
+    vm.registers[Register::R0 as usize] = if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// Create a VM without a terminal that is ready to run trap routines
+    fn vm() -> LC3 {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        vm.captured_output = Some(Vec::new());
+        vm.extended_traps = true;
+        vm.start();
+        vm
+    }
+
+    #[test]
+    fn memcpy_copies_region() {
+        let mut vm = vm();
+        vm.memory[0x4000..0x4003].copy_from_slice(&[1, 2, 3]);
+        vm.registers[Register::R0 as usize] = 0x4000;
+        vm.registers[Register::R1 as usize] = 0x5000;
+        vm.registers[Register::R2 as usize] = 3;
+        memcpy(&mut vm);
+        assert_eq!(vm.memory[0x5000..0x5004], [1, 2, 3, 0]);
+        assert!(vm.is_running());
+    }
+
+    #[test]
+    fn memcpy_past_end_of_memory_fails() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = 0x4000;
+        vm.registers[Register::R1 as usize] = 0xFFFE;
+        vm.registers[Register::R2 as usize] = 3;
+        memcpy(&mut vm);
+        assert_eq!(vm.error, Some(VmError::AddressOutOfRange));
+        assert_eq!(vm.memory[0xFFFE], 0);
+    }
+
+    #[test]
+    fn same_seed_gives_same_random_numbers() {
+        let sequence = |seed| {
+            let mut vm = vm();
+            vm.seed_rng(seed);
+            (0..8)
+                .map(|_| {
+                    rand(&mut vm);
+                    vm.registers[Register::R0 as usize]
+                })
+                .collect::<Vec<u16>>()
+        };
+        assert_eq!(sequence(42), sequence(42));
+        assert_ne!(sequence(42), sequence(43));
+    }
+
+    #[test]
+    fn getn_reads_negative_number() {
+        let mut vm = vm();
+        vm.set_input(io::Cursor::new(b"-5\n".to_vec()));
+        getn(&mut vm);
+        assert_eq!(vm.registers[Register::R0 as usize], 0xFFFB);
+        assert!(vm.is_running());
+    }
+
+    #[test]
+    fn getn_rejects_overflow_when_strict() {
+        let mut vm = vm();
+        vm.strict_getn = true;
+        vm.set_input(io::Cursor::new(b"70000\n".to_vec()));
+        getn(&mut vm);
+        assert_eq!(vm.error, Some(VmError::NumberOutOfRange));
+    }
+
+    #[test]
+    fn puts_prints_whole_string() {
+        let mut vm = vm();
+        vm.memory[0x4000] = u16::from(b'H');
+        vm.memory[0x4001] = u16::from(b'i');
+        vm.memory[0x4002] = 0;
+        vm.memory[0x4003] = u16::from(b'!');
+        vm.registers[Register::R0 as usize] = 0x4000;
+        puts(&mut vm);
+        assert_eq!(vm.take_captured_output(), b"Hi");
+    }
+
+    #[test]
+    fn putsp_prints_two_characters_per_word() {
+        let mut vm = vm();
+        vm.memory[0x4000] = u16::from_le_bytes([b'H', b'e']);
+        vm.memory[0x4001] = u16::from_le_bytes([b'l', b'l']);
+        // An odd length string leaves the high byte of its last word empty
+        vm.memory[0x4002] = u16::from(b'o');
+        vm.memory[0x4003] = 0;
+        vm.registers[Register::R0 as usize] = 0x4000;
+        putsp(&mut vm);
+        assert_eq!(vm.take_captured_output(), b"Hello");
+    }
+
+    #[test]
+    fn memcpy_reads_memory_mapped_registers() {
+        let mut vm = vm();
+        vm.registers[Register::R0 as usize] = consts::MemoryMappedRegister::DSR as u16;
+        vm.registers[Register::R1 as usize] = 0x5000;
+        vm.registers[Register::R2 as usize] = 1;
+        memcpy(&mut vm);
+        assert_eq!(vm.memory[0x5000], consts::DSR_READY);
+    }
+}
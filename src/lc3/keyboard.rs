@@ -0,0 +1,87 @@
+/// Keyboard input for the VM that can be polled without blocking
+///
+/// Programs either wait for a key with the GETC/IN traps or poll the KBSR memory mapped register,
+/// and polling can't block. Since an arbitrary input stream can't be read without blocking, the
+/// stream is read on a background thread that forwards every byte over a channel. The thread is
+/// only started the first time the program asks for input so that nothing is read from the stream
+/// until then.
+use std::{
+    io::Read,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// A source of keys that supports both waiting for and polling for input
+pub struct Keyboard {
+    /// The stream keys are read from, until the reader thread takes it over
+    source: Option<Box<dyn Read + Send>>,
+
+    /// The keys forwarded by the reader thread, once it has been started
+    keys: Option<Receiver<u8>>,
+}
+
+impl Keyboard {
+    /// Create a keyboard that reads keys from `source`
+    pub fn new<R: Read + Send + 'static>(source: R) -> Self {
+        Keyboard {
+            source: Some(Box::new(source)),
+            keys: None,
+        }
+    }
+
+    /// Wait for the next key, like C's `getchar()`
+    ///
+    /// This returns 0 once the input stream is exhausted.
+    pub fn read_key(&mut self) -> u8 {
+        self.keys().recv().unwrap_or_default()
+    }
+
+    /// Create a keyboard whose keys are sent over a channel instead of read from a stream
+    ///
+    /// No reader thread is started. Once every sender is dropped, the keyboard behaves like an
+    /// exhausted stream.
+    pub fn from_channel(keys: Receiver<u8>) -> Self {
+        Keyboard {
+            source: None,
+            keys: Some(keys),
+        }
+    }
+
+    /// Get the next key if one has already been typed
+    pub fn poll_key(&mut self) -> Option<u8> {
+        self.keys().try_recv().ok()
+    }
+
+    /// The channel of keys, starting the reader thread if it isn't running yet
+    fn keys(&mut self) -> &Receiver<u8> {
+        if let Some(mut source) = self.source.take() {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let mut buf = [0];
+                // Stop at the end of the stream, on an error, or once the VM is gone
+                while let Ok(1) = source.read(&mut buf) {
+                    if sender.send(buf[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+            self.keys = Some(receiver);
+        }
+        self.keys.as_ref().expect("keyboard reader was not started")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_key_returns_one_byte_at_a_time() {
+        // A raw mode terminal sends each keypress as soon as it is typed, without a newline
+        let mut keyboard = Keyboard::new(Cursor::new(b"wa".to_vec()));
+        assert_eq!(keyboard.read_key(), b'w');
+        assert_eq!(keyboard.read_key(), b'a');
+        assert_eq!(keyboard.read_key(), 0);
+    }
+}
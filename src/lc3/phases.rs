@@ -0,0 +1,202 @@
+/// Stepping that reports the phases of the LC3's instruction cycle
+///
+/// The classic LC3 datapath splits every instruction into phases: fetch, decode, evaluate address,
+/// fetch operands, execute, and store result. `LC3::step_phases` executes an instruction like
+/// `step` and also returns the phases it went through with their intermediate values, so that a
+/// UI can animate the datapath. Instructions only go through the phases they need, so a load has
+/// no execute phase and `ADD` has no address to evaluate.
+use super::{
+    consts::{EffectiveAddress, Register},
+    error::VmError,
+    instruction::decode::{decode, Instruction, Operand},
+    DispatchTables, LC3,
+};
+
+/// Where a result is stored
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Destination {
+    /// A register
+    Register(Register),
+
+    /// A memory address
+    Memory(u16),
+}
+
+/// A phase of the instruction cycle with the values it produced
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// The instruction was read from memory at `pc`, and the PC was incremented
+    Fetch { pc: u16, instr: u16 },
+
+    /// The instruction was decoded
+    Decode(Instruction),
+
+    /// The address of the memory the instruction accesses was computed, following the pointer
+    /// for indirect loads and stores
+    EvaluateAddress { address: u16 },
+
+    /// The values the instruction works on were read from registers or memory
+    FetchOperands { operands: Vec<u16> },
+
+    /// The ALU computed the result
+    Execute { result: u16 },
+
+    /// The result was written to its destination
+    StoreResult {
+        destination: Destination,
+        value: u16,
+    },
+}
+
+impl LC3 {
+    /// Execute one instruction like `step`, returning the phases it went through
+    ///
+    /// The detailed phases are reported for the operate instructions (`ADD`, `AND`, and `NOT`)
+    /// and for the loads, stores, and `LEA`. Other instructions only report their fetch and decode.
+    pub fn step_phases(&mut self, tables: &DispatchTables) -> Result<Vec<Phase>, VmError> {
+        let pc = self.registers[Register::PC as usize];
+        let instr = self.memory[pc as usize];
+        let instruction = decode(instr);
+        // The address is computed before the instruction runs, since running it can change the
+        // registers it depends on
+        let address = self.effective_address().map(|address| match address {
+            EffectiveAddress::Direct(address) => address,
+            EffectiveAddress::Indirect(_, address) => address,
+        });
+        let register = |vm: &LC3, register: Register| vm.registers[register as usize];
+        let operand = |vm: &LC3, operand: Operand| match operand {
+            Operand::Register(r) => register(vm, r),
+            Operand::Immediate(value) => value as u16,
+        };
+
+        // Operands of operate instructions and stores are read before the instruction runs
+        let operands = match instruction {
+            Instruction::ADD {
+                sr1, operand: op, ..
+            }
+            | Instruction::AND {
+                sr1, operand: op, ..
+            } => Some(vec![register(self, sr1), operand(self, op)]),
+            Instruction::NOT { sr, .. } => Some(vec![register(self, sr)]),
+            Instruction::ST { sr, .. }
+            | Instruction::STI { sr, .. }
+            | Instruction::STR { sr, .. } => Some(vec![register(self, sr)]),
+            _ => None,
+        };
+
+        self.step(tables)?;
+
+        let mut phases = vec![Phase::Fetch { pc, instr }, Phase::Decode(instruction)];
+        match instruction {
+            Instruction::ADD { dr, .. }
+            | Instruction::AND { dr, .. }
+            | Instruction::NOT { dr, .. } => {
+                let result = register(self, dr);
+                phases.push(Phase::FetchOperands {
+                    operands: operands.unwrap_or_default(),
+                });
+                phases.push(Phase::Execute { result });
+                phases.push(Phase::StoreResult {
+                    destination: Destination::Register(dr),
+                    value: result,
+                });
+            }
+            Instruction::LD { dr, .. }
+            | Instruction::LDI { dr, .. }
+            | Instruction::LDR { dr, .. } => {
+                // The loaded value is what was read, which for a memory mapped register can
+                // differ from what was stored in memory before the step
+                let value = register(self, dr);
+                phases.extend(address.map(|address| Phase::EvaluateAddress { address }));
+                phases.push(Phase::FetchOperands {
+                    operands: vec![value],
+                });
+                phases.push(Phase::StoreResult {
+                    destination: Destination::Register(dr),
+                    value,
+                });
+            }
+            Instruction::LEA { dr, .. } => {
+                phases.extend(address.map(|address| Phase::EvaluateAddress { address }));
+                phases.push(Phase::StoreResult {
+                    destination: Destination::Register(dr),
+                    value: register(self, dr),
+                });
+            }
+            Instruction::ST { .. } | Instruction::STI { .. } | Instruction::STR { .. } => {
+                let operands = operands.unwrap_or_default();
+                let (value, address) = (operands[0], address.unwrap_or_default());
+                phases.push(Phase::EvaluateAddress { address });
+                phases.push(Phase::FetchOperands { operands });
+                phases.push(Phase::StoreResult {
+                    destination: Destination::Memory(address),
+                    value,
+                });
+            }
+            _ => (),
+        }
+        Ok(phases)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn ldr_goes_through_memory_phases() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        // LDR R0, R2, #1
+        vm.memory[0x3000] = 0x6081;
+        vm.memory[0x4001] = 42;
+        vm.registers[Register::R2 as usize] = 0x4000;
+        vm.start();
+
+        let phases = vm.step_phases(&DispatchTables::new()).unwrap();
+        assert_eq!(
+            phases,
+            [
+                Phase::Fetch {
+                    pc: 0x3000,
+                    instr: 0x6081
+                },
+                Phase::Decode(Instruction::LDR {
+                    dr: Register::R0,
+                    base: Register::R2,
+                    offset: 1
+                }),
+                Phase::EvaluateAddress { address: 0x4001 },
+                Phase::FetchOperands { operands: vec![42] },
+                Phase::StoreResult {
+                    destination: Destination::Register(Register::R0),
+                    value: 42
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn add_goes_through_execute() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        // ADD R1, R1, #-2
+        vm.memory[0x3000] = 0x127E;
+        vm.registers[Register::R1 as usize] = 5;
+        vm.start();
+
+        let phases = vm.step_phases(&DispatchTables::new()).unwrap();
+        assert_eq!(
+            phases[2..],
+            [
+                Phase::FetchOperands {
+                    operands: vec![5, 0xFFFE]
+                },
+                Phase::Execute { result: 3 },
+                Phase::StoreResult {
+                    destination: Destination::Register(Register::R1),
+                    value: 3
+                },
+            ]
+        );
+    }
+}
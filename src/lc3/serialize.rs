@@ -0,0 +1,127 @@
+/// Serialization of the VM state with serde, enabled by the `serde` feature
+///
+/// Only the runtime state is serialized, the same state that `LC3::save_state` saves. The input and
+/// output streams and the configuration of the VM are left out, so a deserialized VM has the
+/// defaults from `LC3::new` for those.
+use super::LC3;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// The serialized form of the VM
+#[derive(Serialize, Deserialize)]
+struct State {
+    memory: Vec<u16>,
+    registers: Vec<u16>,
+    running: bool,
+    trap: bool,
+    supervisor_mode: bool,
+    priority: u16,
+    saved_stack_pointer: u16,
+    instruction_count: u64,
+    rng_state: u32,
+}
+
+impl Serialize for LC3 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        State {
+            memory: self.memory.clone(),
+            registers: self.registers.clone(),
+            running: self.running,
+            trap: self.trap,
+            supervisor_mode: self.supervisor_mode,
+            priority: self.priority,
+            saved_stack_pointer: self.saved_stack_pointer,
+            instruction_count: self.instruction_count,
+            rng_state: self.rng_state,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LC3 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = State::deserialize(deserializer)?;
+        let mut vm = LC3::new();
+        if state.memory.len() != vm.memory.len() {
+            return Err(de::Error::invalid_length(
+                state.memory.len(),
+                &"a word for every memory address",
+            ));
+        }
+        if state.registers.len() != vm.registers.len() {
+            return Err(de::Error::invalid_length(
+                state.registers.len(),
+                &"a value for every register",
+            ));
+        }
+        vm.memory = state.memory;
+        vm.registers = state.registers;
+        vm.running = state.running;
+        vm.trap = state.trap;
+        vm.supervisor_mode = state.supervisor_mode;
+        vm.priority = state.priority & 0x7;
+        vm.saved_stack_pointer = state.saved_stack_pointer;
+        vm.instruction_count = state.instruction_count;
+        vm.rng_state = state.rng_state;
+        Ok(vm)
+    }
+}
+
+impl LC3 {
+    /// Serialize the state of the VM to a JSON string
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// Create a VM from the JSON written by `to_json`
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::{
+        consts::{ConditionFlag, Register},
+        DispatchTables,
+    };
+
+    #[test]
+    fn json_round_trip_preserves_state() {
+        let mut vm = LC3::new();
+        vm.captured_output = Some(Vec::new());
+        vm.memory[0x3000] = 0x103F; // ADD R0, R0, #-1
+        vm.memory[0x3001] = 0x1263; // ADD R1, R1, #3
+        vm.start();
+        vm.step(&DispatchTables::new()).unwrap();
+
+        let json = vm.to_json().unwrap();
+        let mut restored = LC3::from_json(&json).unwrap();
+        assert_eq!(restored.registers, vm.registers);
+        assert_eq!(restored.registers[Register::PC as usize], 0x3001);
+        assert_eq!(restored.condition_flags(), ConditionFlag::NEG);
+        assert_eq!(restored.memory, vm.memory);
+        assert_eq!(restored.psr(), vm.psr());
+        assert_eq!(restored.instruction_count(), 1);
+        assert!(restored.is_running());
+
+        restored.captured_output = Some(Vec::new());
+        restored.step(&DispatchTables::new()).unwrap();
+        assert_eq!(restored.registers[Register::R1 as usize], 3);
+    }
+
+    #[test]
+    fn state_types_serialize() {
+        assert_eq!(serde_json::to_string(&Register::PC).unwrap(), "\"PC\"");
+        let flag: ConditionFlag = serde_json::from_str("\"ZRO\"").unwrap();
+        assert_eq!(flag, ConditionFlag::ZRO);
+    }
+
+    #[test]
+    fn short_memory_is_rejected() {
+        let mut json: serde_json::Value =
+            serde_json::from_str(&LC3::new().to_json().unwrap()).unwrap();
+        json["memory"] = serde_json::json!([0, 1, 2]);
+        assert!(LC3::from_json(&json.to_string()).is_err());
+    }
+}
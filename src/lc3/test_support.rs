@@ -0,0 +1,15 @@
+/// Fixtures shared by the unit tests of the VM and of the modules that drive it
+use super::LC3;
+use crate::assembler;
+use std::io;
+
+/// Create a VM without a terminal and load the assembled `source` into it
+///
+/// Output is captured, so it can be checked with `take_captured_output`.
+pub(crate) fn load(source: &str) -> LC3 {
+    let mut vm = LC3::with_io(io::empty(), io::sink());
+    vm.captured_output = Some(Vec::new());
+    let image = assembler::assemble(source).unwrap();
+    vm.load_image(&assembler::to_bytes(&image)).unwrap();
+    vm
+}
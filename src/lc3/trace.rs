@@ -0,0 +1,77 @@
+/// A compact binary trace of executed instructions and its offline decoder
+///
+/// The text trace written to `LC3::trace` is easy to read but large for long runs. The binary
+/// trace instead stores a fixed-size record per executed instruction, which can be expanded into
+/// disassembly later with `read_records`.
+use crate::disassembler::disassemble;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// The size of a record in bytes: the step number, the PC, and the instruction word, big-endian
+pub const RECORD_SIZE: usize = 12;
+
+/// One executed instruction in a binary trace
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TraceRecord {
+    /// The number of instructions executed before this one since the VM was reset
+    pub step: u64,
+
+    /// The address the instruction was fetched from
+    pub pc: u16,
+
+    /// The instruction word
+    pub instr: u16,
+}
+
+impl TraceRecord {
+    /// Write the record in its binary form
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<()> {
+        let mut buf = [0; RECORD_SIZE];
+        buf[..8].copy_from_slice(&self.step.to_be_bytes());
+        buf[8..10].copy_from_slice(&self.pc.to_be_bytes());
+        buf[10..].copy_from_slice(&self.instr.to_be_bytes());
+        writer.write_all(&buf)
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} {:04x} {:04x} {}",
+            self.step,
+            self.pc,
+            self.instr,
+            disassemble(self.instr)
+        )
+    }
+}
+
+/// Read every record of a binary trace
+///
+/// A trace that ends partway through a record, such as one from a VM that was killed while
+/// writing it, is reported as an `UnexpectedEof` error.
+pub fn read_records<R: Read>(mut reader: R) -> io::Result<Vec<TraceRecord>> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    if buf.len() % RECORD_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "trace ends partway through a record",
+        ));
+    }
+    Ok(buf
+        .chunks(RECORD_SIZE)
+        .map(|record| {
+            let mut step = [0; 8];
+            step.copy_from_slice(&record[..8]);
+            TraceRecord {
+                step: u64::from_be_bytes(step),
+                pc: u16::from_be_bytes([record[8], record[9]]),
+                instr: u16::from_be_bytes([record[10], record[11]]),
+            }
+        })
+        .collect())
+}
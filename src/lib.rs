@@ -0,0 +1,39 @@
+//! A virtual machine for the LC3 architecture.
+//!
+//! The VM itself lives in the `lc3` module, and the most commonly used types are re-exported at
+//! the top of the crate so that the VM can be embedded in other programs:
+//!
+//! ```no_run
+//! use lc3_vm::{DispatchTables, LC3};
+//!
+//! let mut vm = LC3::new();
+//! let tables = DispatchTables::new();
+//! vm.read_image_file(&"program.obj".into()).unwrap();
+//! if let Err(error) = vm.run_loop(&tables) {
+//!     eprintln!("the program faulted: {}", error);
+//! }
+//! ```
+//!
+//! Programs that don't need a terminal, such as when grading or testing them, can be run in one
+//! call with `run_to_halt`, which returns what the program printed. Untrusted programs should be
+//! run with `run_sandboxed` instead, which engages every limit the VM has.
+//!
+//! The `lc3-vm` binary is a thin command line wrapper around this library.
+pub mod assembler;
+pub mod battery;
+pub mod control_flow;
+pub mod debugger;
+pub mod disassembler;
+pub mod generator;
+pub mod lc3;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod protocol;
+pub mod worker;
+
+pub use lc3::{
+    consts,
+    error::{RunError, VmError},
+    instruction, run_sandboxed, run_to_halt, CpuState, DispatchTables, NullIo, RunResult,
+    SandboxConfig, StepRecord, LC3,
+};
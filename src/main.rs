@@ -1,47 +1,461 @@
 use log::debug;
+use num_traits::FromPrimitive;
 use pretty_env_logger;
+use sha2::{Digest, Sha256};
 use std::{
-    io::{self, Read, Write},
-    path::PathBuf,
+    error::Error,
+    fs,
+    io::{self, BufReader, BufWriter, Read, Write},
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use structopt::StructOpt;
 use termion::raw::IntoRawMode;
 use tui::{backend::TermionBackend, Terminal};
 
-mod debugger;
-mod lc3;
-
-use debugger::Debugger;
+use lc3::{
+    consts::{EffectiveAddress, Register},
+    format::{self, ImageFormat},
+    trace,
+};
+#[cfg(feature = "net")]
+use lc3_vm::net;
+use lc3_vm::{
+    assembler, battery,
+    control_flow::ControlFlowGraph,
+    debugger::{self, Debugger, PromptKind},
+    disassembler, lc3, protocol,
+};
 
 /// A VM for the LC3 architecture
 #[derive(Debug, StructOpt)]
 #[structopt(author)]
 struct Opt {
     /// The path to an image file for the VM to execute
-    #[structopt(parse(from_os_str))]
-    pub image_file: PathBuf,
+    #[structopt(parse(from_os_str), required_unless_one = &["url", "battery", "trace-decode", "check"])]
+    pub image_file: Option<PathBuf>,
+
+    /// Download the image to execute from an HTTP(S) URL instead of reading a file
+    #[cfg(feature = "net")]
+    #[structopt(long, conflicts_with = "image-file")]
+    pub url: Option<String>,
 
     /// Whether the VM should run with the debugger
     #[structopt(short, long)]
     pub debug: bool,
+
+    /// Put the terminal in raw mode while the VM runs so GETC and IN receive single keypresses
+    /// without waiting for Enter (ignored when running with the debugger)
+    #[structopt(long)]
+    pub raw_input: bool,
+
+    /// Read the program's keyboard input from this file instead of the terminal. GETC and IN read
+    /// 0 once the file runs out.
+    #[structopt(long, parse(from_os_str), conflicts_with = "json-protocol")]
+    pub input: Option<PathBuf>,
+
+    /// Log every change to a register, such as `R3` (can be repeated). Changes are logged under
+    /// the `watch` target, so run with `RUST_LOG=watch=info` to see them.
+    #[structopt(long = "watch-reg", number_of_values = 1)]
+    pub watch_reg: Vec<Register>,
+
+    /// Log every change to a memory address, such as `0x3100` or `x3100` (can be repeated)
+    #[structopt(long = "watch-mem", parse(try_from_str = parse_u16), number_of_values = 1)]
+    pub watch_mem: Vec<u16>,
+
+    /// Refuse to run the image unless the SHA-256 digest of its raw bytes matches this hex string
+    #[structopt(long)]
+    pub expect_sha256: Option<String>,
+
+    /// Warn when the program reads memory that it never wrote and that wasn't loaded from the
+    /// image. Warnings are logged under the `uninit` target, e.g. `RUST_LOG=uninit=warn`, and
+    /// written to the `--trace` file.
+    #[structopt(long)]
+    pub warn_uninit: bool,
+
+    /// Drive the VM over stdin/stdout with a JSON lines debug protocol. Send `step`, `continue`,
+    /// or `quit` on stdin, and one JSON record is written to stdout per executed instruction.
+    #[structopt(long, conflicts_with = "debug")]
+    pub json_protocol: bool,
+
+    /// Halt with an error if the program stores into the memory occupied by the loaded image,
+    /// which catches accidental self-modifying code
+    #[structopt(long)]
+    pub protect_code: bool,
+
+    /// Enable the non-standard extended trap routines: PUTN (x26), MEMCPY (x30), RAND (x31), and
+    /// GETN (x32)
+    #[structopt(long)]
+    pub extended_traps: bool,
+
+    /// Seed for the random numbers returned by the RAND trap. Runs with the same seed produce the
+    /// same numbers. A seed based on the current time is used if this isn't set.
+    #[structopt(long)]
+    pub seed: Option<u32>,
+
+    /// Seed a register (`R1=5`) or memory address (`@x4000=0x20`) before the program starts. Values
+    /// can be hex, decimal, or negative decimal (can be repeated).
+    #[structopt(long = "arg", number_of_values = 1)]
+    pub args: Vec<ProgramArg>,
+
+    /// How many instructions per second the debugger executes in play mode (toggled with space)
+    #[structopt(long, default_value = "20")]
+    pub play_rate: u32,
+
+    /// Halt when the program accesses a memory mapped register in a way it doesn't support, such
+    /// as writing to KBDR. By default these accesses are skipped and logged under the `mmio`
+    /// target.
+    #[structopt(long)]
+    pub strict_mmio: bool,
+
+    /// Track the depth of the stack, assuming R6 is a stack pointer that grows downward from this
+    /// address. The current and peak depth are printed when the program halts and shown in the
+    /// debugger.
+    #[structopt(long, parse(try_from_str = parse_u16))]
+    pub stack_base: Option<u16>,
+
+    /// Enable the CLOCK memory mapped register at xFE08, which reads as the low 16 bits of the
+    /// number of instructions executed so far
+    #[structopt(long)]
+    pub clock_register: bool,
+
+    /// The format of the image: `obj`, `hex` (Intel HEX), or `gzip`. By default the format is
+    /// detected from the contents of the image.
+    #[structopt(long)]
+    pub format: Option<ImageFormat>,
+
+    /// Record which instructions are executed and print how many words of the image were covered
+    /// when the program halts
+    #[structopt(long)]
+    pub coverage: bool,
+
+    /// The address to start executing from instead of x3000
+    #[structopt(long, parse(try_from_str = parse_u16))]
+    pub entry: Option<u16>,
+
+    /// Start executing from the origin of the image instead of x3000
+    #[structopt(long, conflicts_with = "entry")]
+    pub start_at_origin: bool,
+
+    /// Halt when the GETN trap reads a number that doesn't fit in 16 bits instead of wrapping it
+    #[structopt(long)]
+    pub strict_getn: bool,
+
+    /// Stop the program after it has executed this many instructions, so that a program stuck in
+    /// an infinite loop still terminates. There is no limit by default.
+    #[structopt(long)]
+    pub max_instructions: Option<u64>,
+
+    /// Write a line to this file for every executed instruction, with its address, raw word,
+    /// disassembly, and the registers after it executed
+    #[structopt(long, parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+
+    /// Write a compact 12 byte record to this file for every executed instruction, holding the
+    /// step number, address, and raw word. Expand it later with `--trace-decode`.
+    #[structopt(long, parse(from_os_str))]
+    pub binary_trace: Option<PathBuf>,
+
+    /// Save the memory, registers, and PC to this file if the program faults, in the same format
+    /// as a saved VM state
+    #[structopt(long, parse(from_os_str))]
+    pub dump_on_fault: Option<PathBuf>,
+
+    /// Print the disassembly of the image instead of running it
+    #[structopt(long, conflicts_with_all = &["debug", "json-protocol", "battery"])]
+    pub disasm: bool,
+
+    /// Print the control flow graph of the image in the Graphviz dot language instead of running
+    /// it, e.g. `lc3-vm --cfg program.obj | dot -Tsvg > program.svg`
+    #[structopt(long, conflicts_with_all = &["debug", "json-protocol", "battery", "disasm"])]
+    pub cfg: bool,
+
+    /// Disassemble a raw dump whose first word is code rather than the origin. The words are
+    /// placed at `--base`.
+    #[structopt(long, requires = "disasm")]
+    pub no_header: bool,
+
+    /// The address of the first word of a headerless dump, x3000 by default
+    #[structopt(long, parse(try_from_str = parse_u16), requires = "no-header")]
+    pub base: Option<u16>,
+
+    /// Print the disassembly of every instruction in a binary trace file instead of running an
+    /// image
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["image-file", "battery"])]
+    pub trace_decode: Option<PathBuf>,
+
+    /// Set a debugger breakpoint at an address, such as `x3005` (can be repeated). Press `c` in
+    /// the debugger to continue to the next breakpoint.
+    #[structopt(long = "break", parse(try_from_str = parse_u16), number_of_values = 1)]
+    pub breakpoints: Vec<u16>,
+
+    /// Set a debugger watchpoint on a memory address (can be repeated). Continuing stops as soon
+    /// as an instruction changes the value at the address.
+    #[structopt(long = "watchpoint", parse(try_from_str = parse_u16), number_of_values = 1)]
+    pub watchpoints: Vec<u16>,
+
+    /// The most steps the debugger can undo with `u`. Set this to 0 to turn undoing off.
+    #[structopt(long, default_value = "10000")]
+    pub history_limit: usize,
+
+    /// The most instructions the debugger executes for `c`, `t`, `T`, or a counted `n` before
+    /// stopping, so that a program stuck in a loop doesn't freeze it. Typing a count before `c`
+    /// overrides this once.
+    #[structopt(long, default_value = "1000000")]
+    pub run_limit: usize,
+
+    /// Run every image in this directory headlessly and report which ones pass. `NAME.in` next to
+    /// an image is fed to it as input, and `NAME.out` holds exactly what it must print, including
+    /// the HALT message. `--max-instructions` limits each image.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["image-file", "debug", "json-protocol"])]
+    pub battery: Option<PathBuf>,
+
+    /// Check an assembly source file without running it, printing every error and warning as
+    /// `line:col: message`. Exits with a failure status if there are any errors.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["image-file", "battery", "trace-decode"])]
+    pub check: Option<PathBuf>,
 }
 
-fn main() -> Result<(), io::Error> {
+/// A value that is placed in a register or in memory before the program starts running
+#[derive(Debug)]
+enum ProgramArg {
+    /// Set a register to a value
+    Register(Register, u16),
+
+    /// Set the word at a memory address to a value
+    Memory(u16, u16),
+}
+
+impl FromStr for ProgramArg {
+    type Err = String;
+
+    /// Parse an argument of the form `Rn=value` or `@ADDR=value`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let target = parts.next().unwrap_or_default().trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("expected TARGET=VALUE, got \"{}\"", s))?
+            .trim();
+
+        // Negative decimal values are stored in two's complement
+        let value = parse_u16(value)
+            .or_else(|_| value.parse::<i16>().map(|v| v as u16))
+            .map_err(|e| format!("invalid value \"{}\": {}", value, e))?;
+
+        if let Some(addr) = target.strip_prefix('@') {
+            let addr =
+                parse_u16(addr).map_err(|e| format!("invalid address \"{}\": {}", addr, e))?;
+            Ok(ProgramArg::Memory(addr, value))
+        } else {
+            Ok(ProgramArg::Register(target.parse()?, value))
+        }
+    }
+}
+
+/// Combine big-endian byte pairs into words, padding a trailing odd byte like the image loader
+fn to_words(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or_default()]))
+        .collect()
+}
+
+/// Seed the registers and memory given by `--arg` before the program starts
+fn apply_args(vm: &mut lc3::LC3, args: &[ProgramArg]) {
+    for arg in args {
+        match *arg {
+            ProgramArg::Register(r, value) => vm.registers[r as usize] = value,
+            ProgramArg::Memory(addr, value) => vm.mem_write(addr, value),
+        }
+    }
+}
+
+/// Parse a 16 bit value written in hex (`0x3000` or the LC3 style `x3000`) or in decimal
+fn parse_u16(s: &str) -> Result<u16, ParseIntError> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix('x')) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    }
+}
+
+/// Derive a random number generator seed from the current time
+fn time_seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos() ^ elapsed.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// Read the raw bytes of the image selected on the command line
+fn read_image(opt: &Opt) -> Result<Vec<u8>, Box<dyn Error>> {
+    #[cfg(feature = "net")]
+    {
+        if let Some(url) = &opt.url {
+            return net::fetch_image(url);
+        }
+    }
+
+    // `image_file` is required unless another image source was given
+    let image_file = opt.image_file.as_ref().expect("no image file provided");
+    Ok(fs::read(image_file)?)
+}
+
+/// Check that the SHA-256 digest of an image matches the expected hex digest
+///
+/// The comparison is case insensitive. A mismatch is reported as an `InvalidData` error.
+fn verify_checksum(image: &[u8], expected: &str) -> io::Result<()> {
+    let actual: String = Sha256::digest(image)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "image checksum mismatch: expected {}, got {}",
+                expected, actual
+            ),
+        ))
+    }
+}
+
+/// Run the test battery in `dir` and print the outcome of every test case
+///
+/// Returns whether every test case passed.
+fn run_battery(dir: &Path, max_steps: u64) -> io::Result<bool> {
+    let results = battery::run_battery(&battery::load_cases(dir)?, max_steps);
+    let passed = results
+        .iter()
+        .filter(|(_, outcome)| outcome.is_pass())
+        .count();
+    for (name, outcome) in &results {
+        println!("{}: {}", name, outcome);
+    }
+    println!("\n{} of {} passed", passed, results.len());
+    Ok(passed == results.len())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
     let opt = Opt::from_args();
+    if let Some(path) = &opt.trace_decode {
+        for record in trace::read_records(BufReader::new(fs::File::open(path)?))? {
+            println!("{}", record);
+        }
+        return Ok(());
+    }
+    if let Some(path) = &opt.check {
+        let diagnostics = assembler::check(&fs::read_to_string(path)?);
+        for diagnostic in &diagnostics {
+            println!("{}:{}", path.display(), diagnostic);
+        }
+        if diagnostics.iter().any(|diagnostic| !diagnostic.warning) {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(dir) = &opt.battery {
+        let max_steps = opt.max_instructions.unwrap_or(battery::DEFAULT_MAX_STEPS);
+        if !run_battery(dir, max_steps)? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if opt.cfg {
+        let words = to_words(&format::decode(&read_image(&opt)?, opt.format)?);
+        print!("{}", ControlFlowGraph::build(&words).to_dot());
+        return Ok(());
+    }
+    if opt.disasm {
+        let raw_image = read_image(&opt)?;
+        let (words, base) = if opt.no_header {
+            (
+                to_words(&raw_image),
+                Some(opt.base.unwrap_or(lc3::consts::PC_START)),
+            )
+        } else {
+            (to_words(&format::decode(&raw_image, opt.format)?), None)
+        };
+        for (addr, text) in disassembler::disassemble_image(&words, base) {
+            println!("x{:04X}  {}", addr, text);
+        }
+        return Ok(());
+    }
     debug!("Initialized VM");
-    let mut vm = lc3::LC3::new();
+    let mut vm = lc3::LC3::with_pc_start(opt.entry.unwrap_or(lc3::consts::PC_START));
     let tables = lc3::DispatchTables::new();
-    vm.read_image_file(&opt.image_file)?;
+    if opt.warn_uninit {
+        vm.track_uninitialized_reads();
+    }
+    let raw_image = read_image(&opt)?;
+    let load_report = vm.load_image(&format::decode(&raw_image, opt.format)?)?;
+    debug!("{}", load_report);
+    if opt.start_at_origin {
+        vm.start_at(load_report.origin);
+    }
 
-    if opt.debug {
+    if let Some(expected) = &opt.expect_sha256 {
+        verify_checksum(&raw_image, expected)?;
+    }
+    vm.protect_code = opt.protect_code;
+    vm.extended_traps = opt.extended_traps;
+    vm.strict_getn = opt.strict_getn;
+    vm.max_instructions = opt.max_instructions;
+    vm.fault_dump = opt.dump_on_fault.clone();
+    if let Some(path) = &opt.input {
+        vm.set_input(fs::File::open(path)?);
+    }
+    if let Some(path) = &opt.trace {
+        vm.trace = Some(Box::new(BufWriter::new(fs::File::create(path)?)));
+    }
+    if let Some(path) = &opt.binary_trace {
+        vm.binary_trace = Some(Box::new(BufWriter::new(fs::File::create(path)?)));
+    }
+    vm.strict_mmio = opt.strict_mmio;
+    vm.clock_register = opt.clock_register;
+    if opt.coverage {
+        vm.track_coverage();
+    }
+    if let Some(base) = opt.stack_base {
+        vm.track_stack(base);
+    }
+    vm.seed_rng(opt.seed.unwrap_or_else(time_seed));
+    vm.watched_registers = opt.watch_reg.clone();
+    vm.watched_addresses = opt.watch_mem.clone();
+
+    apply_args(&mut vm, &opt.args);
+
+    if opt.json_protocol {
+        protocol::serve(&mut vm, &tables, BufReader::new(io::stdin()), io::stdout())?;
+    } else if opt.debug {
         let mut stdout = io::stdout().into_raw_mode()?;
         write!(stdout, "{}", termion::clear::All)?;
         let backend = TermionBackend::new(stdout);
         let mut terminal = Terminal::new(backend)?;
-        let mut debug_state = Debugger::new(&mut vm);
+        let mut debug_state = Debugger::with_breakpoints(&mut vm, opt.breakpoints.clone());
+        debug_state.message = Some(load_report.to_string());
+        debug_state.history_limit = opt.history_limit;
+        for &addr in &opt.watchpoints {
+            debug_state.toggle_watchpoint(addr);
+        }
+        // The debugger reads its commands from stdin, so the program's input is typed through it
+        if opt.input.is_none() {
+            debug_state.own_keyboard();
+        }
         let mut reader = termion::async_stdin();
         let mut buf = String::new();
+        // A count typed before a command, such as the `5` in `5n`
+        let mut count = String::new();
+        let play_interval = Duration::from_secs(1) / opt.play_rate.max(1);
+        let mut last_play_tick = Instant::now();
 
         // Draw the initial state
         debugger::draw(&mut terminal, &debug_state)?;
@@ -50,24 +464,290 @@ fn main() -> Result<(), io::Error> {
         // repaint the screen constantly, only when the display output changes (otherwise this will
         // waste a lot of resources just constantly repainting).
         loop {
-            // get next key and perform appropriate action
+            // get next keys and perform the appropriate actions
             reader.read_to_string(&mut buf)?;
-            match buf.as_ref() {
-                "q" => {
-                    let mut stdout = io::stdout().into_raw_mode().unwrap();
-                    write!(stdout, "{}", termion::clear::All)?;
-                    return Ok(());
-                }
-                "n" => {
-                    debug_state.tick(&tables);
+            for key in buf.chars() {
+                // While the prompt is open, keys are typed into it instead of running commands
+                if debug_state.prompt.is_some() {
+                    debug_state.prompt_key(key);
                     debugger::draw(&mut terminal, &debug_state)?;
+                    continue;
+                }
+                match key {
+                    'q' => {
+                        let mut stdout = io::stdout().into_raw_mode().unwrap();
+                        write!(stdout, "{}", termion::clear::All)?;
+                        return Ok(());
+                    }
+                    // `n` steps once, or `count` times if a count was typed first, stopping early at
+                    // a breakpoint or watchpoint
+                    'n' => {
+                        let steps = count.parse().unwrap_or(1).min(opt.run_limit);
+                        count.clear();
+                        debug_state.tick_n(&tables, steps);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `u` undoes the last step, or the last `count` steps
+                    'u' => {
+                        let steps = count.parse().unwrap_or(1);
+                        count.clear();
+                        for _ in 0..steps {
+                            if !debug_state.step_back() {
+                                debug_state.message = Some(String::from("nothing to undo"));
+                                break;
+                            }
+                        }
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `t` runs to the next trap and stops before it, `T` stops after it
+                    't' | 'T' => {
+                        count.clear();
+                        debug_state.run_to_trap(&tables, key == 'T', opt.run_limit);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `c` continues until the PC hits a breakpoint or the program halts, executing
+                    // at most `count` instructions if a count was typed first
+                    'c' => {
+                        let limit = count.parse::<usize>().unwrap_or(opt.run_limit);
+                        count.clear();
+                        debug_state.continue_to_breakpoint(&tables, limit);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `b` sets or removes a breakpoint at the PC, or `count` words after it if a
+                    // count was typed first, so `5b` targets the same address as `BR #4`
+                    'b' => {
+                        let offset = count.parse::<u16>().unwrap_or(0);
+                        count.clear();
+                        let pc = debug_state.vm.registers[Register::PC as usize];
+                        debug_state.toggle_breakpoint(pc.wrapping_add(offset));
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `m` watches or stops watching the memory the next instruction loads from or
+                    // stores to
+                    'm' => {
+                        count.clear();
+                        match debug_state.vm.effective_address() {
+                            Some(EffectiveAddress::Direct(addr))
+                            | Some(EffectiveAddress::Indirect(_, addr)) => {
+                                debug_state.toggle_watchpoint(addr)
+                            }
+                            None => {
+                                debug_state.message =
+                                    Some(String::from("the next instruction doesn't access memory"))
+                            }
+                        }
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `j` and `k` move the memory cursor down and up a row, or `count` rows, and
+                    // `J` and `K` move it a page at a time
+                    'j' | 'k' | 'J' | 'K' => {
+                        let rows: i32 = count.parse().unwrap_or(1);
+                        count.clear();
+                        // Memory wraps around, so a huge count wraps instead of overflowing
+                        let rows = match key {
+                            'j' => rows,
+                            'k' => rows.wrapping_neg(),
+                            'J' => rows.wrapping_mul(debugger::MEMORY_PAGE_ROWS),
+                            _ => rows.wrapping_neg().wrapping_mul(debugger::MEMORY_PAGE_ROWS),
+                        };
+                        debug_state.scroll_memory(rows);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `g` prompts for an address to move the memory cursor to
+                    'g' => {
+                        count.clear();
+                        debug_state.open_prompt(PromptKind::MemoryAddress);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // space starts or stops automatically stepping through the program
+                    ' ' => {
+                        count.clear();
+                        debug_state.toggle_play();
+                        last_play_tick = Instant::now();
+                    }
+                    // `w` toggles between wrapping and truncating long output lines
+                    'w' => {
+                        count.clear();
+                        debug_state.wrap_output = !debug_state.wrap_output;
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `f` freezes or unfreezes the general purpose register given by the count,
+                    // such as `0f` for R0
+                    'f' => {
+                        let register = count
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|&idx| idx < Register::PC as u16)
+                            .and_then(FromPrimitive::from_u16);
+                        count.clear();
+                        match register {
+                            Some(register) => debug_state.toggle_freeze(register),
+                            None => {
+                                debug_state.message =
+                                    Some(String::from("type a register number 0-7 before f"))
+                            }
+                        }
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `e` prompts for a new value for the register given by the count, such as
+                    // `0e` for R0, `8e` for the PC, or `9e` for the COND flag. `E` also sets the
+                    // condition flag from the new value of R0-R7, as if an instruction had
+                    // written it.
+                    'e' | 'E' => {
+                        let register = count
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|&idx| idx < Register::COUNT as u16)
+                            .and_then(FromPrimitive::from_u16);
+                        count.clear();
+                        match register {
+                            Some(register) => {
+                                debug_state.open_prompt(PromptKind::Register(register, key == 'E'))
+                            }
+                            None => {
+                                debug_state.message =
+                                    Some(String::from("type a register number 0-9 before e"))
+                            }
+                        }
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `p` prompts for a value to write at the memory cursor, or for an address and
+                    // a value, to patch memory in place
+                    'p' => {
+                        count.clear();
+                        debug_state.open_prompt(PromptKind::MemoryWrite);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `i` prompts for a line of keyboard input for the program
+                    'i' => {
+                        count.clear();
+                        debug_state.open_prompt(PromptKind::Input);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `x` prompts for a file to export the executed instructions to
+                    'x' => {
+                        count.clear();
+                        debug_state.open_prompt(PromptKind::ExportHistory);
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `s` and `l` prompt for a file to save the debugging session to or load it
+                    // from, including the VM's state, breakpoints, watchpoints, and history
+                    's' | 'l' => {
+                        count.clear();
+                        debug_state.open_prompt(if key == 's' {
+                            PromptKind::SaveSession
+                        } else {
+                            PromptKind::LoadSession
+                        });
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    // `r` reloads the image from its source, keeping the registers and the rest
+                    // of memory, for quick edit and run cycles
+                    'r' => {
+                        count.clear();
+                        let image = read_image(&opt).and_then(|raw_image| {
+                            format::decode(&raw_image, opt.format).map_err(Into::into)
+                        });
+                        match image {
+                            Ok(image) => debug_state.reload(&image),
+                            Err(e) => debug_state.message = Some(format!("reload failed: {}", e)),
+                        }
+                        debugger::draw(&mut terminal, &debug_state)?;
+                    }
+                    c if c.is_ascii_digit() => count.push(c),
+                    _ => count.clear(),
                 }
-                _ => (),
             }
             buf.clear();
+
+            if debug_state.playing && last_play_tick.elapsed() >= play_interval {
+                last_play_tick = Instant::now();
+                debug_state.play_tick(&tables);
+                debugger::draw(&mut terminal, &debug_state)?;
+            }
         }
     } else {
-        vm.run_loop(&tables);
+        // The raw terminal handle restores the original terminal mode when it is dropped, which
+        // also happens if the VM panics and unwinds through here. It is dropped as soon as the
+        // program stops so that the messages below are printed in the original mode.
+        let result = {
+            let _raw_terminal = if opt.raw_input {
+                vm.crlf_newlines = true;
+                Some(io::stdout().into_raw_mode()?)
+            } else {
+                None
+            };
+            vm.run_loop(&tables)
+        };
+        // Errors go to stderr, which still works if the program's output can't be written
+        if let Err(error) = &result {
+            eprintln!("PANIC: {}", error);
+        }
+
+        let mut stdout = io::stdout();
+        if let Some(usage) = vm.stack_usage {
+            writeln!(
+                stdout,
+                "\nStack: {} words in use, peak of {} words below {:#06x}",
+                usage.depth, usage.peak, usage.base
+            )?;
+        }
+        if opt.coverage {
+            let code_words = vm.code_segment().map_or(0, |segment| segment.count());
+            let covered_code_words = match vm.code_segment() {
+                Some(segment) => vm.coverage().filter(|addr| segment.contains(addr)).count(),
+                None => 0,
+            };
+            writeln!(
+                stdout,
+                "\nCoverage: {} of {} image words executed ({} addresses executed in total)",
+                covered_code_words,
+                code_words,
+                vm.coverage_count()
+            )?;
+        }
+        if result.is_err() {
+            process::exit(1);
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_checksum_compares_digest() {
+        let image = b"abc";
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert!(verify_checksum(image, digest).is_ok());
+        assert!(verify_checksum(image, &digest.to_uppercase()).is_ok());
+
+        let error = verify_checksum(image, &digest.replace('b', "c")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn args_seed_register_and_memory() {
+        let opt = Opt::from_iter(&["lc3-vm", "x.obj", "--arg", "R1=5", "--arg", "@x4000=-2"]);
+        let mut vm = lc3::LC3::with_io(io::empty(), io::sink());
+        // LDI R2, #1 loads from the address stored after the HALT
+        let image = [0x3000, 0xA401, 0xF025, 0x4000];
+        vm.load_words(&image).unwrap();
+        apply_args(&mut vm, &opt.args);
+        assert_eq!(vm.registers[Register::R1 as usize], 5);
+        assert_eq!(vm.memory[0x4000], 0xFFFE);
+
+        vm.captured_output = Some(Vec::new());
+        vm.start();
+        vm.step(&lc3::DispatchTables::new()).unwrap();
+        assert_eq!(vm.registers[Register::R2 as usize], 0xFFFE);
+    }
+
+    #[test]
+    fn break_flags_collect_breakpoints() {
+        let opt = Opt::from_iter(&["lc3-vm", "x.obj", "--break", "x3005", "--break", "0x3001"]);
+        assert_eq!(opt.breakpoints, [0x3005, 0x3001]);
+    }
+}
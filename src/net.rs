@@ -0,0 +1,68 @@
+/// Helpers for fetching VM images over the network.
+///
+/// This module is only compiled with the `net` feature, which pulls in a blocking HTTP client.
+use crate::lc3::consts;
+use std::{error::Error, io::Read};
+
+/// The largest valid image: the origin word followed by every addressable word, two bytes each
+const MAX_IMAGE_BYTES: usize = 2 * (consts::MEMORY_LIMIT + 1);
+
+/// Download an image from an HTTP(S) URL and return its raw bytes
+///
+/// Responses that advertise or contain more bytes than could ever fit in the VM's memory are
+/// rejected, so a misbehaving server can't make us buffer an arbitrarily large body.
+pub fn fetch_image(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = ureq::get(url).call()?;
+    let content_length = response
+        .header("Content-Length")
+        .and_then(|len| len.parse::<usize>().ok());
+    read_image(response.into_reader(), content_length)
+        .map_err(|e| format!("image at {}: {}", url, e).into())
+}
+
+/// Read a downloaded image from the body of a response, checking that it could fit in memory
+///
+/// `content_length` is the length the server advertised, if any, which is checked before anything
+/// is read.
+pub fn read_image<R: Read>(
+    body: R,
+    content_length: Option<usize>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some(len) = content_length {
+        if len > MAX_IMAGE_BYTES {
+            return Err(format!("image is too large ({} bytes)", len).into());
+        }
+    }
+
+    let mut buf = Vec::new();
+    body.take(MAX_IMAGE_BYTES as u64 + 1)
+        .read_to_end(&mut buf)?;
+
+    if buf.len() > MAX_IMAGE_BYTES {
+        return Err("image is too large".into());
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::LC3;
+    use std::io;
+
+    #[test]
+    fn downloaded_image_loads() {
+        let body: &[u8] = &[0x30, 0x00, 0x12, 0x34, 0xF0, 0x25];
+        let image = read_image(body, Some(body.len())).unwrap();
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        let report = vm.load_image(&image).unwrap();
+        assert_eq!(report.words_loaded, 2);
+        assert_eq!(vm.memory[0x3000..0x3002], [0x1234, 0xF025]);
+    }
+
+    #[test]
+    fn oversized_image_is_rejected() {
+        assert!(read_image(io::empty(), Some(MAX_IMAGE_BYTES + 1)).is_err());
+        assert!(read_image(io::repeat(0).take(MAX_IMAGE_BYTES as u64 + 2), None).is_err());
+    }
+}
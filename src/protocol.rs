@@ -0,0 +1,197 @@
+/// A minimal line-based debug protocol for driving the VM from another process.
+///
+/// Commands are read one per line from the input stream, and every executed instruction emits a
+/// JSON object on its own line (JSON lines) describing the state of the VM after the step. This
+/// lets external GUIs debug a program over a pipe without having to embed the VM.
+///
+/// Program output is captured and reported in the `output` field of each record so that it can't
+/// corrupt the JSON stream. Commands come from stdin, so keyboard input is given with the `input`
+/// command rather than read from stdin by the VM.
+use crate::lc3::{consts::Register, DispatchTables, LC3};
+use std::io::{self, BufRead, Write};
+
+/// Serve protocol commands from `input` until it is exhausted or `quit` is received
+///
+/// The supported commands are:
+///
+/// - `step`: execute one instruction and emit its record
+/// - `continue`: execute instructions until the VM halts, emitting a record for each one
+/// - `input <text>`: type `text` and a newline on the program's keyboard
+/// - `quit`: stop serving commands
+///
+/// Unknown commands and steps after the program has halted emit an object with an `error` field,
+/// and so does stepping while the program waits for a key that hasn't been typed, which also stops
+/// `continue`. Once the VM has executed `max_instructions` instructions, stepping emits an
+/// `instruction limit` error instead. A step that faults adds a `fault` field describing the fault
+/// to its record.
+pub fn serve<R: BufRead, W: Write>(
+    vm: &mut LC3,
+    tables: &DispatchTables,
+    input: R,
+    mut output: W,
+) -> io::Result<()> {
+    vm.captured_output = Some(Vec::new());
+    let keys = vm.input_sender();
+    vm.start();
+
+    for line in input.lines() {
+        match line?.trim() {
+            command @ "step" | command @ "continue" if vm.is_running() => loop {
+                if vm.waiting_for_key() {
+                    writeln!(output, r#"{{"error":"waiting for input"}}"#)?;
+                    break;
+                }
+                if let Some(limit) = vm.max_instructions {
+                    if vm.instruction_count() >= limit {
+                        writeln!(output, r#"{{"error":"instruction limit"}}"#)?;
+                        break;
+                    }
+                }
+                step(vm, tables, &mut output)?;
+                if command == "step" || !vm.is_running() {
+                    break;
+                }
+            },
+            command if command == "input" || command.starts_with("input ") => {
+                let text = command.get("input ".len()..).unwrap_or_default();
+                for &key in text.as_bytes().iter().chain(b"\n") {
+                    // The VM owns the receiver, so this can't fail
+                    let _ = keys.send(key);
+                }
+            }
+            "step" | "continue" => writeln!(output, r#"{{"error":"the program has halted"}}"#)?,
+            "quit" => break,
+            "" => (),
+            command => writeln!(
+                output,
+                r#"{{"error":"unknown command {}"}}"#,
+                escape(&format!("\"{}\"", command))
+            )?,
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Execute one instruction and emit the record describing the state of the VM afterwards
+fn step<W: Write>(vm: &mut LC3, tables: &DispatchTables, output: &mut W) -> io::Result<()> {
+    // Read the instruction directly so that memory mapped registers aren't triggered twice
+    let pc = vm.registers[Register::PC as usize];
+    let instr = vm.memory[pc as usize];
+    let fault = match vm.step(tables) {
+        Ok(()) => String::new(),
+        Err(error) => format!(r#","fault":"{}""#, escape(&error.to_string())),
+    };
+
+    let registers: Vec<String> = vm.registers[..=Register::R7 as usize]
+        .iter()
+        .map(u16::to_string)
+        .collect();
+    let program_output = vm.take_captured_output();
+    writeln!(
+        output,
+        r#"{{"pc":{},"cond":{},"registers":[{}],"last_pc":{},"last_instr":{},"running":{},"output":"{}"{}}}"#,
+        vm.registers[Register::PC as usize],
+        vm.registers[Register::COND as usize],
+        registers.join(","),
+        pc,
+        instr,
+        vm.is_running(),
+        escape(&String::from_utf8_lossy(&program_output)),
+        fault,
+    )
+}
+
+/// Escape a string so that it can be embedded in a JSON string literal
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+
+    #[test]
+    fn step_emits_state_record() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        let image = assembler::assemble(".ORIG x3000\nADD R1, R1, #3\nHALT\n.END").unwrap();
+        vm.load_image(&assembler::to_bytes(&image)).unwrap();
+
+        let mut output = Vec::new();
+        serve(
+            &mut vm,
+            &DispatchTables::new(),
+            &b"step\nbogus\ncontinue\nstep\n"[..],
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"pc":12289,"cond":1,"registers":[0,3,0,0,0,0,0,0],"last_pc":12288,"last_instr":4707,"running":true,"output":""}"#,
+                r#"{"error":"unknown command \"bogus\""}"#,
+                r#"{"pc":12290,"cond":1,"registers":[0,3,0,0,0,0,0,12290],"last_pc":12289,"last_instr":61477,"running":false,"output":"HALT"}"#,
+                r#"{"error":"the program has halted"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn input_command_types_keys() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        let image = assembler::assemble(".ORIG x3000\nGETC\nOUT\nHALT\n.END").unwrap();
+        vm.load_image(&assembler::to_bytes(&image)).unwrap();
+
+        let mut output = Vec::new();
+        serve(
+            &mut vm,
+            &DispatchTables::new(),
+            &b"continue\ninput x\ncontinue\n"[..],
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], r#"{"error":"waiting for input"}"#);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].contains(r#""output":"x""#), "{}", lines[2]);
+    }
+
+    #[test]
+    fn continue_stops_at_instruction_limit() {
+        let mut vm = LC3::with_io(io::empty(), io::sink());
+        let image = assembler::assemble(".ORIG x3000\nLOOP BRnzp LOOP\n.END").unwrap();
+        vm.load_image(&assembler::to_bytes(&image)).unwrap();
+        vm.max_instructions = Some(3);
+
+        let mut output = Vec::new();
+        serve(
+            &mut vm,
+            &DispatchTables::new(),
+            &b"continue\nstep\n"[..],
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[3], r#"{"error":"instruction limit"}"#);
+        assert_eq!(lines[4], r#"{"error":"instruction limit"}"#);
+        assert_eq!(vm.instruction_count(), 3);
+    }
+}
@@ -0,0 +1,263 @@
+/// Runs the VM on a worker thread that is controlled over channels.
+///
+/// This keeps the compute loop off of the UI thread: the UI sends `Command`s and receives a
+/// `StateUpdate` whenever the VM stops, so it never blocks on a long running program. The
+/// single-threaded `LC3` API is unaffected, and the VM is handed back when the worker finishes.
+///
+/// Program output is captured and delivered with the updates. Keyboard input is only what is sent
+/// with `Command::Input`, so the worker never reads from stdin.
+use crate::lc3::{consts::Register, error::VmError, DispatchTables, LC3};
+use std::{
+    collections::HashSet,
+    sync::mpsc::{self, Receiver, SendError, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+};
+
+/// The number of instructions executed between checks for new commands while continuing
+const COMMAND_POLL_STEPS: usize = 1024;
+
+/// The commands that control a worker
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Execute a single instruction
+    Step,
+
+    /// Execute instructions until the program halts, hits a breakpoint, reaches the VM's
+    /// `max_instructions`, or is paused
+    Continue,
+
+    /// Stop continuing
+    Pause,
+
+    /// Stop continuing before the instruction at this address is executed
+    SetBreakpoint(u16),
+
+    /// Remove a breakpoint set with `SetBreakpoint`
+    ClearBreakpoint(u16),
+
+    /// Type these bytes on the VM's keyboard
+    ///
+    /// Input goes straight to the keyboard rather than waiting its turn behind other commands, so
+    /// it reaches a program that is blocked waiting for a key.
+    Input(Vec<u8>),
+
+    /// Stop the worker and hand back the VM
+    Quit,
+}
+
+/// The state of the VM, sent by the worker whenever the VM stops
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StateUpdate {
+    /// The values of all of the registers, indexed by `Register`
+    pub registers: Vec<u16>,
+
+    /// Whether the program can still execute instructions
+    pub running: bool,
+
+    /// Whether the VM stopped because it reached a breakpoint
+    pub at_breakpoint: bool,
+
+    /// The output the program has written since the previous update
+    pub output: Vec<u8>,
+
+    /// The fault that stopped the program, if it stopped since the previous update because of one
+    pub error: Option<VmError>,
+}
+
+impl StateUpdate {
+    /// The value of the program counter
+    pub fn pc(&self) -> u16 {
+        self.registers[Register::PC as usize]
+    }
+}
+
+/// A handle to a VM running on its own thread
+pub struct Worker {
+    commands: Sender<Command>,
+    keys: Sender<u8>,
+    updates: Receiver<StateUpdate>,
+    handle: JoinHandle<LC3>,
+}
+
+impl Worker {
+    /// Move a VM onto a new worker thread
+    ///
+    /// The VM starts out paused, so nothing is executed until a command is sent.
+    pub fn spawn(mut vm: LC3) -> Self {
+        let keys = vm.input_sender();
+        let (commands, command_receiver) = mpsc::channel();
+        let (update_sender, updates) = mpsc::channel();
+        let handle = thread::spawn(move || run(vm, command_receiver, update_sender));
+        Worker {
+            commands,
+            keys,
+            updates,
+            handle,
+        }
+    }
+
+    /// Send a command to the worker, which fails if the worker has already stopped
+    pub fn send(&self, command: Command) -> Result<(), SendError<Command>> {
+        match command {
+            Command::Input(bytes) => {
+                for (idx, &byte) in bytes.iter().enumerate() {
+                    if self.keys.send(byte).is_err() {
+                        return Err(SendError(Command::Input(bytes[idx..].to_vec())));
+                    }
+                }
+                Ok(())
+            }
+            command => self.commands.send(command),
+        }
+    }
+
+    /// The channel that state updates from the worker arrive on
+    pub fn updates(&self) -> &Receiver<StateUpdate> {
+        &self.updates
+    }
+
+    /// Stop the worker and take back the VM
+    ///
+    /// If the worker thread panicked, the panic is resumed on the calling thread.
+    pub fn join(self) -> LC3 {
+        // The worker may have stopped already, in which case there is nobody left to tell
+        let _ = self.commands.send(Command::Quit);
+        // A program waiting for a key would never see the command, so end its input too
+        drop(self.keys);
+        match self.handle.join() {
+            Ok(vm) => vm,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// The worker thread's loop, which handles commands until it is told to quit or disconnected
+fn run(mut vm: LC3, commands: Receiver<Command>, updates: Sender<StateUpdate>) -> LC3 {
+    let tables = DispatchTables::new();
+    let mut breakpoints = HashSet::new();
+    let mut continuing = false;
+    vm.captured_output = Some(Vec::new());
+    vm.start();
+
+    loop {
+        // Only block waiting for a command when there's nothing else to do
+        let command = if continuing {
+            match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => break,
+            }
+        };
+
+        let mut stopped = false;
+        let mut error = None;
+        match command {
+            Some(Command::Step) => {
+                if vm.is_running() {
+                    error = vm.step(&tables).err();
+                }
+                continuing = false;
+                stopped = true;
+            }
+            Some(Command::Continue) => {
+                continuing = vm.is_running();
+                stopped = !continuing;
+            }
+            Some(Command::Pause) => {
+                stopped = continuing;
+                continuing = false;
+            }
+            Some(Command::SetBreakpoint(addr)) => {
+                breakpoints.insert(addr);
+            }
+            Some(Command::ClearBreakpoint(addr)) => {
+                breakpoints.remove(&addr);
+            }
+            // Input never reaches the worker thread, since it's sent to the keyboard directly
+            Some(Command::Input(_)) => (),
+            Some(Command::Quit) => break,
+            None => (),
+        }
+
+        let mut at_breakpoint = false;
+        if continuing {
+            for _ in 0..COMMAND_POLL_STEPS {
+                if let Some(limit) = vm.max_instructions {
+                    if vm.instruction_count() >= limit {
+                        error = Some(VmError::InstructionLimit { limit });
+                        continuing = false;
+                        stopped = true;
+                        break;
+                    }
+                }
+                error = vm.step(&tables).err();
+                at_breakpoint = breakpoints.contains(&vm.registers[Register::PC as usize]);
+                if !vm.is_running() || at_breakpoint {
+                    continuing = false;
+                    stopped = true;
+                    break;
+                }
+            }
+        }
+
+        if stopped {
+            let update = StateUpdate {
+                registers: vm.registers.clone(),
+                running: vm.is_running(),
+                at_breakpoint,
+                output: vm.take_captured_output(),
+                error,
+            };
+            if updates.send(update).is_err() {
+                break;
+            }
+        }
+    }
+    vm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::test_support::load;
+
+    #[test]
+    fn step_sends_update_after_one_instruction() {
+        let worker = Worker::spawn(load(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END"));
+        worker.send(Command::Step).unwrap();
+        let update = worker.updates().recv().unwrap();
+        assert_eq!(update.pc(), 0x3001);
+        assert_eq!(update.registers[Register::R0 as usize], 1);
+        assert!(update.running);
+        assert_eq!(update.error, None);
+        assert_eq!(worker.join().instruction_count(), 1);
+    }
+
+    #[test]
+    fn input_reaches_waiting_program() {
+        let worker = Worker::spawn(load(".ORIG x3000\nGETC\nOUT\nHALT\n.END"));
+        worker.send(Command::Continue).unwrap();
+        worker.send(Command::Input(b"x".to_vec())).unwrap();
+        let update = worker.updates().recv().unwrap();
+        assert!(!update.running);
+        assert_eq!(update.output, b"xHALT");
+        worker.join();
+    }
+
+    #[test]
+    fn continue_stops_at_instruction_limit() {
+        let mut vm = load(".ORIG x3000\nLOOP BRnzp LOOP\n.END");
+        vm.max_instructions = Some(10);
+        let worker = Worker::spawn(vm);
+        worker.send(Command::Continue).unwrap();
+        let update = worker.updates().recv().unwrap();
+        assert_eq!(update.error, Some(VmError::InstructionLimit { limit: 10 }));
+        assert!(!update.at_breakpoint);
+        assert_eq!(worker.join().instruction_count(), 10);
+    }
+}
@@ -0,0 +1,87 @@
+/// Tests that embed the VM through the library's public API, the way another program would
+use lc3_vm::{
+    assembler::to_bytes, consts::Register, run_to_halt, DispatchTables, RunError, VmError, LC3,
+};
+use std::{
+    fs, io,
+    sync::{Arc, Mutex},
+};
+
+/// An output stream whose contents can still be read after it is handed to the VM
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn runs_hand_assembled_program_in_process() {
+    let output = SharedBuffer::default();
+    let mut vm = LC3::with_io(io::empty(), output.clone());
+    vm.load_image(&to_bytes(&[
+        0x3000, // .ORIG x3000
+        0x1022, // ADD R0, R0, #2
+        0x1023, // ADD R0, R0, #3
+        0x3003, // ST R0, #3
+        0x2203, // LD R1, #3
+        0xF025, // HALT
+        0x0000, // x3005
+        0x0000, // x3006, where the sum is stored
+        0x0021, // x3007, which is loaded into R1
+    ]))
+    .unwrap();
+    vm.run_loop(&DispatchTables::new()).unwrap();
+
+    assert_eq!(vm.registers[Register::R0 as usize], 5);
+    assert_eq!(vm.registers[Register::R1 as usize], 0x21);
+    assert_eq!(vm.mem_read(0x3006), 5);
+    assert_eq!(String::from_utf8_lossy(&output.0.lock().unwrap()), "HALT");
+}
+
+#[test]
+fn steps_and_writes_memory_from_outside() {
+    let mut vm = LC3::with_io(io::empty(), io::sink());
+    vm.captured_output = Some(Vec::new());
+    // ADD R0, R0, #1 followed by HALT, patched in one word at a time
+    vm.mem_write(0x3000, 0x1021);
+    vm.mem_write(0x3001, 0xF025);
+    let tables = DispatchTables::new();
+    vm.start();
+    vm.step(&tables).unwrap();
+    assert_eq!(vm.registers[Register::R0 as usize], 1);
+    assert!(vm.is_running());
+    vm.step(&tables).unwrap();
+    assert!(!vm.is_running());
+}
+
+#[test]
+fn reads_image_file() {
+    let path = std::env::temp_dir().join(format!("lc3-library-{}.obj", std::process::id()));
+    fs::write(&path, to_bytes(&[0x3000, 0xF025])).unwrap();
+    let mut vm = LC3::with_io(io::empty(), io::sink());
+    let report = vm.read_image_file(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(report.origin, 0x3000);
+    assert_eq!(vm.mem_read(0x3000), 0xF025);
+}
+
+#[test]
+fn run_to_halt_returns_output() {
+    // GETC, OUT, HALT
+    let image = to_bytes(&[0x3000, 0xF020, 0xF021, 0xF025]);
+    assert_eq!(run_to_halt(&image, b"a", 100).unwrap(), "aHALT");
+
+    // BRnzp #-1 never halts
+    let image = to_bytes(&[0x3000, 0x0FFF]);
+    assert!(matches!(
+        run_to_halt(&image, b"", 100),
+        Err(RunError::Fault(VmError::InstructionLimit { limit: 100 }))
+    ));
+}